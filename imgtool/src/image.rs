@@ -0,0 +1,377 @@
+// A disk image held in memory - the whole thing is read in, changed, and written back out
+// Everything about the format comes from ustar-format, so an image made here is exactly what the kernel would have
+// written, and one the kernel wrote reads back the same way it does on boot
+
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use ustar_format::{blocks_for_size, find_overlaps, long_name_headers, tombstone_block, Entry, FsckProblem, Header, Item,
+    Reader, BLOCK_SIZE, JOURNAL_BLOCKS, JOURNAL_IDLE, JOURNAL_MAGIC};
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    // There isn't room between the end of the archive and the journal
+    NoSpace,
+    // The journal has a transaction the kernel hasn't finished - booting the image finishes it
+    JournalPending,
+    // Something that can't go in or come out of an image - an empty name, or one with .. in it
+    BadName(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "{}", err),
+            ImageError::NoSpace => write!(f, "There isn't enough space left in the image"),
+            ImageError::JournalPending => write!(f, "The journal has an unfinished transaction - boot the image so the kernel can finish it"),
+            ImageError::BadName(name) => write!(f, "{} can't go in an image", name),
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> ImageError {
+        ImageError::Io(err)
+    }
+}
+
+pub struct Image {
+    data: Vec<u8>,
+}
+
+impl Image {
+    // An empty archive of size bytes - all zeros is already a valid empty archive, and the journal gets marked so the
+    // kernel knows it's one
+    pub fn new(size: u64) -> Image {
+        let blocks = blocks_for_size(size);
+        let mut image = Image { data: vec![0; blocks as usize * BLOCK_SIZE] };
+        if image.block_count() > JOURNAL_BLOCKS {
+            let journal_start = image.journal_start();
+            image.write_blocks(journal_start, &JOURNAL_IDLE);
+        }
+        image
+    }
+
+    pub fn from_bytes(mut data: Vec<u8>) -> Image {
+        let blocks = blocks_for_size(data.len() as u64);
+        data.resize(blocks as usize * BLOCK_SIZE, 0);
+        Image { data }
+    }
+
+    pub fn open(path: &Path) -> Result<Image, ImageError> {
+        Ok(Image::from_bytes(fs::read(path)?))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ImageError> {
+        fs::write(path, &self.data)?;
+        Ok(())
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn block_count(&self) -> u64 {
+        (self.data.len() / BLOCK_SIZE) as u64
+    }
+
+    // Reading past the end gives back zeros, which looks like the end of the archive
+    pub fn block(&self, block_id: u64) -> Vec<u8> {
+        let start = block_id as usize * BLOCK_SIZE;
+        match self.data.get(start..start + BLOCK_SIZE) {
+            Some(block) => block.to_vec(),
+            None => vec![0; BLOCK_SIZE],
+        }
+    }
+
+    fn write_blocks(&mut self, block_id: u64, data: &[u8]) {
+        let start = block_id as usize * BLOCK_SIZE;
+        self.data[start..start + data.len()].copy_from_slice(data);
+    }
+
+    // Same place the kernel puts it - the last JOURNAL_BLOCKS blocks
+    pub fn journal_start(&self) -> u64 {
+        self.block_count().saturating_sub(JOURNAL_BLOCKS)
+    }
+
+    pub fn journal_pending(&self) -> bool {
+        self.block(self.journal_start())[0..8] == JOURNAL_MAGIC
+    }
+
+    // Everything in the archive in the order it's on the disk - tombstones and the trash included - and the block the
+    // archive ends at
+    pub fn entries(&self) -> (Vec<Entry>, u64) {
+        let mut entries = Vec::new();
+        let mut reader = Reader::new();
+        while let Ok(Some(entry)) = reader.next(|block_id| Ok::<_, Infallible>(self.block(block_id))) {
+            entries.push(entry);
+        }
+        (entries, reader.position())
+    }
+
+    // Files, links and directories that haven't been removed
+    pub fn items(&self) -> Vec<Item> {
+        self.entries().0.into_iter().filter_map(|entry| match entry {
+            Entry::Item(item) if !item.trashed && !item.is_tombstone() => Some(item),
+            _ => None,
+        }).collect()
+    }
+
+    // What's in the trash, oldest first like the kernel keeps it
+    pub fn trash(&self) -> Vec<Item> {
+        let mut trash: Vec<Item> = self.entries().0.into_iter().filter_map(|entry| match entry {
+            Entry::Item(item) if item.trashed => Some(item),
+            _ => None,
+        }).collect();
+        trash.sort_by_key(|item| item.removed);
+        trash
+    }
+
+    // The data comes right after the header
+    pub fn read_data(&self, item: &Item) -> Vec<u8> {
+        let start = (item.block_id + item.extra_blocks + 1) as usize * BLOCK_SIZE;
+        let end = (start + item.header.size as usize).min(self.data.len());
+        self.data[start.min(end)..end].to_vec()
+    }
+
+    // The live item at path, if there is one
+    pub fn find(&self, path: &str) -> Option<Item> {
+        let path = normalize(path);
+        self.items().into_iter().find(|item| normalize(&item.header.name) == path)
+    }
+
+    // Puts source in the image at target (an absolute path) - a directory gets everything in it put in too
+    // Directories on the host become directories in the image, and symlinks stay symlinks
+    pub fn add(&mut self, source: &Path, target: &str) -> Result<(), ImageError> {
+        let metadata = fs::symlink_metadata(source)?;
+        let target = normalize(target);
+        let mut header = Header::new(&format!("/{}", target), b'0', 0);
+        header.mode = host_mode(&metadata);
+        header.mtime = unix_time(metadata.modified().ok());
+        if metadata.is_dir() {
+            if !target.is_empty() && self.find(&target).is_none() {
+                header.name.push('/');
+                header.type_flag = b'5';
+                self.append(header, &[])?;
+            }
+            let mut children = fs::read_dir(source)?.collect::<Result<Vec<_>, io::Error>>()?;
+            children.sort_by_key(|child| child.file_name());
+            for child in children {
+                let name = format!("{}/{}", target, child.file_name().to_string_lossy());
+                self.add(&child.path(), &name)?;
+            }
+            Ok(())
+        }
+        else if metadata.file_type().is_symlink() {
+            header.type_flag = b'2';
+            header.linked_name = fs::read_link(source)?.to_string_lossy().into_owned();
+            self.append(header, &[])
+        }
+        else {
+            let data = fs::read(source)?;
+            header.size = data.len() as u64;
+            self.append(header, &data)
+        }
+    }
+
+    // Adds one entry to the end of the archive, with whatever long name headers it needs in front of it
+    // Anything already at the same path is turned into free space, and missing directories above it are made
+    pub fn append(&mut self, header: Header, data: &[u8]) -> Result<(), ImageError> {
+        if self.journal_pending() {
+            return Err(ImageError::JournalPending);
+        }
+        check_name(&header.name)?;
+        let name = normalize(&header.name);
+        if let Some(parent) = parent(&name) {
+            if self.find(parent).is_none() {
+                let mut folder = Header::new(&format!("/{}/", parent), b'5', 0);
+                folder.mode = 0o755;
+                folder.mtime = unix_time(Some(SystemTime::now()));
+                self.append(folder, &[])?;
+            }
+        }
+        let mut result = long_name_headers(&header.name, &header.linked_name, false, 0);
+        result.extend(header.to_block());
+        result.extend(data);
+        result.resize(blocks_for_size(result.len() as u64) as usize * BLOCK_SIZE, 0);
+        let end = self.entries().1;
+        let blocks = result.len() as u64 / BLOCK_SIZE as u64;
+        // The end of the archive is two empty blocks
+        if end + blocks + 2 > self.journal_start() {
+            return Err(ImageError::NoSpace);
+        }
+        if let Some(old) = self.find(&name) {
+            self.write_blocks(old.block_id, &tombstone_block(old.blocks()));
+        }
+        self.write_blocks(end, &result);
+        self.write_blocks(end + blocks, &[0; 2 * BLOCK_SIZE]);
+        Ok(())
+    }
+
+    // Writes out everything that hasn't been removed under dest. Tombstones don't have anything left to write, and the
+    // trash only goes out (to dest/.trash) if trash is set
+    pub fn extract(&self, dest: &Path, trash: bool) -> Result<(), ImageError> {
+        let mut directories = Vec::new();
+        for item in self.items() {
+            self.extract_item(&item, dest, &mut directories)?;
+        }
+        if trash {
+            let trash_dest = dest.join(".trash");
+            for item in self.trash() {
+                self.extract_item(&item, &trash_dest, &mut directories)?;
+            }
+        }
+        // Directories get their mode last, in case it doesn't let anything be written in them
+        for (path, mode) in directories.into_iter().rev() {
+            set_host_mode(&path, mode)?;
+        }
+        Ok(())
+    }
+
+    fn extract_item(&self, item: &Item, dest: &Path, directories: &mut Vec<(PathBuf, u64)>) -> Result<(), ImageError> {
+        let path = host_path(dest, &item.header.name)?;
+        if item.is_directory() {
+            fs::create_dir_all(&path)?;
+            directories.push((path, item.header.mode));
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if path.symlink_metadata().is_ok() {
+            fs::remove_file(&path)?;
+        }
+        match item.header.type_flag {
+            b'1' => {
+                fs::hard_link(host_path(dest, &item.header.linked_name)?, &path)?;
+            },
+            b'2' => make_symlink(&item.header.linked_name, &path)?,
+            _ => {
+                fs::write(&path, self.read_data(item))?;
+                set_host_mode(&path, item.header.mode)?;
+            },
+        }
+        Ok(())
+    }
+
+    // The same checks the kernel's fsck makes on the disk, minus the ones about what it has in memory
+    pub fn fsck(&self) -> Vec<FsckProblem> {
+        let mut problems = Vec::new();
+        let (entries, end) = self.entries();
+        let mut ranges = Vec::new();
+        let mut directories = BTreeSet::new();
+        let mut parents = BTreeSet::new();
+        for entry in entries.iter() {
+            match entry {
+                Entry::Damaged { block_id, name } => problems.push(FsckProblem::BadChecksum { block_id: *block_id, name: name.to_string() }),
+                Entry::Item(item) if item.is_tombstone() => {
+                    ranges.push((item.block_id, item.blocks(), format!("Free space at block {}", item.block_id)));
+                },
+                Entry::Item(item) if item.trashed => {
+                    ranges.push((item.block_id, item.blocks(), format!("{} in the trash", item.header.name.trim_end_matches('/'))));
+                },
+                Entry::Item(item) => {
+                    let name = normalize(&item.header.name);
+                    if item.is_directory() {
+                        directories.insert(name.to_string());
+                    }
+                    let mut path = name.as_str();
+                    while let Some(above) = parent(path) {
+                        parents.insert(above.to_string());
+                        path = above;
+                    }
+                    ranges.push((item.block_id, item.blocks(), item.header.name.to_string()));
+                },
+                _ => {},
+            }
+        }
+        // The first block that isn't a header should be the first of two empty ones
+        if self.block(end).iter().any(|b| *b != 0) {
+            problems.push(FsckProblem::Unreadable { block_id: end });
+        }
+        else if self.block(end + 1).iter().any(|b| *b != 0) {
+            problems.push(FsckProblem::BadEndMarker { block_id: end });
+        }
+        for parent in parents.difference(&directories) {
+            problems.push(FsckProblem::MissingDirectory { name: format!("/{}/", parent) });
+        }
+        ranges.push((end, 2, "The end of the archive".to_string()));
+        ranges.push((self.journal_start(), JOURNAL_BLOCKS, "The journal".to_string()));
+        problems.append(&mut find_overlaps(ranges));
+        problems
+    }
+}
+
+// Paths get compared without the / on either end - the kernel writes /dir/ where tar writes dir/
+fn normalize(path: &str) -> String {
+    path.trim_matches('/').to_string()
+}
+
+fn parent(path: &str) -> Option<&str> {
+    match path.rfind('/') {
+        Some(index) => Some(&path[..index]),
+        None => None,
+    }
+}
+
+// Names can be as long as the long name headers let them be, but .. would let them get out of wherever they're
+// extracted to
+fn check_name(name: &str) -> Result<(), ImageError> {
+    if normalize(name).is_empty() || name.split('/').any(|part| part == "..") {
+        return Err(ImageError::BadName(name.to_string()));
+    }
+    Ok(())
+}
+
+fn host_path(dest: &Path, name: &str) -> Result<PathBuf, ImageError> {
+    let relative = Path::new(name.trim_start_matches('/'));
+    if relative.components().any(|part| !matches!(part, Component::Normal(_))) {
+        return Err(ImageError::BadName(name.to_string()));
+    }
+    Ok(dest.join(relative))
+}
+
+fn unix_time(time: Option<SystemTime>) -> u64 {
+    match time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+        Some(duration) => duration.as_secs(),
+        None => 0,
+    }
+}
+
+#[cfg(unix)]
+fn host_mode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() as u64 & 0o7777
+}
+
+#[cfg(not(unix))]
+fn host_mode(metadata: &fs::Metadata) -> u64 {
+    if metadata.is_dir() { 0o755 } else { 0o644 }
+}
+
+#[cfg(unix)]
+fn set_host_mode(path: &Path, mode: u64) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode as u32))
+}
+
+#[cfg(not(unix))]
+fn set_host_mode(_path: &Path, _mode: u64) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+// Windows wants to know what the link points at before making one, so they're left out
+#[cfg(not(unix))]
+fn make_symlink(_target: &str, _path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,6 @@
+// Builds, reads and fixes up disk images for the kernel's file system from the host
+// The format itself lives in ustar-format, which the kernel uses too
+
+pub mod image;
+
+pub use image::{Image, ImageError};
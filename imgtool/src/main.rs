@@ -0,0 +1,252 @@
+// Host side tool for the disk images the kernel mounts - run it with no arguments to see what it can do
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use imgtool::{Image, ImageError};
+use ustar_format::{Entry, Item, JOURNAL_BLOCKS};
+
+// Same as gendisk.sh always made
+const DEFAULT_SIZE: u64 = 32 * 1024 * 1024;
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        help();
+        process::exit(2);
+    }
+    let command = args.remove(0);
+    let result = match command.as_str() {
+        "build" => build(args),
+        "list" => list(args),
+        "extract" => extract(args),
+        "fsck" => fsck(args),
+        "inject" => inject(args),
+        "help" => {
+            help();
+            Ok(())
+        },
+        _ => usage(),
+    };
+    if let Err(err) = result {
+        eprintln!("imgtool: {}", err);
+        process::exit(1);
+    }
+}
+
+fn help() {
+    println!("imgtool - makes and looks at disk images for the file system");
+    println!();
+    println!("imgtool build <image> <path>... [--size <size>]");
+    println!("    Makes a new image. Files go in the root, and directories have what's in them put in the root");
+    println!("    The size can end in K, M or G, and is 32M if it isn't given - the journal takes up the last {} blocks", JOURNAL_BLOCKS);
+    println!("imgtool list <image>");
+    println!("    Lists everything in the archive in order, including free space and the trash");
+    println!("imgtool extract <image> <dir> [--trash]");
+    println!("    Writes out everything in the image, and the trash to <dir>/.trash with --trash");
+    println!("imgtool fsck <image>");
+    println!("    Checks the headers and where everything is, the same way fsck in the kernel does");
+    println!("imgtool inject <image> <path>... [--to <dir>]");
+    println!("    Adds files to an image, the same way build does. Anything already there gets replaced");
+}
+
+fn usage() -> ! {
+    help();
+    process::exit(2);
+}
+
+// Takes --name <value> out of args
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    if index + 1 >= args.len() {
+        usage();
+    }
+    args.remove(index);
+    Some(args.remove(index))
+}
+
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|arg| arg == name) {
+        Some(index) => {
+            args.remove(index);
+            true
+        },
+        None => false,
+    }
+}
+
+// Bytes, or a number of K, M or G
+fn parse_size(text: &str) -> Option<u64> {
+    let (number, multiplier) = match text.chars().last()? {
+        'K' | 'k' => (&text[..text.len() - 1], 1024),
+        'M' | 'm' => (&text[..text.len() - 1], 1024 * 1024),
+        'G' | 'g' => (&text[..text.len() - 1], 1024 * 1024 * 1024),
+        _ => (text, 1),
+    };
+    number.parse::<u64>().ok().map(|number| number * multiplier)
+}
+
+// Puts source in the image under dir - a file keeps its name, and a directory has what's in it put there
+fn add_source(image: &mut Image, source: &str, dir: &str) -> Result<(), ImageError> {
+    let path = Path::new(source);
+    if fs::metadata(path)?.is_dir() {
+        image.add(path, dir)
+    }
+    else {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return Err(ImageError::BadName(source.to_string())),
+        };
+        image.add(path, &format!("{}/{}", dir.trim_end_matches('/'), name))
+    }
+}
+
+fn build(mut args: Vec<String>) -> Result<(), ImageError> {
+    let size = match take_option(&mut args, "--size") {
+        Some(size) => match parse_size(&size) {
+            Some(size) => size,
+            None => usage(),
+        },
+        None => DEFAULT_SIZE,
+    };
+    if args.len() < 2 {
+        usage();
+    }
+    let mut image = Image::new(size);
+    for source in args[1..].iter() {
+        add_source(&mut image, source, "/")?;
+    }
+    image.save(Path::new(&args[0]))?;
+    println!("{}: {} of {} blocks used", args[0], image.entries().1 + 2, image.journal_start());
+    Ok(())
+}
+
+fn inject(mut args: Vec<String>) -> Result<(), ImageError> {
+    let dir = match take_option(&mut args, "--to") {
+        Some(dir) => dir,
+        None => "/".to_string(),
+    };
+    if args.len() < 2 {
+        usage();
+    }
+    let path = Path::new(&args[0]);
+    let mut image = Image::open(path)?;
+    for source in args[1..].iter() {
+        add_source(&mut image, source, &dir)?;
+    }
+    image.save(path)
+}
+
+// Turns permission bits into rwxr-xr-x
+fn mode_string(mode: u64) -> String {
+    let mut result = String::with_capacity(9);
+    for shift in [6, 3, 0].iter() {
+        let bits = (mode >> shift) & 7;
+        result.push(if bits & 4 != 0 { 'r' } else { '-' });
+        result.push(if bits & 2 != 0 { 'w' } else { '-' });
+        result.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    result
+}
+
+// YYYY-MM-DD HH:MM:SS from seconds since 1970, in UTC like the kernel's clock
+fn time_string(time: u64) -> String {
+    let mut days = time / 86400;
+    let seconds = time % 86400;
+    let mut year = 1970;
+    loop {
+        let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+        let length = if leap { 366 } else { 365 };
+        if days < length {
+            break;
+        }
+        days -= length;
+        year += 1;
+    }
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let months = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    let mut month = 0;
+    while days >= months[month] {
+        days -= months[month];
+        month += 1;
+    }
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month + 1, days + 1, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+fn item_line(item: &Item) -> String {
+    if item.is_tombstone() {
+        return format!("{:>8} {:>6}  free", item.block_id, item.blocks());
+    }
+    let header = &item.header;
+    let kind = if item.trashed {
+        "trash"
+    }
+    else {
+        match header.type_flag {
+            b'1' => "link",
+            b'2' => "symlink",
+            b'5' => "dir",
+            _ => "file",
+        }
+    };
+    let mut line = format!("{:>8} {:>6}  {:<7} {} {:>5}/{:<5} {:>10}  {}  {}", item.block_id, item.blocks(), kind,
+        mode_string(header.mode), header.owner_name, header.group_name, header.size, time_string(header.mtime), header.name);
+    if header.type_flag == b'1' || header.type_flag == b'2' {
+        line.push_str(&format!(" -> {}", header.linked_name));
+    }
+    line
+}
+
+fn list(args: Vec<String>) -> Result<(), ImageError> {
+    if args.len() != 1 {
+        usage();
+    }
+    let image = Image::open(Path::new(&args[0]))?;
+    let (entries, end) = image.entries();
+    println!("{:>8} {:>6}  {:<7} {:<9} {:>11} {:>10}  {:<19}  name", "block", "blocks", "type", "mode", "owner", "size", "modified");
+    for entry in entries.iter() {
+        match entry {
+            Entry::Item(item) => println!("{}", item_line(item)),
+            Entry::Global { block_id, blocks } => println!("{:>8} {:>6}  global", block_id, blocks),
+            Entry::Damaged { block_id, name } => println!("{:>8} {:>6}  damaged {}", block_id, "?", name),
+            Entry::Unsupported { block_id, blocks, type_flag } => println!("{:>8} {:>6}  unknown type {}", block_id, blocks, *type_flag as char),
+        }
+    }
+    println!("The archive ends at block {}, and the journal starts at block {}", end, image.journal_start());
+    if image.journal_pending() {
+        println!("The journal has an unfinished transaction");
+    }
+    Ok(())
+}
+
+fn extract(mut args: Vec<String>) -> Result<(), ImageError> {
+    let trash = take_flag(&mut args, "--trash");
+    if args.len() != 2 {
+        usage();
+    }
+    let image = Image::open(Path::new(&args[0]))?;
+    if image.journal_pending() {
+        println!("The journal has an unfinished transaction, so this might not be what the kernel would see");
+    }
+    image.extract(Path::new(&args[1]), trash)
+}
+
+fn fsck(args: Vec<String>) -> Result<(), ImageError> {
+    if args.len() != 1 {
+        usage();
+    }
+    let image = Image::open(Path::new(&args[0]))?;
+    if image.journal_pending() {
+        println!("The journal has an unfinished transaction - the kernel finishes it on boot");
+    }
+    let problems = image.fsck();
+    if problems.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    process::exit(1);
+}
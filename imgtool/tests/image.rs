@@ -0,0 +1,170 @@
+use std::fs;
+use std::path::PathBuf;
+use imgtool::{Image, ImageError};
+use ustar_format::{fix_checksum, tombstone_block, trash_headers, Entry, FsckProblem, Header, BLOCK_SIZE, JOURNAL_MAGIC};
+
+const SIZE: u64 = 1024 * 1024;
+
+// A fresh directory under the system temp directory for each test
+fn scratch(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("imgtool-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+fn names(image: &Image) -> Vec<String> {
+    image.items().into_iter().map(|item| item.header.name).collect()
+}
+
+#[test]
+fn build_and_extract() {
+    let source = scratch("build-source");
+    let long = "d".repeat(80);
+    fs::create_dir_all(source.join("docs").join(&long)).unwrap();
+    fs::write(source.join("hello.txt"), "Hello, World!").unwrap();
+    fs::write(source.join("docs").join("notes.txt"), "x".repeat(2000)).unwrap();
+    fs::write(source.join("docs").join(&long).join("f".repeat(90)), "deep").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("../hello.txt", source.join("docs").join("hello")).unwrap();
+
+    let mut image = Image::new(SIZE);
+    image.add(&source, "/").unwrap();
+    let names = names(&image);
+    assert!(names.contains(&"/hello.txt".to_string()));
+    assert!(names.contains(&"/docs/".to_string()));
+    assert!(names.contains(&format!("/docs/{}/{}", long, "f".repeat(90))));
+    assert_eq!(image.fsck(), Vec::new());
+
+    // Saving and opening it again is what the kernel would see
+    let path = scratch("build-image").join("os.tar");
+    image.save(&path).unwrap();
+    let image = Image::open(&path).unwrap();
+    assert_eq!(image.bytes().len() as u64, SIZE);
+
+    let dest = scratch("build-dest");
+    image.extract(&dest, false).unwrap();
+    assert_eq!(fs::read_to_string(dest.join("hello.txt")).unwrap(), "Hello, World!");
+    assert_eq!(fs::read(dest.join("docs").join("notes.txt")).unwrap().len(), 2000);
+    assert_eq!(fs::read_to_string(dest.join("docs").join(&long).join("f".repeat(90))).unwrap(), "deep");
+    #[cfg(unix)]
+    assert_eq!(fs::read_to_string(dest.join("docs").join("hello")).unwrap(), "Hello, World!");
+}
+
+#[test]
+fn inject_replaces_and_makes_directories() {
+    let source = scratch("inject-source");
+    fs::write(source.join("a.txt"), "first").unwrap();
+    let mut image = Image::new(SIZE);
+    image.add(&source.join("a.txt"), "/bin/a.txt").unwrap();
+    assert_eq!(names(&image), vec!["/bin/".to_string(), "/bin/a.txt".to_string()]);
+
+    fs::write(source.join("a.txt"), "second, which is longer").unwrap();
+    image.add(&source.join("a.txt"), "/bin/a.txt").unwrap();
+    let item = image.find("/bin/a.txt").unwrap();
+    assert_eq!(image.read_data(&item), b"second, which is longer");
+    // The old one is free space now, not a second copy
+    let (entries, _) = image.entries();
+    let free = entries.iter().filter(|entry| matches!(entry, Entry::Item(item) if item.is_tombstone())).count();
+    assert_eq!(free, 1);
+    assert_eq!(names(&image).len(), 2);
+    assert_eq!(image.fsck(), Vec::new());
+}
+
+// What the kernel leaves behind after rm - the header is renamed to defrag with the path in the prefix, and keeps its
+// time alongside when it was removed
+#[test]
+fn trash_and_tombstones() {
+    let mut image = Image::new(SIZE);
+    let mut header = Header::new("/gone.txt", b'0', 5);
+    header.mtime = 100;
+    image.append(header.clone(), b"gone!").unwrap();
+    image.append(Header::new("/kept.txt", b'0', 0), &[]).unwrap();
+    image.append(Header::new("/freed.txt", b'0', 0), &[]).unwrap();
+    let mut data = image.bytes().to_vec();
+    let trashed = trash_headers(header.to_block(), "/gone.txt", "", 0, 200).unwrap();
+    data[0..BLOCK_SIZE].copy_from_slice(&trashed);
+    data[3 * BLOCK_SIZE..4 * BLOCK_SIZE].copy_from_slice(&tombstone_block(1));
+    let image = Image::from_bytes(data);
+
+    assert_eq!(names(&image), vec!["/kept.txt".to_string()]);
+    let trash = image.trash();
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].header.name, "/gone.txt");
+    assert_eq!(trash[0].header.mtime, 100);
+    assert_eq!(trash[0].removed, 200);
+    assert_eq!(image.fsck(), Vec::new());
+
+    let dest = scratch("trash-dest");
+    image.extract(&dest, true).unwrap();
+    assert!(dest.join("kept.txt").exists());
+    assert!(!dest.join("gone.txt").exists());
+    assert!(!dest.join("freed.txt").exists());
+    assert_eq!(fs::read_to_string(dest.join(".trash").join("gone.txt")).unwrap(), "gone!");
+}
+
+// A path too long for the prefix goes in a PAX record in front of the header instead
+#[test]
+fn trash_long_paths() {
+    let mut image = Image::new(SIZE);
+    let path = format!("/{}", "l".repeat(200));
+    let header = Header::new(&path, b'0', 3);
+    image.append(header.clone(), b"abc").unwrap();
+    let item = image.items().remove(0);
+    assert_eq!(item.extra_blocks, 2);
+    let mut data = image.bytes().to_vec();
+    let trashed = trash_headers(header.to_block(), &path, "", item.extra_blocks, 300).unwrap();
+    data[0..trashed.len()].copy_from_slice(&trashed);
+    let image = Image::from_bytes(data);
+
+    assert!(names(&image).is_empty());
+    let trash = image.trash();
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].header.name, path);
+    assert_eq!(trash[0].removed, 300);
+    assert_eq!(image.fsck(), Vec::new());
+    // With nothing in front of the header there's nowhere for it to go
+    assert_eq!(trash_headers(header.to_block(), &path, "", 0, 300), None);
+}
+
+#[test]
+fn fsck_finds_problems() {
+    let mut image = Image::new(SIZE);
+    image.append(Header::new("/a.txt", b'0', 3), b"aaa").unwrap();
+    image.append(Header::new("/b.txt", b'0', 3), b"bbb").unwrap();
+    let mut data = image.bytes().to_vec();
+    // A header with a byte changed after the name, and one that says it's in a directory nobody made
+    data[2 * BLOCK_SIZE + 10] = b'x';
+    let orphan = Header::new("/nowhere/c.txt", b'0', 0).to_block();
+    data[4 * BLOCK_SIZE..5 * BLOCK_SIZE].copy_from_slice(&orphan);
+    let image = Image::from_bytes(data.clone());
+    let problems = image.fsck();
+    assert!(problems.contains(&FsckProblem::BadChecksum { block_id: 2, name: "/b.txt".to_string() }));
+    assert!(problems.contains(&FsckProblem::MissingDirectory { name: "/nowhere/".to_string() }));
+
+    // Garbage where the end of the archive should be
+    let mut block = data[2 * BLOCK_SIZE..3 * BLOCK_SIZE].to_vec();
+    fix_checksum(&mut block);
+    data[2 * BLOCK_SIZE..3 * BLOCK_SIZE].copy_from_slice(&block);
+    data[5 * BLOCK_SIZE] = 1;
+    let problems = Image::from_bytes(data).fsck();
+    assert_eq!(problems, vec![
+        FsckProblem::Unreadable { block_id: 5 },
+        FsckProblem::MissingDirectory { name: "/nowhere/".to_string() },
+    ]);
+}
+
+#[test]
+fn refuses_when_full_or_journal_pending() {
+    let mut image = Image::new(SIZE);
+    let big = vec![0; SIZE as usize];
+    assert!(matches!(image.append(Header::new("/big", b'0', big.len() as u64), &big), Err(ImageError::NoSpace)));
+    assert!(matches!(image.append(Header::new("/../escape", b'0', 0), &[]), Err(ImageError::BadName(_))));
+
+    let mut data = image.bytes().to_vec();
+    let journal = (image.journal_start() as usize) * BLOCK_SIZE;
+    data[journal..journal + 8].copy_from_slice(&JOURNAL_MAGIC);
+    let mut image = Image::from_bytes(data);
+    assert!(image.journal_pending());
+    assert!(matches!(image.append(Header::new("/a", b'0', 0), &[]), Err(ImageError::JournalPending)));
+}
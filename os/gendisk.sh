@@ -1,2 +1,2 @@
-tar --format=ustar -c helloworld.txt -f os.tar
-truncate -s 32M os.tar
\ No newline at end of file
+# imgtool builds for the host, so it has to be run from the top level directory instead of here
+(cd .. && cargo run --release -q -p imgtool -- build os/os.tar os/helloworld.txt)
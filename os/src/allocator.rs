@@ -6,7 +6,7 @@ static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 // maps location of heap
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 64 * 1024 * 1024; // 64 MiB - we want a heap this large so that it can contain the entire fs in ram, plus more
+pub const HEAP_SIZE: usize = 16 * 1024 * 1024; // 16 MiB - file contents are loaded when they're needed, so the fs doesn't have to fit in ram
 
 // initializes the heap, mapping it to a region of memory and creates pages
 pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<(), MapToError<Size4KiB>> {
@@ -35,3 +35,13 @@ pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl
 
     Ok(())
 }
+
+// how many bytes of the heap are currently handed out
+pub fn heap_used() -> usize {
+    ALLOCATOR.lock().used()
+}
+
+// how many bytes of the heap are left
+pub fn heap_free() -> usize {
+    ALLOCATOR.lock().free()
+}
@@ -25,24 +25,188 @@ SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 #![allow(dead_code)]
 
+use alloc::string::String;
 use alloc::vec::Vec;
+use alloc::format;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
 use cpuio::UnsafePort;
+use futures_util::task::AtomicWaker;
+use crate::alloc::string::ToString;
+use crate::block_device::{check_range, BlockDevice};
+use crate::println;
+use crate::timer_routing;
+use crate::vfs::FsError;
 
 const SECTOR_SIZE: usize = 0x200;
 
-const PORT_DATA: u16 = 0x1F0;
-const PORT_SECCOUNT: u16 = 0x1F2;
-const PORT_LBA0: u16 = 0x1F3;
-const PORT_LBA1: u16 = 0x1F4;
-const PORT_LBA2: u16 = 0x1F5;
-const PORT_LBA3: u16 = 0x1F6;
-const PORT_COMMAND: u16 = 0x1F7;
-const PORT_DEV_CTRL: u16 = 0x3F6;
+// The two buses every PC has - where their registers start, and their device control port
+const PRIMARY_BUS: (u16, u16) = (0x1F0, 0x3F6);
+const SECONDARY_BUS: (u16, u16) = (0x170, 0x376);
+
+// Registers, from the start of the bus
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_SECCOUNT: u16 = 2;
+const REG_LBA0: u16 = 3;
+const REG_LBA1: u16 = 4;
+const REG_LBA2: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_COMMAND: u16 = 7;
+
+const COMMAND_READ: u8 = 0x20;
+const COMMAND_READ_EXT: u8 = 0x24;
+const COMMAND_WRITE: u8 = 0x30;
+const COMMAND_WRITE_EXT: u8 = 0x34;
+const COMMAND_CACHE_FLUSH: u8 = 0xE7;
+const COMMAND_CACHE_FLUSH_EXT: u8 = 0xEA;
+
+// The first sector LBA28 can't get to - 128 GiB in
+const LBA28_LIMIT: u64 = 1 << 28;
+
+// Status bits that mean the last command didn't work - error and drive fault
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DF: u8 = 0x20;
+// The drive has a sector ready to go, or wants the next one
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_RDY: u8 = 0x40;
+const STATUS_BSY: u8 = 0x80;
+
+// What went wrong, when the status has ERR set
+const ERROR_AMNF: u8 = 0x01;
+const ERROR_TKZNF: u8 = 0x02;
+const ERROR_ABRT: u8 = 0x04;
+const ERROR_MCR: u8 = 0x08;
+const ERROR_IDNF: u8 = 0x10;
+const ERROR_MC: u8 = 0x20;
+const ERROR_UNC: u8 = 0x40;
+const ERROR_BBK: u8 = 0x80;
+
+// Setting this in the device control register resets both drives on the bus
+const CONTROL_SRST: u8 = 0x04;
+
+// How long a drive gets to answer before it's given up on, when waiting for its interrupt
+// That's only done from the executor, where interrupts are on, so the timer keeps going
+const TIMEOUT_MILLIS: u64 = 2000;
+// Everything else spins on the status register with interrupts off, so the timer doesn't move - the timeout there is
+// this many status reads instead. Each one is a port read, which takes around a microsecond on real hardware, so it's
+// about as long as TIMEOUT_MILLIS (an emulator can get through them a lot quicker)
+const TIMEOUT_POLLS: u64 = 2_000_000;
+// Transfers that fail in a way that might not happen again get this many more goes, with a reset in between
+const RETRIES: usize = 3;
+
+// The most sectors that go in one command
+pub const MAX_SECTORS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    // Nothing is plugged in there
+    NoDevice,
+    // Something is, but it isn't a hard drive - probably a CD drive
+    NotAta,
+    // The drive can't be addressed by LBA, which is all this driver does
+    NoLba,
+    // The drive stayed busy, or never asked for data, for longer than TIMEOUT_MILLIS or TIMEOUT_POLLS
+    Timeout,
+    // DF was set
+    DriveFault,
+    // ERR was set - this is what was in the error register
+    Device(u8),
+    // The sectors aren't on the drive, or can't be reached without LBA48
+    OutOfRange,
+    // Another transfer is still going on the same bus
+    Busy,
+}
+
+impl AtaError {
+    // Whether trying again might work - a timeout or a bad read could be a one off, but the drive isn't going to change
+    // its mind about a command it won't do or a sector it doesn't have
+    fn retryable(&self) -> bool {
+        match self {
+            AtaError::Timeout | AtaError::DriveFault => true,
+            AtaError::Device(error) => error & (ERROR_ABRT | ERROR_IDNF) == 0,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for AtaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtaError::NoDevice => write!(f, "no drive"),
+            AtaError::NotAta => write!(f, "not an ATA drive"),
+            AtaError::NoLba => write!(f, "drive doesn't support LBA"),
+            AtaError::Timeout => write!(f, "drive timed out"),
+            AtaError::DriveFault => write!(f, "drive fault"),
+            AtaError::Device(error) => {
+                write!(f, "drive error")?;
+                let bits = [
+                    (ERROR_BBK, "bad block"),
+                    (ERROR_UNC, "uncorrectable data"),
+                    (ERROR_MC, "media changed"),
+                    (ERROR_IDNF, "sector not found"),
+                    (ERROR_MCR, "media change requested"),
+                    (ERROR_ABRT, "command aborted"),
+                    (ERROR_TKZNF, "track 0 not found"),
+                    (ERROR_AMNF, "address mark not found"),
+                ];
+                let mut first = true;
+                for (bit, name) in bits.iter() {
+                    if error & bit != 0 {
+                        write!(f, "{} {}", if first { ":" } else { "," }, name)?;
+                        first = false;
+                    }
+                }
+                Ok(())
+            },
+            AtaError::OutOfRange => write!(f, "sector out of range"),
+            AtaError::Busy => write!(f, "bus busy"),
+        }
+    }
+}
+
+// The file systems only need to know roughly what happened
+impl From<AtaError> for FsError {
+    fn from(err: AtaError) -> FsError {
+        match err {
+            AtaError::NoDevice | AtaError::NotAta => FsError::NotFound,
+            AtaError::NoLba => FsError::Unsupported,
+            AtaError::OutOfRange => FsError::InvalidArgument,
+            AtaError::Busy => FsError::Busy,
+            AtaError::Timeout | AtaError::DriveFault | AtaError::Device(_) => FsError::Io,
+        }
+    }
+}
+
+// How many more times the status can be read before the drive is given up on
+struct PollBudget {
+    left: u64,
+}
+
+impl PollBudget {
+    fn new() -> PollBudget {
+        PollBudget { left: TIMEOUT_POLLS }
+    }
+
+    fn spent(&mut self) -> bool {
+        if self.left == 0 {
+            return true;
+        }
+        self.left -= 1;
+        false
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct DriveProperties {
     lba28_sectors: u32,
     lba48_sectors: Option<u64>,
+    // What the drive says it is, with the padding taken off
+    model: String,
+    serial: String,
 }
 impl DriveProperties {
     fn supports_lba48(&self) -> bool {
@@ -52,126 +216,384 @@ impl DriveProperties {
     fn sector_count(&self) -> u64 {
         self.lba48_sectors.unwrap_or(self.lba28_sectors as u64)
     }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+}
+
+// What procfs shows about a drive
+impl fmt::Display for DriveProperties {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "model: {}\nserial: {}\n", self.model, self.serial)?;
+        write!(f, "sectors: {} ({} KiB)\nlba28 sectors: {}\n", self.sector_count(), self.sector_count() / 2, self.lba28_sectors)?;
+        match self.lba48_sectors {
+            Some(sectors) => writeln!(f, "lba48 sectors: {}", sectors),
+            None => writeln!(f, "lba48: not supported"),
+        }
+    }
+}
+
+// Whether each bus has raised an interrupt that hasn't been waited for yet, and who's waiting for it
+// IRQ14 is the primary bus, IRQ15 the secondary one
+struct BusInterrupt {
+    fired: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl BusInterrupt {
+    const fn new() -> BusInterrupt {
+        BusInterrupt { fired: AtomicBool::new(false), waker: AtomicWaker::new() }
+    }
+}
+
+static INTERRUPTS: [BusInterrupt; 2] = [BusInterrupt::new(), BusInterrupt::new()];
+
+// Set while a bus is in the middle of a transfer - an async one can be stopped partway through for other tasks to run,
+// and anything else that tries to use the bus then would mess it up
+static CLAIMED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+// Called by the interrupt handlers
+// Reading the status is what tells the drive its interrupt was seen - a drive that's still busy wasn't the one that
+// raised it
+pub fn handle_interrupt(bus: usize) {
+    let base = [PRIMARY_BUS, SECONDARY_BUS][bus].0;
+    let status = unsafe { UnsafePort::<u8>::new(base + REG_COMMAND).read() };
+    if status & STATUS_BSY == 0 {
+        INTERRUPTS[bus].fired.store(true, Ordering::SeqCst);
+        INTERRUPTS[bus].waker.wake();
+    }
+}
+
+// Called on every timer tick, so anything waiting for an interrupt that never comes gets to notice it's timed out
+pub fn wake_waiting() {
+    for interrupt in INTERRUPTS.iter() {
+        interrupt.waker.wake();
+    }
+}
+
+// Waits for the next interrupt from a bus - the drive raises one when it has a sector ready, when it's taken one that
+// was written, and when a command without any data is done
+// It gives up once the timer says TIMEOUT_MILLIS have gone by - wake_waiting makes sure it gets to check
+struct Interrupt {
+    bus: usize,
+    deadline: u64,
+}
+
+impl Interrupt {
+    fn new(bus: usize) -> Interrupt {
+        Interrupt { bus, deadline: timer_routing::uptime_millis() + TIMEOUT_MILLIS }
+    }
+}
+
+impl Future for Interrupt {
+    type Output = Result<(), AtaError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), AtaError>> {
+        let interrupt = &INTERRUPTS[self.bus];
+        if interrupt.fired.swap(false, Ordering::SeqCst) {
+            return Poll::Ready(Ok(()));
+        }
+        // Same as the scancode stream - the interrupt could come in between checking and registering
+        interrupt.waker.register(cx.waker());
+        if interrupt.fired.swap(false, Ordering::SeqCst) {
+            interrupt.waker.take();
+            return Poll::Ready(Ok(()));
+        }
+        if timer_routing::uptime_millis() >= self.deadline {
+            return Poll::Ready(Err(AtaError::Timeout));
+        }
+        Poll::Pending
+    }
 }
 
+// Lets every other task that's ready run once before carrying on
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// Keeps a bus to itself until it's dropped
+struct BusClaim {
+    bus: usize,
+}
+
+impl BusClaim {
+    fn take(bus: usize) -> Option<BusClaim> {
+        match CLAIMED[bus].compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => Some(BusClaim { bus }),
+            Err(_) => None,
+        }
+    }
+}
+
+impl Drop for BusClaim {
+    fn drop(&mut self) {
+        CLAIMED[self.bus].store(false, Ordering::SeqCst);
+    }
+}
+
+// Strings in the IDENTIFY data have the two bytes of each word swapped around, and are padded with spaces
+fn identify_string(words: &[u16]) -> String {
+    let mut result = String::with_capacity(words.len() * 2);
+    for word in words.iter() {
+        result.push((word >> 8) as u8 as char);
+        result.push((word & 0xFF) as u8 as char);
+    }
+    result.trim().to_string()
+}
+
+// Every drive that answers IDENTIFY, named the way Linux does - hda and hdb are the master and slave on the primary bus,
+// hdc and hdd on the secondary one
+pub fn probe_all() -> Vec<(String, AtaPio)> {
+    let mut drives = Vec::new();
+    for (i, (base, control)) in [PRIMARY_BUS, SECONDARY_BUS].iter().enumerate() {
+        for slave in [false, true].iter() {
+            if let Ok(drive) = AtaPio::probe(*base, *control, *slave) {
+                let letter = (b'a' + (i * 2) as u8 + *slave as u8) as char;
+                drives.push((format!("hd{}", letter), drive));
+            }
+        }
+    }
+    drives
+}
+
+#[derive(Clone)]
 pub struct AtaPio {
+    // First register of the bus the drive is on, and the bus's device control port
+    base: u16,
+    control: u16,
+    slave: bool,
     properties: DriveProperties,
 }
 impl AtaPio {
-    pub fn try_new() -> AtaPio  {
+    // The drive at one of the four positions, if there is one
+    pub fn probe(base: u16, control: u16, slave: bool) -> Result<AtaPio, AtaError> {
+        let mut drive = AtaPio {
+            base,
+            control,
+            slave,
+            properties: DriveProperties { lba28_sectors: 0, lba48_sectors: None, model: String::new(), serial: String::new() },
+        };
+        let _claim = drive.claim()?;
         unsafe {
-            Self::check_floating_bus();
-            Self::reset_drives();
+            drive.check_floating_bus()?;
+            // The BIOS might have told the drives not to raise interrupts, and the async transfers need them
+            UnsafePort::<u8>::new(control).write(0);
+            drive.properties = drive.identify()?;
+        }
+        Ok(drive)
+    }
+
+    // Which bus the drive is on, as an index into INTERRUPTS and CLAIMED
+    fn bus(&self) -> usize {
+        if self.base == PRIMARY_BUS.0 { 0 } else { 1 }
+    }
+
+    // Takes the bus for a transfer that runs start to finish without stopping, so it can't wait for whatever has it
+    fn claim(&self) -> Result<BusClaim, AtaError> {
+        match BusClaim::take(self.bus()) {
+            Some(claim) => Ok(claim),
+            None => Err(AtaError::Busy),
         }
-        let properties = unsafe { Self::identify() };
+    }
 
-        AtaPio { properties }
+    // Takes the bus, letting other tasks run until whoever has it is done
+    async fn claim_async(&self) -> BusClaim {
+        loop {
+            if let Some(claim) = BusClaim::take(self.bus()) {
+                return claim;
+            }
+            YieldNow { yielded: false }.await;
+        }
     }
 
     #[inline]
-    unsafe fn send_command(cmd: u8) {
-        let mut cmd_port = UnsafePort::<u8>::new(PORT_COMMAND);
+    unsafe fn send_command(&self, cmd: u8) {
+        let mut cmd_port = UnsafePort::<u8>::new(self.base + REG_COMMAND);
         cmd_port.write(cmd);
     }
 
     #[inline]
-    unsafe fn read_status() -> u8 {
-        let mut status_port = UnsafePort::<u8>::new(PORT_COMMAND);
+    unsafe fn read_status(&self) -> u8 {
+        let mut status_port = UnsafePort::<u8>::new(self.base + REG_COMMAND);
         status_port.read()
     }
 
-    unsafe fn reset_drives() {
-        // https://wiki.osdev.org/ATA_PIO_Mode#Resetting_a_drive_.2F_Software_Reset
+    // https://wiki.osdev.org/ATA_PIO_Mode#400ns_delays
+    // Reading the alternate status doesn't do anything, it just takes a while
+    unsafe fn delay_400ns(&self) {
+        let mut ctrl = UnsafePort::<u8>::new(self.control);
+        for _ in 0..4 {
+            let _ = ctrl.read();
+        }
+    }
 
-        // TODO: currently using (primary bus, master drive) only
+    // Points the bus at this drive - bits is everything else that goes in the drive register, like LBA mode and the
+    // top bits of the address
+    unsafe fn select_drive(&self, bits: u8) {
+        let mut port = UnsafePort::<u8>::new(self.base + REG_DRIVE);
+        let slave = if self.slave { 0x10 } else { 0 };
+        port.write(bits | slave);
+        // The drive takes a moment to switch over
+        self.delay_400ns();
+    }
 
-        let mut ctrl = UnsafePort::<u8>::new(PORT_DEV_CTRL);
+    // Nothing is plugged in if the bus reads all ones
+    unsafe fn check_floating_bus(&self) -> Result<(), AtaError> {
+        let data: u8 = self.read_status();
+        if data == 0xFF {
+            return Err(AtaError::NoDevice);
+        }
+        Ok(())
+    }
 
-        // Disable interrupts, run software reset
-        ctrl.write(0);
+    // What a status with ERR or DF in it means
+    unsafe fn error(&self, status: u8) -> AtaError {
+        if status & STATUS_DF != 0 {
+            return AtaError::DriveFault;
+        }
+        let mut port = UnsafePort::<u8>::new(self.base + REG_ERROR);
+        AtaError::Device(port.read())
+    }
 
-        // Wait for BSY to be clear and RDY set
-        for _ in 0..4 {
-            // 400ns delay
-            let _ = ctrl.read();
+    // Checks if the last command failed
+    unsafe fn check_error(&self) -> Result<(), AtaError> {
+        let status = self.read_status();
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(self.error(status));
         }
+        Ok(())
+    }
 
+    // Waits for BSY to clear and RDY to be set
+    // Any error bits are left alone - they could be from the last command, and only the next one clears them
+    unsafe fn wait_ready(&self) -> Result<(), AtaError> {
+        let mut budget = PollBudget::new();
         loop {
-            let v = ctrl.read();
-            if (v & 0xc0) == 0x40 {
-                // BSY clear, RDY set?
-                break;
+            let status = self.read_status();
+            if status & (STATUS_BSY | STATUS_RDY) == STATUS_RDY {
+                return Ok(());
+            }
+            if budget.spent() {
+                return Err(AtaError::Timeout);
             }
         }
     }
 
-    unsafe fn select_drive() {
-        // https://wiki.osdev.org/ATA_PIO_Mode#400ns_delays
-
-        Self::reset_drives(); // HACK: selects master drive
+    // Waits for the drive to be ready to move the next sector of a transfer - BSY clear and DRQ set, or an error
+    unsafe fn wait_drq(&self) -> Result<(), AtaError> {
+        self.delay_400ns();
+        let mut budget = PollBudget::new();
+        loop {
+            let status = self.read_status();
+            if status & STATUS_BSY == 0 {
+                if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    return Err(self.error(status));
+                }
+                if status & STATUS_DRQ != 0 {
+                    return Ok(());
+                }
+            }
+            if budget.spent() {
+                return Err(AtaError::Timeout);
+            }
+        }
     }
 
-    unsafe fn check_floating_bus() {
-        let data: u8 = Self::read_status();
-        if data == 0xFF {
-            panic!("No ATA drives attached.");
-        }
+    // Resets both drives on the bus, for when one has got itself stuck
+    // https://wiki.osdev.org/ATA_PIO_Mode#Resetting_a_drive_.2F_bus
+    unsafe fn reset_bus(&self) -> Result<(), AtaError> {
+        let mut ctrl = UnsafePort::<u8>::new(self.control);
+        ctrl.write(CONTROL_SRST);
+        self.delay_400ns();
+        ctrl.write(0);
+        // The reset puts the bus back on the master
+        self.select_drive(0b11100000);
+        self.wait_ready()
     }
 
-    /// Polls ATA controller to see if the drive is ready
-    #[inline]
-    unsafe fn is_ready() -> bool {
-        for _ in 0..4 {
-            let _ = Self::read_status();
+    // Whether a transfer that failed with err should be left there, after attempts goes at it already
+    // If not, the bus gets reset ready for the next go
+    fn give_up(&self, err: AtaError, attempts: usize, what: &str, lba: u64) -> bool {
+        // If the reset doesn't work either, there's no point going again
+        if !err.retryable() || attempts == RETRIES || unsafe { self.reset_bus() }.is_err() {
+            println!("Couldn't {} sector {}: {}", what, lba, err);
+            return true;
         }
-        let data: u8 = Self::read_status();
-        (data & 0xc0) == 0x40 // BSY clear, RDY set?
+        false
     }
 
-    /// Polls ATA controller to until the drive is ready
-    unsafe fn wait_ready() {
-        while !Self::is_ready() {}
+    // Runs transfer, and runs it again if it fails in a way that might not happen twice
+    fn retry<T>(&self, what: &str, lba: u64, mut transfer: impl FnMut() -> Result<T, AtaError>) -> Result<T, AtaError> {
+        let mut attempts = 0;
+        loop {
+            match transfer() {
+                Ok(result) => return Ok(result),
+                Err(err) if self.give_up(err, attempts, what, lba) => return Err(err),
+                Err(_) => attempts += 1,
+            }
+        }
     }
 
-    unsafe fn identify() -> DriveProperties {
+    unsafe fn identify(&self) -> Result<DriveProperties, AtaError> {
         // https://wiki.osdev.org/ATA_PIO_Mode#IDENTIFY_command
 
-        // I know this is bad, but it as of now hardcodes the drive to identify
-        let mut port_lba3 = UnsafePort::<u8>::new(PORT_LBA3);
-        port_lba3.write(0xB0);
+        self.select_drive(0xA0);
 
         // Clear LBA_N ports
-        let mut port_seccount = UnsafePort::<u8>::new(PORT_SECCOUNT);
+        let mut port_seccount = UnsafePort::<u8>::new(self.base + REG_SECCOUNT);
         port_seccount.write(0);
-        let mut port_lba0 = UnsafePort::<u8>::new(PORT_LBA0);
+        let mut port_lba0 = UnsafePort::<u8>::new(self.base + REG_LBA0);
         port_lba0.write(0);
-        let mut port_lba1 = UnsafePort::<u8>::new(PORT_LBA1);
+        let mut port_lba1 = UnsafePort::<u8>::new(self.base + REG_LBA1);
         port_lba1.write(0);
-        let mut port_lba2 = UnsafePort::<u8>::new(PORT_LBA2);
+        let mut port_lba2 = UnsafePort::<u8>::new(self.base + REG_LBA2);
         port_lba2.write(0);
 
 
         // Send IDENTIFY command
-        Self::send_command(0xEC);
-
-        //for j in 0..10000 {
-        //    let _ = ctrl.read();
-        //}
+        self.send_command(0xEC);
 
         let mut first_cleared = true;
+        let mut budget = PollBudget::new();
         loop {
-            let data: u8 = Self::read_status();
+            let data: u8 = self.read_status();
 
+            // The drive doesn't exist
             if data == 0 {
-                panic!("ATA_PIO: Drive does not exist");
+                return Err(AtaError::NoDevice);
             }
 
+            // Drive controller error on IDENTIFY - CD drives abort it, and leave their signature in LBA1 and LBA2
             if (data & 1) != 0 {
-                panic!("ATA_PIO: Drive controller error on IDENTIFY");
+                if port_lba1.read() != 0 || port_lba2.read() != 0 {
+                    return Err(AtaError::NotAta);
+                }
+                return Err(self.error(data));
             }
 
             if (data & (1 << 7)) != 0 {
                 // is busy
+                if budget.spent() {
+                    return Err(AtaError::Timeout);
+                }
                 continue;
             }
 
@@ -179,8 +601,9 @@ impl AtaPio {
                 first_cleared = false;
                 let v1 = port_lba1.read();
                 let v2 = port_lba2.read();
+                // Not an ATA drive (probably ATAPI)
                 if v1 != 0 || v2 != 0 {
-                    panic!("ATA_PIO: Not an ATA drive");
+                    return Err(AtaError::NotAta);
                 }
                 continue;
             }
@@ -188,18 +611,18 @@ impl AtaPio {
             if (data & (1 << 3)) != 0 {
                 break;
             }
+            if budget.spent() {
+                return Err(AtaError::Timeout);
+            }
         }
 
-        let mut data_port = UnsafePort::<u16>::new(PORT_DATA);
+        let mut data_port = UnsafePort::<u16>::new(self.base + REG_DATA);
         let mut data: [u16; 256] = [0; 256];
 
         // Lints are annoying
         #[allow(clippy::all)]
         for i in 0..256 {
             data[i] = data_port.read();
-            //for j in 0..10000 {
-            //    let _ = ctrl.read();
-            //}
         }
 
         let lba48_supported = (data[83] & (1 << 10)) != 0;
@@ -215,53 +638,108 @@ impl AtaPio {
             None
         };
 
+        // The drive controller does not support LBA
         if lba28_sectors == 0 && (lba48_sectors.is_none() || lba48_sectors == Some(0)) {
-            panic!("ATA_PIO: The drive controller does not support LBA.");
+            return Err(AtaError::NoLba);
         }
 
-        DriveProperties {
+        Ok(DriveProperties {
             lba28_sectors,
             lba48_sectors,
-        }
+            model: identify_string(&data[27..47]),
+            serial: identify_string(&data[10..20]),
+        })
     }
-    /// # Safety
-    /// 
-    /// This function uses ports, which make it unsafe. Carry on.
-    pub unsafe fn read_lba(&self, lba: u32, sectors: u8) -> Vec<u8> {
-        // https://wiki.osdev.org/ATA_read/write_sectors#Read_in_LBA_mode
 
-        assert!(sectors > 0);
-        let _data: u8 = Self::read_status();
+    // LBA28 is quicker to set up, so it's used whenever the whole transfer fits in it
+    fn needs_lba48(lba: u64, sectors: u16) -> bool {
+        lba + sectors as u64 > LBA28_LIMIT || sectors as usize > MAX_SECTORS
+    }
 
+    // Sends everything but the command for a transfer of sectors starting at lba
+    // Gives back whether it's an LBA48 transfer, since that needs the EXT version of the command
+    unsafe fn setup_transfer(&self, lba: u64, sectors: u16) -> Result<bool, AtaError> {
+        if sectors == 0 || lba.saturating_add(sectors as u64) > self.properties.sector_count() {
+            return Err(AtaError::OutOfRange);
+        }
+        if !Self::needs_lba48(lba, sectors) {
+            self.setup_lba28(lba as u32, sectors);
+            return Ok(false);
+        }
+        if !self.properties.supports_lba48() {
+            return Err(AtaError::OutOfRange);
+        }
+        self.setup_lba48(lba, sectors);
+        Ok(true)
+    }
+
+    unsafe fn setup_lba28(&self, lba: u32, sectors: u16) {
         // Send bits 24-27 of LBA, drive number and LBA mode
-        let mut port = UnsafePort::<u8>::new(PORT_LBA3);
-        let mut bits24_27: u8 = (lba >> 24) as u8;
-        assert!(bits24_27 < 8);
-        bits24_27 |= 0b11110000; // Drive select - we want the slave drive
-        port.write(bits24_27);
+        let bits24_27: u8 = ((lba >> 24) & 0x0F) as u8;
+        self.select_drive(0b11100000 | bits24_27);
 
-        // Send number of sectors
-        let mut port = UnsafePort::<u8>::new(PORT_SECCOUNT);
-        port.write(sectors);
+        // Send number of sectors - 256 goes in as 0
+        let mut port = UnsafePort::<u8>::new(self.base + REG_SECCOUNT);
+        port.write(sectors as u8);
 
         // Send bits 0-7 of LBA
-        let mut port = UnsafePort::<u8>::new(PORT_LBA0);
+        let mut port = UnsafePort::<u8>::new(self.base + REG_LBA0);
         port.write((lba & 0xFF) as u8);
 
-        // Send bits0xB016-23 of LBA
-        let mut port = UnsafePort::<u8>::new(PORT_LBA2);
+        // Send bits 8-15 of LBA
+        let mut port = UnsafePort::<u8>::new(self.base + REG_LBA1);
+        port.write(((lba & 0xFF00) >> 0x8) as u8);
+
+        // Send bits 16-23 of LBA
+        let mut port = UnsafePort::<u8>::new(self.base + REG_LBA2);
         port.write(((lba & 0xFF0000) >> 0x10) as u8);
+    }
 
-        // Send command
-        Self::send_command(0x20); // Read with retry
+    // https://wiki.osdev.org/ATA_PIO_Mode#48_bit_PIO
+    // Each register holds two bytes - the high one goes in first, then the low one
+    unsafe fn setup_lba48(&self, lba: u64, sectors: u16) {
+        // Just the drive and LBA mode - the address all goes in the other registers
+        self.select_drive(0b11100000);
+
+        let mut seccount = UnsafePort::<u8>::new(self.base + REG_SECCOUNT);
+        let mut lba0 = UnsafePort::<u8>::new(self.base + REG_LBA0);
+        let mut lba1 = UnsafePort::<u8>::new(self.base + REG_LBA1);
+        let mut lba2 = UnsafePort::<u8>::new(self.base + REG_LBA2);
+
+        // Bits 8-15 of the count and bits 24-47 of the LBA
+        seccount.write((sectors >> 8) as u8);
+        lba0.write((lba >> 24) as u8);
+        lba1.write((lba >> 32) as u8);
+        lba2.write((lba >> 40) as u8);
+
+        // Bits 0-7 of the count and bits 0-23 of the LBA
+        seccount.write(sectors as u8);
+        lba0.write(lba as u8);
+        lba1.write((lba >> 8) as u8);
+        lba2.write((lba >> 16) as u8);
+    }
+
+    /// # Safety
+    /// 
+    /// This function uses ports, which make it unsafe. Carry on.
+    pub unsafe fn read_lba(&self, lba: u64, sectors: u16) -> Result<Vec<u8>, AtaError> {
+        // https://wiki.osdev.org/ATA_read/write_sectors#Read_in_LBA_mode
+        self.select_drive(0b11100000);
+        self.wait_ready()?;
 
-        Self::wait_ready();
+        let lba48 = self.setup_transfer(lba, sectors)?;
 
-        let mut data_port = UnsafePort::<u16>::new(PORT_DATA);
+        // Send command
+        self.send_command(if lba48 { COMMAND_READ_EXT } else { COMMAND_READ });
+
+        let mut data_port = UnsafePort::<u16>::new(self.base + REG_DATA);
         let u16_per_sector = SECTOR_SIZE / 2;
 
-        let mut result: Vec<u8> = Vec::new();
+        let mut result: Vec<u8> = Vec::with_capacity(sectors as usize * SECTOR_SIZE);
         for _ in 0..sectors {
+            // Each sector has to be waited for on its own - reading before the drive has it ready is what was
+            // corrupting bigger reads
+            self.wait_drq()?;
             for _ in 0..u16_per_sector {
                 let word: u16 = data_port.read();
                 result.push((word & 0xFF) as u8);
@@ -269,7 +747,7 @@ impl AtaPio {
             }
         }
 
-        result
+        Ok(result)
     }
 
     fn init(&mut self) -> bool {
@@ -280,62 +758,42 @@ impl AtaPio {
         0x200
     }
 
+    pub fn properties(&self) -> &DriveProperties {
+        &self.properties
+    }
+
     /// Capacity in sectors
-    fn capacity_sectors(&mut self) -> u64 {
+    pub fn capacity_sectors(&self) -> u64 {
         self.properties.sector_count()
     }
 
-    fn read(&mut self, sector: u64) -> Vec<u8> {
-        assert!(sector < self.properties.sector_count());
+    fn read(&mut self, sector: u64) -> Result<Vec<u8>, AtaError> {
+        if sector >= self.properties.sector_count() {
+            return Err(AtaError::OutOfRange);
+        }
 
-        unsafe { self.read_lba(sector as u32, 1) }
+        unsafe { self.read_lba(sector, 1) }
     }
 
     // Make the fs driver do the hard job of converting Vec<u8> to Vec<u16>
     /// # Safety
     /// 
     /// This function uses ports, which make it unsafe. Carry on.
-    pub unsafe fn write(&self, lba: u32, sectors: u8, data: Vec<u16>) {
+    pub unsafe fn write(&self, lba: u64, sectors: u16, data: &[u16]) -> Result<(), AtaError> {
         // https://wiki.osdev.org/ATA_read/write_sectors#Read_in_LBA_mode
-        Self::wait_ready();
-
-        assert!(sectors > 0);
+        self.select_drive(0b11100000);
+        self.wait_ready()?;
 
-        // Send bits 24-27 of LBA, drive number and LBA mode
-        let mut port = UnsafePort::<u8>::new(PORT_LBA3);
-        let mut bits24_27: u8 = (lba >> 24) as u8;
-        assert!(bits24_27 < 8);
-        bits24_27 |= 0b11110000; // Drive select - we want the slave drive
-        port.write(bits24_27);
-
-        // Send number of sectors
-        let mut port = UnsafePort::<u8>::new(PORT_SECCOUNT);
-        port.write(sectors);
-
-        // Send bits 0-7 of LBA
-        let mut port = UnsafePort::<u8>::new(PORT_LBA0);
-        port.write((lba & 0xFF) as u8);
-
-        // Send bits 8-15 of LBA
-        let mut port = UnsafePort::<u8>::new(PORT_LBA1);
-        port.write(((lba & 0xFF00) >> 0x8) as u8);
-
-        // Send bits 16-23 of LBA
-        let mut port = UnsafePort::<u8>::new(PORT_LBA2);
-        port.write(((lba & 0xFF0000) >> 0x10) as u8);
+        let lba48 = self.setup_transfer(lba, sectors)?;
 
         // Send command
-        Self::send_command(0x30); // Read with retry
+        self.send_command(if lba48 { COMMAND_WRITE_EXT } else { COMMAND_WRITE });
 
-        Self::wait_ready();
-
-        let mut data_port = UnsafePort::<u16>::new(PORT_DATA);
+        let mut data_port = UnsafePort::<u16>::new(self.base + REG_DATA);
         let u16_per_sector = SECTOR_SIZE / 2;
-        // Not sure if this code works
-        /*for i in data.iter() {
-            data_port.write(*i);
-        }*/
         for i in 0..sectors {
+            // Same as reading - the drive has to ask for each sector
+            self.wait_drq()?;
             for j in 0..u16_per_sector {
                 let word = data.get(i as usize*u16_per_sector + j);
                 let word=  match word {
@@ -346,7 +804,164 @@ impl AtaPio {
             }
         }
 
+        // The drive says if it worked once it's done with the data
+        self.wait_ready()?;
+        self.check_error()
+    }
+
+    // Tells the drive to write out anything it's still holding onto
+    unsafe fn cache_flush(&self) -> Result<(), AtaError> {
+        self.select_drive(0b11100000);
+        self.wait_ready()?;
+        // Drives that know about LBA48 might be holding onto sectors LBA28 can't reach
+        self.send_command(if self.properties.supports_lba48() { COMMAND_CACHE_FLUSH_EXT } else { COMMAND_CACHE_FLUSH });
+        self.wait_ready()?;
+        self.check_error()
+    }
+}
+
+// The same transfers as the BlockDevice ones, but waiting on the drive's interrupts instead of spinning on its status,
+// so the executor gets to run everything else while the drive is busy
+impl AtaPio {
+    pub async fn read_blocks_async(&self, block_id: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        check_range(self, block_id, buf.len())?;
+        let _claim = self.claim_async().await;
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = block_id + (i * MAX_SECTORS) as u64;
+            let mut attempts = 0;
+            loop {
+                match self.read_async(lba, chunk).await {
+                    Ok(()) => break,
+                    Err(err) if self.give_up(err, attempts, "read", lba) => return Err(err.into()),
+                    Err(_) => attempts += 1,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn write_blocks_async(&self, block_id: u64, data: &[u8]) -> Result<(), FsError> {
+        check_range(self, block_id, data.len())?;
+        let _claim = self.claim_async().await;
+        for (i, chunk) in data.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = block_id + (i * MAX_SECTORS) as u64;
+            let mut attempts = 0;
+            loop {
+                match self.write_async(lba, chunk).await {
+                    Ok(()) => break,
+                    Err(err) if self.give_up(err, attempts, "write", lba) => return Err(err.into()),
+                    Err(_) => attempts += 1,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn flush_async(&self) -> Result<(), FsError> {
+        let _claim = self.claim_async().await;
+        unsafe {
+            self.select_drive(0b11100000);
+            self.wait_ready()?;
+            self.start_command(if self.properties.supports_lba48() { COMMAND_CACHE_FLUSH_EXT } else { COMMAND_CACHE_FLUSH });
+        }
+        Interrupt::new(self.bus()).await?;
+        unsafe { self.check_error()?; }
+        Ok(())
+    }
+
+    // Sends a command whose interrupts are going to be waited for
+    // Anything left over from before is forgotten first, so it doesn't look like this command is already done
+    unsafe fn start_command(&self, command: u8) {
+        INTERRUPTS[self.bus()].fired.store(false, Ordering::SeqCst);
+        self.send_command(command);
+    }
+
+    // Up to MAX_SECTORS sectors, in one command
+    async fn read_async(&self, lba: u64, buf: &mut [u8]) -> Result<(), AtaError> {
+        unsafe {
+            self.select_drive(0b11100000);
+            self.wait_ready()?;
+            let lba48 = self.setup_transfer(lba, (buf.len() / SECTOR_SIZE) as u16)?;
+            self.start_command(if lba48 { COMMAND_READ_EXT } else { COMMAND_READ });
+        }
+        for sector in buf.chunks_mut(SECTOR_SIZE) {
+            Interrupt::new(self.bus()).await?;
+            unsafe {
+                self.wait_drq()?;
+                let mut data_port = UnsafePort::<u16>::new(self.base + REG_DATA);
+                for pair in sector.chunks_mut(2) {
+                    let word: u16 = data_port.read();
+                    pair[0] = (word & 0xFF) as u8;
+                    pair[1] = (word >> 8) as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_async(&self, lba: u64, data: &[u8]) -> Result<(), AtaError> {
+        unsafe {
+            self.select_drive(0b11100000);
+            self.wait_ready()?;
+            let lba48 = self.setup_transfer(lba, (data.len() / SECTOR_SIZE) as u16)?;
+            self.start_command(if lba48 { COMMAND_WRITE_EXT } else { COMMAND_WRITE });
+        }
+        for (i, sector) in data.chunks(SECTOR_SIZE).enumerate() {
+            // The drive asks for the first sector straight away - there's only an interrupt once it's taken one
+            if i > 0 {
+                Interrupt::new(self.bus()).await?;
+            }
+            unsafe {
+                self.wait_drq()?;
+                let mut data_port = UnsafePort::<u16>::new(self.base + REG_DATA);
+                for pair in sector.chunks(2) {
+                    data_port.write(((pair[1] as u16) << 8) | pair[0] as u16);
+                }
+            }
+        }
+        // The last interrupt is for the drive being done with all of it
+        Interrupt::new(self.bus()).await?;
+        unsafe { self.check_error() }
+    }
+}
+
+// Up to MAX_SECTORS sectors go in each command
+impl BlockDevice for AtaPio {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
 
+    fn block_count(&self) -> u64 {
+        self.capacity_sectors()
+    }
 
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        check_range(self, block_id, buf.len())?;
+        let _claim = self.claim()?;
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = block_id + (i * MAX_SECTORS) as u64;
+            let sectors = (chunk.len() / SECTOR_SIZE) as u16;
+            let data = self.retry("read", lba, || unsafe { self.read_lba(lba, sectors) })?;
+            chunk.copy_from_slice(&data);
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+
+    // The drive takes 16 bits at a time
+    fn write_blocks(&mut self, block_id: u64, data: &[u8]) -> Result<(), FsError> {
+        check_range(self, block_id, data.len())?;
+        let _claim = self.claim()?;
+        for (i, chunk) in data.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = block_id + (i * MAX_SECTORS) as u64;
+            let words: Vec<u16> = chunk.chunks(2).map(|pair| ((pair[1] as u16) << 8) | pair[0] as u16).collect();
+            let sectors = (chunk.len() / SECTOR_SIZE) as u16;
+            self.retry("write", lba, || unsafe { self.write(lba, sectors, &words) })?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        let _claim = self.claim()?;
+        unsafe { self.cache_flush() }.map_err(FsError::from)
+    }
+}
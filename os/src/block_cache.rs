@@ -0,0 +1,267 @@
+// Write-back block cache
+// Sits between a file system and the device it's on. Blocks that get read stay in memory until they're the ones that
+// were used longest ago and something else needs the room, and writes stay in memory until sync - so editing the same
+// file over and over doesn't go to the disk every time.
+//
+// A flush doesn't write anything here, it just starts a new batch of writes. The batches go out oldest first, with a
+// flush of the device after each one, so anything written before a flush still reaches the disk before anything written
+// after it. That's all the journal needs - it just happens at sync instead of straight away
+//
+// The write-back task can also take the oldest batch and write it out in the background - see task/writeback.rs
+
+use alloc::collections::vec_deque::VecDeque;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use hashbrown::HashMap;
+use crate::block_device::{check_range, BlockDevice};
+use crate::vfs::FsError;
+
+// How many blocks the file systems on the disks keep in memory - 1 MiB of 512 byte blocks
+pub const CACHE_BLOCKS: usize = 2048;
+
+pub struct BlockCache<D: BlockDevice> {
+    device: D,
+    // The most blocks to hold onto - past this, clean ones get thrown out and dirty ones get written out
+    capacity: usize,
+    // Blocks that are the same as what's on the device, and when they were last used
+    clean: HashMap<u64, (Vec<u8>, u64)>,
+    // Writes that haven't been to the device yet, split up by flushes, oldest first - there's always at least one
+    // Each batch is in block order, so blocks next to each other can go out together
+    dirty: VecDeque<BTreeMap<u64, Vec<u8>>>,
+    dirty_blocks: usize,
+    // Goes up every time a block is used
+    clock: u64,
+    // The oldest batch has been handed out to be written in the background, and isn't back yet
+    writing: bool,
+}
+
+impl<D: BlockDevice> BlockCache<D> {
+    pub fn new(device: D, capacity: usize) -> BlockCache<D> {
+        let mut dirty = VecDeque::new();
+        dirty.push_back(BTreeMap::new());
+        BlockCache {
+            device,
+            capacity: capacity.max(1),
+            clean: HashMap::new(),
+            dirty,
+            dirty_blocks: 0,
+            clock: 0,
+            writing: false,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    // Takes the device back out - anything that wasn't synced is lost, like when the power goes
+    pub fn into_device(self) -> D {
+        self.device
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Whatever doesn't fit anymore gets thrown out, or written out if it has to be
+    pub fn set_capacity(&mut self, capacity: usize) -> Result<(), FsError> {
+        self.capacity = capacity.max(1);
+        self.make_room()
+    }
+
+    // How many blocks are waiting for a sync
+    pub fn dirty_blocks(&self) -> usize {
+        self.dirty_blocks
+    }
+
+    // The newest copy of a block that's in memory, if there is one
+    fn newest(&self, block_id: u64) -> Option<&Vec<u8>> {
+        for batch in self.dirty.iter().rev() {
+            if let Some(data) = batch.get(&block_id) {
+                return Some(data);
+            }
+        }
+        match self.clean.get(&block_id) {
+            Some((data, _)) => Some(data),
+            None => None,
+        }
+    }
+
+    // Moves a clean block to the back of the line for getting thrown out
+    fn touch(&mut self, block_id: u64) {
+        self.clock += 1;
+        if let Some(entry) = self.clean.get_mut(&block_id) {
+            entry.1 = self.clock;
+        }
+    }
+
+    // The oldest batch, as runs of blocks that are next to each other - (first block, data)
+    fn oldest_runs(&self) -> Vec<(u64, Vec<u8>)> {
+        let size = self.device.block_size();
+        let mut runs: Vec<(u64, Vec<u8>)> = Vec::new();
+        if let Some(batch) = self.dirty.front() {
+            for (block_id, data) in batch.iter() {
+                match runs.last_mut() {
+                    Some((start, run)) if *block_id == *start + (run.len() / size) as u64 => run.extend_from_slice(data),
+                    _ => runs.push((*block_id, data.clone())),
+                }
+            }
+        }
+        runs
+    }
+
+    // Writes the oldest batch to the device
+    // One that's being written in the background can't go out twice, or be overtaken by the one after it
+    fn write_oldest(&mut self) -> Result<(), FsError> {
+        if self.writing {
+            return Err(FsError::Busy);
+        }
+        for (start, run) in self.oldest_runs() {
+            self.device.write_blocks(start, &run)?;
+        }
+        self.device.flush()?;
+        self.forget_oldest();
+        Ok(())
+    }
+
+    // The oldest batch made it to the device, so its blocks are clean, unless a later batch has them too
+    // Only called once it's made it, so a sync that fails can be tried again
+    fn forget_oldest(&mut self) {
+        let batch = match self.dirty.pop_front() {
+            Some(batch) => batch,
+            None => return,
+        };
+        if self.dirty.is_empty() {
+            self.dirty.push_back(BTreeMap::new());
+        }
+        self.dirty_blocks -= batch.len();
+        for (block_id, data) in batch.into_iter() {
+            if !self.dirty.iter().any(|later| later.contains_key(&block_id)) {
+                self.clock += 1;
+                self.clean.insert(block_id, (data, self.clock));
+            }
+        }
+    }
+
+    // Hands the oldest batch out to be written somewhere else, like the write-back task - it stays here until
+    // finish_write_back, so reads still see it and nothing that comes after it can get to the device first
+    pub fn start_write_back(&mut self) -> Option<Vec<(u64, Vec<u8>)>> {
+        if self.writing || self.dirty_blocks == 0 {
+            return None;
+        }
+        // Anything written from here on goes in the next batch, so this one can't change while it's out
+        if self.dirty.len() == 1 {
+            self.dirty.push_back(BTreeMap::new());
+        }
+        self.writing = true;
+        Some(self.oldest_runs())
+    }
+
+    // result is how writing the batch from start_write_back went, which has to have included a flush
+    // If it didn't work the batch is still dirty, and goes out with the next sync or write-back
+    pub fn finish_write_back(&mut self, result: Result<(), FsError>) -> Result<(), FsError> {
+        if !self.writing {
+            return Err(FsError::InvalidArgument);
+        }
+        self.writing = false;
+        result?;
+        self.forget_oldest();
+        self.make_room()
+    }
+
+    // Gets everything back under capacity - writes go out if there are too many of them, then the clean blocks that
+    // were used longest ago are thrown out
+    fn make_room(&mut self) -> Result<(), FsError> {
+        // Writes that are already going out in the background have to finish first, so it's over capacity until then
+        while self.dirty_blocks > self.capacity && !self.writing {
+            // Writing out the batch that's still going is the same as a flush happening here, which is always fine
+            self.write_oldest()?;
+        }
+        let total = self.clean.len() + self.dirty_blocks;
+        if total > self.capacity {
+            let mut by_age: Vec<(u64, u64)> = self.clean.iter().map(|(block_id, (_, used))| (*used, *block_id)).collect();
+            by_age.sort_unstable();
+            for (_, block_id) in by_age.iter().take(total - self.capacity) {
+                self.clean.remove(block_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BlockCache<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        check_range(self, block_id, buf.len())?;
+        let size = self.block_size();
+        let count = buf.len() / size;
+        // Everything from the first block that isn't in memory to the last one is read in one go
+        let missing: Vec<usize> = (0..count).filter(|i| self.newest(block_id + *i as u64).is_none()).collect();
+        if let (Some(first), Some(last)) = (missing.first(), missing.last()) {
+            let mut data = vec![0; (last - first + 1) * size];
+            self.device.read_blocks(block_id + *first as u64, &mut data)?;
+            for (i, block) in data.chunks(size).enumerate() {
+                let id = block_id + (first + i) as u64;
+                if self.newest(id).is_none() {
+                    self.clean.insert(id, (block.to_vec(), 0));
+                }
+            }
+        }
+        for (i, chunk) in buf.chunks_mut(size).enumerate() {
+            let id = block_id + i as u64;
+            self.touch(id);
+            match self.newest(id) {
+                Some(data) => chunk.copy_from_slice(data),
+                None => return Err(FsError::Io),
+            }
+        }
+        self.make_room()
+    }
+
+    fn write_blocks(&mut self, block_id: u64, data: &[u8]) -> Result<(), FsError> {
+        check_range(self, block_id, data.len())?;
+        let size = self.block_size();
+        for (i, block) in data.chunks(size).enumerate() {
+            let id = block_id + i as u64;
+            // Writing what's already there doesn't need to go anywhere - like the end of the archive, every time
+            if let Some(cached) = self.newest(id) {
+                if &cached[..] == block {
+                    self.touch(id);
+                    continue;
+                }
+            }
+            self.clean.remove(&id);
+            if let Some(batch) = self.dirty.back_mut() {
+                if batch.insert(id, block.to_vec()).is_none() {
+                    self.dirty_blocks += 1;
+                }
+            }
+        }
+        self.make_room()
+    }
+
+    // Starts a new batch - nothing actually has to go anywhere yet
+    fn flush(&mut self) -> Result<(), FsError> {
+        if let Some(batch) = self.dirty.back() {
+            if !batch.is_empty() {
+                self.dirty.push_back(BTreeMap::new());
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        while self.dirty_blocks > 0 {
+            self.write_oldest()?;
+        }
+        self.device.sync()
+    }
+}
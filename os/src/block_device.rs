@@ -0,0 +1,99 @@
+// Block devices
+// Anything the file systems can sit on - the ATA disks, partitions on them, or a disk that only exists in memory.
+// Everything above this only ever deals in whole blocks, so it doesn't care where they end up
+
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::vfs::FsError;
+
+pub trait BlockDevice {
+    // Bytes in a block - buffers passed to read_blocks and write_blocks are always a multiple of this
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    // Fills buf with the blocks starting at block_id
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), FsError>;
+
+    // Writes data to the blocks starting at block_id
+    fn write_blocks(&mut self, block_id: u64, data: &[u8]) -> Result<(), FsError>;
+
+    // Makes sure everything written before this reaches the device before anything written after it - which for
+    // anything that doesn't hold onto writes itself means writing it out now
+    fn flush(&mut self) -> Result<(), FsError>;
+
+    // Makes sure everything that was written is actually on the device, and not in a cache somewhere
+    fn sync(&mut self) -> Result<(), FsError> {
+        self.flush()
+    }
+
+    // Reads one block into a new buffer, for when there's nowhere to put it already
+    fn read_block(&mut self, block_id: u64) -> Result<Vec<u8>, FsError> {
+        let mut block = vec![0; self.block_size()];
+        self.read_blocks(block_id, &mut block)?;
+        Ok(block)
+    }
+}
+
+// Checks that a transfer of length bytes at block_id is whole blocks and stays on the device
+pub fn check_range<D: BlockDevice + ?Sized>(device: &D, block_id: u64, length: usize) -> Result<(), FsError> {
+    let size = device.block_size();
+    if length % size != 0 || block_id.saturating_add((length / size) as u64) > device.block_count() {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+// A disk that lives on the heap - everything on it is gone when it's dropped
+#[derive(Clone)]
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    // An empty disk of blocks 512 byte blocks
+    pub fn new(blocks: u64) -> RamDisk {
+        RamDisk::from_bytes(vec![0; blocks as usize * 512])
+    }
+
+    // A disk with data on it already, like an image that was made somewhere else - the end of it is padded out to a
+    // whole block
+    pub fn from_bytes(mut data: Vec<u8>) -> RamDisk {
+        let blocks = (data.len() + 511) / 512;
+        data.resize(blocks * 512, 0);
+        RamDisk { data }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.len() / 512) as u64
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        check_range(self, block_id, buf.len())?;
+        let start = block_id as usize * 512;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, block_id: u64, data: &[u8]) -> Result<(), FsError> {
+        check_range(self, block_id, data.len())?;
+        let start = block_id as usize * 512;
+        self.data[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    // There's nowhere else for it to go
+    fn flush(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
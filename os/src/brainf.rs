@@ -4,7 +4,7 @@ use crate::vga_buffer::{ADVANCED_WRITER, WRITER, MODE};
 use alloc::vec::Vec;
 use alloc::vec;
 use alloc::string::String;
-use crate::ustar::USTARFS;
+use crate::vfs::VFS;
 use crate::println;
 use crate::keyboard_routing::KEYBOARD_ROUTER;
 use x86_64::instructions::interrupts;
@@ -186,7 +186,7 @@ impl BrainF {
     }
 
     pub fn init_keyboard(&mut self, file: String, id: Option<u64>) {
-        if let Some(data) = USTARFS.lock().read_file(file.to_string(), id) {
+        if let Ok(data) = VFS.lock().read_file(file.to_string(), id) {
             self.instructions = data;
             // Init the keyboard stuff
             interrupts::without_interrupts(|| {
@@ -220,8 +220,8 @@ impl BrainF {
     }
 
     pub fn init_file(&mut self, file: String, file2: String, id: Option<u64>) {
-        if let Some(data) = USTARFS.lock().read_file(file.to_string(), id) {
-            if  let Some(input) = USTARFS.lock().read_file(file2.to_string(), id)  {
+        if let Ok(data) = VFS.lock().read_file(file.to_string(), id) {
+            if  let Ok(input) = VFS.lock().read_file(file2.to_string(), id)  {
                 self.instructions = data;
                 self.input_buffer = VecDeque::from(input);
                 // Init the keyboard stuff
@@ -262,7 +262,7 @@ impl BrainF {
             else {
                 ADVANCED_WRITER.lock().enable_blink();
             }
-            //print!("[user@rust {}]# ", USTARFS.lock().cwd(COMMANDRUNNER.lock().dir_id));
+            //print!("[user@rust {}]# ", VFS.lock().cwd(COMMANDRUNNER.lock().dir_id));
         });
     }
 
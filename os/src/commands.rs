@@ -7,14 +7,20 @@ use crate::vga_buffer::{MODE, BUFFER_HEIGHT, BUFFER_HEIGHT_ADVANCED, ADVANCED_WR
 use vga::colors::Color16;
 use x86_64::instructions::interrupts;
 use alloc::vec::Vec;
+use alloc::format;
 use crate::tetris::TETRIS;
-use crate::ustar::USTARFS;
+use crate::vfs::{VFS, FileType};
 use crate::alloc::string::ToString;
 use crate::play_beep;
 use crate::play_tet_ost;
 use crate::vi::FAKE_VIM;
 use x86::io::outw;
 use crate::brainf::BRAINF;
+use crate::rtc::{self, DateTime};
+use crate::users::{self, User};
+use crate::partition;
+use crate::ustar::USTARFS;
+use crate::task::writeback;
 
 pub fn from_str(input: &str) -> Result<Color16, &str> {
     match input {
@@ -69,7 +75,7 @@ impl CommandRunner {
     }
 
     pub fn init(&mut self) {
-        self.dir_id = USTARFS.lock().get_id();
+        self.dir_id = VFS.lock().get_id();
     }
 
     // Add a character to the command buffer.
@@ -81,7 +87,7 @@ impl CommandRunner {
         if c == '\n' {
             // If the char is a newline, evaluate the buffer
             self.eval_buffer();
-            print!("[user@rust {}]# ", USTARFS.lock().cwd(self.dir_id));
+            print!("[user@rust {}]# ", VFS.lock().cwd(self.dir_id));
             if MODE.lock().text {
                 self.prompt_length = WRITER.lock().get_column_position();
             } else {
@@ -220,6 +226,24 @@ impl CommandRunner {
             "vim"=>self.vim_help(),
             "proot"=>self.proot_help(),
             "brainf"=>self.brainf_help(),
+            "mv"=>self.mv_help(),
+            "cp"=>self.cp_help(),
+            "ln"=>self.ln_help(),
+            "date"=>self.date_help(),
+            "chmod"=>self.chmod_help(),
+            "chown"=>self.chown_help(),
+            "chgrp"=>self.chgrp_help(),
+            "whoami"=>self.whoami_help(),
+            "su"=>self.su_help(),
+            "mount"=>self.mount_help(),
+            "umount"=>self.umount_help(),
+            "fsck"=>self.fsck_help(),
+            "trash"=>self.trash_help(),
+            "undelete"=>self.undelete_help(),
+            "lsblk"=>self.lsblk_help(),
+            "sync"=>self.sync_help(),
+            "mkfs"=>self.mkfs_help(),
+            "cache"=>self.cache_help(),
             _=>print!("\nThat command doesn't exist.\n"),
         }
     }
@@ -254,6 +278,24 @@ impl CommandRunner {
         println!("exit");
         print!("vim, ");
         print!("brainf, ");
+        print!("mv, ");
+        print!("cp, ");
+        print!("ln, ");
+        println!("date");
+        print!("chmod, ");
+        print!("chown, ");
+        print!("chgrp, ");
+        print!("whoami, ");
+        println!("su");
+        print!("mount, ");
+        print!("umount, ");
+        print!("fsck, ");
+        print!("trash, ");
+        print!("undelete, ");
+        print!("lsblk, ");
+        print!("sync, ");
+        print!("mkfs, ");
+        println!("cache");
         println!("\nFor specific options try 'help <command name>'\n");
         println!("You can also run multiple commands at the same time by separating them with a semi-colon ';'\n");
     }
@@ -332,15 +374,15 @@ impl CommandRunner {
     // Describes and displays options for the ls command
     fn ls_help(&self) {
         println!("\nCommand: ls");
-        println!("Lists all of the files and directories in the current directory.");
-        println!("No defined arguments, everything after ls will be ignored.");
+        println!("Lists all of the files and directories in the current directory, or in the directory it's given.");
+        println!("ls -l also shows the type, size and last modified time of each one.");
     }
 
     // Describes and displays options for the dir command
     fn dir_help(&self) {
         println!("\nCommand: dir");
-        println!("Lists all of the files and directories in the current directory.");
-        println!("No defined arguments, everything after dir will be ignored.");
+        println!("Lists all of the files and directories in the current directory, or in the directory it's given.");
+        println!("dir -l also shows the type, size and last modified time of each one.");
     }
 
     // Describes and displays options for the cd command
@@ -361,21 +403,23 @@ impl CommandRunner {
     // Describes and displays options for the rmdir command
     fn rmdir_help(&self) {
         println!("\nCommand: rmdir");
-        println!("Deletes the specified directory.");
+        println!("Deletes the specified directory. It goes in the trash, so 'undelete' can bring it back.");
         println!("One defined argument, the name of the directory to be deleted.");
     }
 
     // Describes and displays options for the defrag command
     fn defrag_help(&self) {
         println!("\nCommand: defrag");
-        println!("Runs a defragmentation process on system memory, cleaning up memory.");
+        println!("Packs the files on disk together. Deleted space gets reused anyway, so this is optional.");
+        println!("Everything in the trash is gone for good afterwards.");
+        println!("What moved goes to the disk in the background, the same as sync.");
         println!("No defined arguments, everything after defrag will be ignored.");
     }
 
     // Describes and displays options for the rm command
     fn rm_help(&self) {
         println!("\nCommand: rm");
-        println!("Deletes the specified file.");
+        println!("Deletes the specified file. It goes in the trash, so 'undelete' can bring it back.");
         println!("One defined argument, the name of the file to be deleted.");
     }
 
@@ -396,10 +440,19 @@ impl CommandRunner {
     // Describes and displays options for the write command
     fn write_help(&self) {
         println!("\nCommand: write");
-        println!("Writes the current changes.");
+        println!("Writes the current changes on every mounted file system. The same as sync.");
         println!("No defined arguments, everything after write will be ignored.");
     }
 
+    // Describes and displays options for the sync command
+    fn sync_help(&self) {
+        println!("\nCommand: sync");
+        println!("Writes everything that's waiting in memory out to the disks - changes to files only go to memory until then.");
+        println!("The disk under / gets written in the background, so you can keep typing - the next command waits until it's done.");
+        println!("exit and umount do this too, but they wait for it.");
+        println!("No defined arguments, everything after sync will be ignored.");
+    }
+
     // Describes and displays options for the write command
     fn touch_help(&self) {
         println!("\nCommand: touch");
@@ -417,7 +470,7 @@ impl CommandRunner {
     // Describes and displays options for the exit command
     fn shut_down_help(&self) {
         println!("\nCommand: exit");
-        println!("Shuts down the system.");
+        println!("Shuts down the system, after writing everything out to the disks.");
         println!("No defined arguments, everything after exit will be ignored.");
         println!("ONLY WORKS FOR QEMU, NOT REAL HARDWARE");
     }
@@ -442,7 +495,7 @@ impl CommandRunner {
 
     fn proot_help(&self){
         println!("\nCommand: proot");
-        println!("Prints the directory tree");
+        println!("Prints the directory tree, across everything that's mounted.");
     }
 
     fn brainf_help(&self){
@@ -451,6 +504,117 @@ impl CommandRunner {
         println!("One defined argument: File to load brainf code from");
     }
 
+    fn mv_help(&self){
+        println!("\nCommand: mv");
+        println!("Moves or renames a file or directory.");
+        println!("Two defined arguments: the path to move, and either the new path or a directory to move it into.");
+    }
+
+    fn cp_help(&self){
+        println!("\nCommand: cp");
+        println!("Copies a file, use 'cp -r' to copy a directory and everything in it.");
+        println!("Two defined arguments: the path to copy, and either the new path or a directory to copy it into.");
+    }
+
+    // Describes and displays options for the ln command
+    fn ln_help(&self){
+        println!("\nCommand: ln");
+        println!("Makes a hard link to a file, use 'ln -s' to make a symbolic link instead.");
+        println!("Two defined arguments: what the link points at, and either the path of the link or a directory to put it in.");
+    }
+
+    // Describes and displays options for the date command
+    fn date_help(&self){
+        println!("\nCommand: date");
+        println!("Prints the current date and time (UTC) from the real time clock.");
+        println!("No defined arguments, everything after date will be ignored.");
+    }
+
+    // Describes and displays options for the chmod command
+    fn chmod_help(&self){
+        println!("\nCommand: chmod");
+        println!("Changes who can read, write and execute a file or directory. Only its owner or root can do this.");
+        println!("Two defined arguments: the mode in octal (like 644), and the path.");
+    }
+
+    // Describes and displays options for the chown command
+    fn chown_help(&self){
+        println!("\nCommand: chown");
+        println!("Gives a file or directory to a different user. Only root can do this.");
+        println!("Two defined arguments: the user (a name or a number), and the path.");
+    }
+
+    // Describes and displays options for the chgrp command
+    fn chgrp_help(&self){
+        println!("\nCommand: chgrp");
+        println!("Changes the group of a file or directory. Its owner can only change it to their own group, root can use any.");
+        println!("Two defined arguments: the group (a name or a number), and the path.");
+    }
+
+    // Describes and displays options for the whoami command
+    fn whoami_help(&self){
+        println!("\nCommand: whoami");
+        println!("Prints the name of the current user.");
+        println!("No defined arguments, everything after whoami will be ignored.");
+    }
+
+    // Describes and displays options for the su command
+    fn su_help(&self){
+        println!("\nCommand: su");
+        println!("Switches to a different user, root if no user is given.");
+        println!("One defined argument: the user (a name like weed, or a number).");
+    }
+
+    fn mount_help(&self){
+        println!("\nCommand: mount");
+        println!("mount <type> <path> [device] mounts a new file system at path. The types are ustar, a tar archive, tmpfs, which only lives in memory, procfs and devfs.");
+        println!("For ustar, device is a disk like hdb or a partition on it like hdb1, and is the one at / if it's left out.");
+        println!("A tmpfs is always mounted at /tmp, and everything on it is gone on a reboot.");
+        println!("procfs is always mounted at /proc, and has files about the kernel: uptime, heap, tasks, keyboard, timer, drives and memmap.");
+        println!("devfs is always mounted at /dev, with the disks, ttyS0, speaker, fb0 and null.");
+        println!("With no arguments, lists everything that's mounted and where.");
+    }
+
+    fn umount_help(&self){
+        println!("\nCommand: umount");
+        println!("umount <path> unmounts the file system at path. / can't be unmounted, and neither can anything you're in.");
+    }
+
+    fn fsck_help(&self){
+        println!("\nCommand: fsck");
+        println!("fsck [-r] [path] checks the file system that path is on (/ if there's no path) for damage.");
+        println!("With -r, it also fixes what it can. Fixed headers show up after a reboot.");
+    }
+
+    fn trash_help(&self){
+        println!("\nCommand: trash");
+        println!("trash [list] shows everything that's been removed, with when it was removed. trash empty gets rid of it for good.");
+        println!("When the disk is full, the oldest things in the trash go first. Anything whose path doesn't fit in the trash is gone for good, and rm says so.");
+    }
+
+    fn undelete_help(&self){
+        println!("\nCommand: undelete");
+        println!("undelete <path> puts back whatever was last removed from path. A directory comes back with everything in it.");
+    }
+
+    fn lsblk_help(&self){
+        println!("\nCommand: lsblk");
+        println!("Lists the disks, hda to hdd, with the partitions on each one, how big they are and where they're mounted.");
+        println!("Any of them can be mounted with mount ustar <path> <name>.");
+    }
+
+    fn cache_help(&self){
+        println!("\nCommand: cache");
+        println!("Shows how many blocks the file system at / keeps in memory, and how many of them haven't been written yet.");
+        println!("cache <blocks> changes how many it keeps - a smaller cache writes to the disk more often.");
+    }
+
+    fn mkfs_help(&self){
+        println!("\nCommand: mkfs");
+        println!("mkfs <device> makes a disk or partition, like hdc or hdb1, into an empty file system that can be mounted.");
+        println!("Everything that was on it is gone. It can't be mounted while this happens, and neither can its partitions.");
+    }
+
     // beep command
     // Calls the pcspeaker and plays a beep for 2 cycles
     pub fn beep(&self, args: &str) {
@@ -524,46 +688,97 @@ impl CommandRunner {
         }
     }
 
-    pub fn ls(&self) {
-        println!();
-        for i in USTARFS.lock().list_files(self.dir_id) {
-            println!("{}", i);
+    pub fn ls(&self, args: &str) {
+        let mut args: Vec<&str> = args.split_whitespace().collect();
+        let long = !args.is_empty() && args[0] == "-l";
+        if long {
+            args.remove(0);
         }
-        for i in USTARFS.lock().list_subdirectories(self.dir_id) {
-            println!("{}", i);
+        let path = match args.first() {
+            Some(path) => path.to_string(),
+            None => ".".to_string(),
+        };
+        let entries = match VFS.lock().list_directory(path, Some(self.dir_id)) {
+            Ok(entries) => entries,
+            Err(err) => {
+                println!("\nCouldn't list {}: {}", args.first().unwrap_or(&"."), err);
+                return;
+            },
+        };
+        println!();
+        for i in entries {
+            if !long {
+                if i.file_type == FileType::Directory {
+                    println!("{}/", i.name);
+                }
+                else {
+                    println!("{}", i.name);
+                }
+                continue;
+            }
+            let kind = match i.file_type {
+                FileType::Directory => 'd',
+                FileType::Symlink => 'l',
+                FileType::File => '-',
+                FileType::CharDevice => 'c',
+                FileType::BlockDevice => 'b',
+            };
+            print!("{}{} {} {} {:>8} {} {}", kind, mode_string(i.permissions.mode), i.permissions.owner_name, i.permissions.group_name, 
+                i.size, DateTime::from_unix(i.mtime), i.name);
+            match i.linked_name {
+                Some(target) => println!(" -> {}", target),
+                None => println!(),
+            }
         }
     }
 
     pub fn cd(&self, args: &str) {
-        USTARFS.lock().change_directory(args.to_string(), self.dir_id);
+        if let Err(err) = VFS.lock().change_directory(args.to_string(), self.dir_id) {
+            println!("\nCouldn't go into {}: {}", args, err);
+        }
     }
 
     pub fn mkdir(&self, args: &str) {
-        USTARFS.lock().create_directory(args.to_string(), self.dir_id);
+        if let Err(err) = VFS.lock().create_directory(args.to_string(), Some(self.dir_id)) {
+            println!("\nCouldn't make {}: {}", args, err);
+        }
     }
 
     pub fn rmdir(&self, args: &str) {
-        USTARFS.lock().remove_directory(args.to_string(), Some(self.dir_id));
+        if let Err(err) = VFS.lock().remove_directory(args.to_string(), Some(self.dir_id)) {
+            println!("\nCouldn't remove {}: {}", args, err);
+        }
     }
 
+    // Everything gets moved in the cache, then the write-back task puts it on the disk
     pub fn defrag(&self) {
-        USTARFS.lock().defragment();
+        if let Err(err) = VFS.lock().defragment() {
+            println!("\nCouldn't defragment: {}", err);
+        }
+        writeback::request();
     }
 
     pub fn rm(&self, args: &str) {
-        USTARFS.lock().remove_file(args.to_string(), Some(self.dir_id));
+        if let Err(err) = VFS.lock().remove_file(args.to_string(), Some(self.dir_id)) {
+            println!("\nCouldn't remove {}: {}", args, err);
+        }
     }
 
     pub fn touchhello(&self, args: &str) {
         let data = String::from("Hello World!");
         let data = data.into_bytes();
-        USTARFS.lock().write_file(args.to_string(), data, Some(self.dir_id));    
+        if let Err(err) = VFS.lock().write_file(args.to_string(), data, Some(self.dir_id)) {
+            println!("\nCouldn't write {}: {}", args, err);
+        }
     }
 
     pub fn cat(&self, args: &str) {
-        let data = match USTARFS.lock().read_file(args.to_string(), Some(self.dir_id)) {
-            Some(data) => data,
-            None => Vec::new(),
+        let data = match VFS.lock().read_file(args.to_string(), Some(self.dir_id)) {
+            Ok(data) => data,
+            Err(err) => {
+                println!("\nCouldn't read {}: {}", args, err);
+                return;
+            },
         };
         println!();
         for i in data.iter() {
@@ -572,18 +787,317 @@ impl CommandRunner {
         println!();
     }
 
-    pub fn write(&self) {
-        USTARFS.lock().write();
+    // The disk under / gets written in the background, and the next command waits for it
+    pub fn sync(&self) {
+        if let Err(err) = VFS.lock().start_sync() {
+            println!("\nCouldn't write everything to the disk: {}", err);
+        }
+        writeback::request();
     }
 
     pub fn touch(&self, args: &str) {
         let data = String::from(" ");
         let data = data.into_bytes();
-        USTARFS.lock().write_file(args.to_string(), data, Some(self.dir_id));
+        if let Err(err) = VFS.lock().write_file(args.to_string(), data, Some(self.dir_id)) {
+            println!("\nCouldn't write {}: {}", args, err);
+        }
     }
 
+    pub fn mv(&self, args: &str) {
+        let args: Vec<&str> = args.split_whitespace().collect();
+        if args.len() != 2 {
+            println!("\nUsage: mv <source> <destination>");
+            return;
+        }
+        let moved = VFS.lock().move_item(args[0].to_string(), args[1].to_string(), Some(self.dir_id));
+        if let Err(err) = moved {
+            println!("\nCouldn't move {} to {}: {}", args[0], args[1], err);
+        }
+    }
+
+    pub fn cp(&self, args: &str) {
+        let mut args: Vec<&str> = args.split_whitespace().collect();
+        let recursive = !args.is_empty() && args[0] == "-r";
+        if recursive {
+            args.remove(0);
+        }
+        if args.len() != 2 {
+            println!("\nUsage: cp [-r] <source> <destination>");
+            return;
+        }
+        let copied = VFS.lock().copy(args[0].to_string(), args[1].to_string(), recursive, Some(self.dir_id));
+        if let Err(err) = copied {
+            println!("\nCouldn't copy {} to {}: {}", args[0], args[1], err);
+        }
+    }
+
+    pub fn ln(&self, args: &str) {
+        let mut args: Vec<&str> = args.split_whitespace().collect();
+        let symbolic = !args.is_empty() && args[0] == "-s";
+        if symbolic {
+            args.remove(0);
+        }
+        if args.len() != 2 {
+            println!("\nUsage: ln [-s] <target> <link name>");
+            return;
+        }
+        if let Err(err) = VFS.lock().create_link(args[0].to_string(), args[1].to_string(), symbolic, Some(self.dir_id)) {
+            println!("\nCouldn't link {} to {}: {}", args[1], args[0], err);
+        }
+    }
+
+    pub fn chmod(&self, args: &str) {
+        let args: Vec<&str> = args.split_whitespace().collect();
+        if args.len() != 2 {
+            println!("\nUsage: chmod <mode in octal> <path>");
+            return;
+        }
+        let mode = match u64::from_str_radix(args[0], 8) {
+            Ok(mode) => mode,
+            Err(_) => {
+                println!("\n{} isn't an octal mode, try something like 644", args[0]);
+                return;
+            },
+        };
+        if let Err(err) = VFS.lock().chmod(args[1].to_string(), mode, Some(self.dir_id)) {
+            println!("\nCouldn't change the mode of {}: {}", args[1], err);
+        }
+    }
+
+    pub fn chown(&self, args: &str) {
+        let args: Vec<&str> = args.split_whitespace().collect();
+        if args.len() != 2 {
+            println!("\nUsage: chown <user> <path>");
+            return;
+        }
+        let user = match User::lookup(args[0]) {
+            Some(user) => user,
+            None => {
+                println!("\nNo such user: {}", args[0]);
+                return;
+            },
+        };
+        if let Err(err) = VFS.lock().chown(args[1].to_string(), user.uid, Some(self.dir_id)) {
+            println!("\nCouldn't change the owner of {}: {}", args[1], err);
+        }
+    }
+
+    pub fn chgrp(&self, args: &str) {
+        let args: Vec<&str> = args.split_whitespace().collect();
+        if args.len() != 2 {
+            println!("\nUsage: chgrp <group> <path>");
+            return;
+        }
+        let group = match users::lookup_group(args[0]) {
+            Some(group) => group,
+            None => {
+                println!("\nNo such group: {}", args[0]);
+                return;
+            },
+        };
+        if let Err(err) = VFS.lock().chgrp(args[1].to_string(), group, Some(self.dir_id)) {
+            println!("\nCouldn't change the group of {}: {}", args[1], err);
+        }
+    }
+
+    pub fn whoami(&self) {
+        println!("\n{}", users::current_user().name);
+    }
+
+    // su command
+    // Switches to a different user - there aren't any passwords
+    pub fn su(&self, args: &str) {
+        let name = if args.trim().is_empty() { "root" } else { args.trim() };
+        match User::lookup(name) {
+            Some(user) => users::switch_user(user),
+            None => println!("\nNo such user: {}", name),
+        }
+    }
+
+    // date command
+    // Prints the current date and time from the real time clock
+    pub fn date(&self) {
+        println!("\n{} UTC", rtc::now());
+    }
+
+    // proot command
+    // Prints every directory on every mounted file system, and the files in each one
     pub fn proot(&self) {
-        USTARFS.lock().print_root();
+        println!();
+        self.print_tree("/".to_string());
+    }
+
+    fn print_tree(&self, path: String) {
+        let entries = match VFS.lock().list_directory(path.to_string(), None) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        println!("{}", path);
+        for i in entries.iter() {
+            if i.file_type != FileType::Directory {
+                println!("    {}", i.name);
+            }
+        }
+        for i in entries.iter() {
+            if i.file_type == FileType::Directory {
+                let child = if path == "/" { format!("/{}", i.name) } else { format!("{}/{}", path, i.name) };
+                self.print_tree(child);
+            }
+        }
+    }
+
+    // mount command
+    // With no arguments, lists what's mounted where
+    pub fn mount(&self, args: &str) {
+        let args: Vec<&str> = args.split_whitespace().collect();
+        if args.is_empty() {
+            println!();
+            for (kind, device, path) in VFS.lock().mounts() {
+                println!("{} ({}) on {}", kind, device, path);
+            }
+            return;
+        }
+        if args.len() != 2 && args.len() != 3 {
+            println!("\nUsage: mount <type> <path> [device]");
+            return;
+        }
+        let device = args.get(2).copied();
+        if let Err(err) = VFS.lock().mount(args[0], args[1].to_string(), device, Some(self.dir_id)) {
+            println!("\nCouldn't mount {} on {}: {}", args[0], args[1], err);
+        }
+    }
+
+    pub fn mkfs(&self, args: &str) {
+        let device = args.trim();
+        if device.is_empty() {
+            println!("\nUsage: mkfs <device>");
+            return;
+        }
+        if let Err(err) = VFS.lock().mkfs(device) {
+            println!("\nCouldn't make a file system on {}: {}", device, err);
+        }
+    }
+
+    pub fn cache(&self, args: &str) {
+        let root = match USTARFS.as_ref() {
+            Some(root) => root,
+            None => {
+                println!("\n/ isn't on a disk, so there's no cache");
+                return;
+            },
+        };
+        let args = args.trim();
+        if !args.is_empty() {
+            let blocks = match args.parse::<usize>() {
+                Ok(blocks) if blocks > 0 => blocks,
+                _ => {
+                    println!("\nUsage: cache [blocks]");
+                    return;
+                },
+            };
+            if let Err(err) = root.lock().set_cache_capacity(blocks) {
+                println!("\nCouldn't write out what didn't fit: {}", err);
+            }
+        }
+        let (capacity, dirty) = root.lock().cache_usage();
+        println!("\n{} blocks ({}), {} waiting to be written", capacity, size_string(capacity as u64 * 512), dirty);
+    }
+
+    pub fn umount(&self, args: &str) {
+        if let Err(err) = VFS.lock().umount(args.trim().to_string(), Some(self.dir_id)) {
+            println!("\nCouldn't unmount {}: {}", args, err);
+        }
+    }
+
+    // fsck command
+    // Checks a file system for damage, and fixes it with -r
+    pub fn fsck(&self, args: &str) {
+        let mut args: Vec<&str> = args.split_whitespace().collect();
+        let repair = !args.is_empty() && args[0] == "-r";
+        if repair {
+            args.remove(0);
+        }
+        let path = match args.first() {
+            Some(path) => path.to_string(),
+            None => "/".to_string(),
+        };
+        let problems = match VFS.lock().fsck(path.to_string(), repair, Some(self.dir_id)) {
+            Ok(problems) => problems,
+            Err(err) => {
+                println!("\nCouldn't check {}: {}", path, err);
+                return;
+            },
+        };
+        if problems.is_empty() {
+            println!("\nNo problems found");
+            return;
+        }
+        println!();
+        for i in problems.iter() {
+            println!("{}", i);
+        }
+        println!("{} problems found", problems.len());
+    }
+
+    // trash command
+    // Lists what's been removed, or gets rid of it for good with empty
+    pub fn trash(&self, args: &str) {
+        match args.trim() {
+            "" | "list" => {
+                let entries = match VFS.lock().trash() {
+                    Ok(entries) => entries,
+                    Err(err) => {
+                        println!("\nCouldn't look in the trash: {}", err);
+                        return;
+                    },
+                };
+                if entries.is_empty() {
+                    println!("\nThe trash is empty");
+                    return;
+                }
+                println!();
+                for i in entries {
+                    let kind = match i.file_type {
+                        FileType::Directory => 'd',
+                        FileType::Symlink => 'l',
+                        _ => '-',
+                    };
+                    println!("{} {:>8} {} {}", kind, i.size, DateTime::from_unix(i.removed), i.path);
+                }
+            },
+            "empty" => {
+                if let Err(err) = VFS.lock().empty_trash() {
+                    println!("\nCouldn't empty the trash: {}", err);
+                }
+            },
+            _ => println!("\nUsage: trash [list | empty]"),
+        }
+    }
+
+    pub fn undelete(&self, args: &str) {
+        if let Err(err) = VFS.lock().undelete(args.trim().to_string(), Some(self.dir_id)) {
+            println!("\nCouldn't undelete {}: {}", args, err);
+        }
+    }
+
+    // lsblk command
+    // Every disk and the partitions on it - the partitions are indented under their disk
+    pub fn lsblk(&self) {
+        let mounts = VFS.lock().mounts();
+        println!("\n{:<8} {:<4} {:>7}  {:<12} model", "name", "type", "size", "mounted on");
+        for partition in partition::partitions() {
+            let (name, kind, model) = if partition.is_disk() {
+                (partition.name().to_string(), "disk", partition.model())
+            }
+            else {
+                (format!("  {}", partition.name()), "part", "")
+            };
+            let mounted: Vec<&str> = mounts.iter()
+                .filter(|(fs_kind, device, _)| fs_kind == "ustar" && device == partition.name())
+                .map(|(_, _, path)| path.as_str())
+                .collect();
+            println!("{:<8} {:<4} {:>7}  {:<12} {}", name, kind, size_string(partition.capacity_sectors() * 512), mounted.join(","), model);
+        }
     }
 
     pub fn vim(&self, args: &str) {
@@ -602,6 +1116,11 @@ impl CommandRunner {
     // shuts down the operating system
     // ONLY WORKS ON QEMU NOT ON REAL HARDWARE!
     pub fn shut_down(&self) {
+        // Anything still in the disk caches would be gone
+        if let Err(err) = VFS.lock().sync() {
+            println!("\nCouldn't write everything to the disk, so not shutting down: {}", err);
+            return;
+        }
         unsafe { outw(0x604, 0x2000); }
     }
 
@@ -630,14 +1149,15 @@ impl CommandRunner {
                 "clear" => self.clear(),
                 "logo" => self.logo(),
                 "yes" => self.yes(),
-                "ls" => self.ls(),
-                "dir" => self.ls(),
+                "ls" => self.ls(args),
+                "dir" => self.ls(args),
                 "cd" => self.cd(args),
                 "cat" => self.cat(args),
                 "mkdir" => self.mkdir(args),
                 "rmdir" => self.rmdir(args),
                 "defrag" => self.defrag(),
-                "write" => self.write(),
+                "write" => self.sync(),
+                "sync" => self.sync(),
                 "touch" => self.touch(args),
                 "vim" => self.vim(args),
                 "rm" => self.rm(args),
@@ -647,6 +1167,23 @@ impl CommandRunner {
                 "exit" => self.shut_down(),
                 "proot" => self.proot(),
                 "brainf" => self.brainf(args),
+                "mv" => self.mv(args),
+                "cp" => self.cp(args),
+                "ln" => self.ln(args),
+                "date" => self.date(),
+                "chmod" => self.chmod(args),
+                "chown" => self.chown(args),
+                "chgrp" => self.chgrp(args),
+                "whoami" => self.whoami(),
+                "su" => self.su(args),
+                "mount" => self.mount(args),
+                "umount" => self.umount(args),
+                "fsck" => self.fsck(args),
+                "trash" => self.trash(args),
+                "undelete" => self.undelete(args),
+                "lsblk" => self.lsblk(),
+                "mkfs" => self.mkfs(args),
+                "cache" => self.cache(args),
                 _ => println!("Invalid Command: {}", command),
             }
 
@@ -697,6 +1234,29 @@ impl CommandRunner {
     }
 }
 
+// Turns permission bits into rwxr-xr-x
+fn mode_string(mode: u64) -> String {
+    let mut result = String::with_capacity(9);
+    for shift in [6, 3, 0].iter() {
+        let bits = (mode >> shift) & 7;
+        result.push(if bits & users::READ != 0 { 'r' } else { '-' });
+        result.push(if bits & users::WRITE != 0 { 'w' } else { '-' });
+        result.push(if bits & users::EXECUTE != 0 { 'x' } else { '-' });
+    }
+    result
+}
+
+// Bytes as K, M or G, with one decimal place once it's past K
+fn size_string(bytes: u64) -> String {
+    let units = [("G", 1 << 30), ("M", 1 << 20)];
+    for (unit, size) in units.iter() {
+        if bytes >= *size {
+            return format!("{}.{}{}", bytes / size, bytes % size * 10 / size, unit);
+        }
+    }
+    format!("{}K", bytes / 1024)
+}
+
 // Calls the CommandRunner class to add a char to the buffer
 pub fn add_command_buffer_fn(c: char) {
         COMMANDRUNNER.lock().add_to_buffer(c);
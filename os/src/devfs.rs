@@ -0,0 +1,342 @@
+// Device nodes - a file system where every file is a piece of hardware
+// It's mounted at /dev, so things that only know about files can still get at the disks, the serial port, the speaker
+// and the screen. Reads and writes go straight to the device instead of being held onto like they are for files
+
+use alloc::vec::Vec;
+use alloc::vec;
+use alloc::string::String;
+use vga::colors::Color16;
+use x86::io::inb;
+use x86_64::instructions::interrupts;
+use crate::alloc::string::ToString;
+use crate::ata_block_driver::MAX_SECTORS;
+use crate::block_device::BlockDevice;
+use crate::partition::{self, Partition};
+use crate::rtc;
+use crate::serial::SERIAL1;
+use crate::speaker::PCSPEAKER;
+use crate::vga_buffer::{ADVANCED_WRITER, MODE};
+use crate::vfs::{FileSystem, FileType, FsError, Permissions, Stat};
+
+const SECTOR_SIZE: u64 = 512;
+// COM1 - the data port, and the line status port that says if anything came in
+const SERIAL_DATA: u16 = 0x3F8;
+const SERIAL_LINE_STATUS: u16 = 0x3FD;
+// Graphics mode is 640x480, one byte per pixel with the color in the bottom 4 bits
+const FB_WIDTH: u64 = 640;
+const FB_HEIGHT: u64 = 480;
+
+enum Device {
+    // A whole disk or a partition on it, as raw sectors
+    Disk(Partition),
+    Serial,
+    // Write a frequency in Hz as text to play it, 0 to stop
+    Speaker,
+    Framebuffer,
+    Null,
+}
+
+struct Node {
+    name: String,
+    device: Device,
+    mode: u64,
+}
+
+impl Node {
+    fn file_type(&self) -> FileType {
+        match self.device {
+            Device::Disk(_) => FileType::BlockDevice,
+            _ => FileType::CharDevice,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match &self.device {
+            Device::Disk(disk) => disk.capacity_sectors() * SECTOR_SIZE,
+            Device::Framebuffer => FB_WIDTH * FB_HEIGHT,
+            _ => 0,
+        }
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            name: self.name.to_string(),
+            file_type: self.file_type(),
+            size: self.size(),
+            mtime: rtc::unix_time(),
+            linked_name: None,
+            permissions: permissions(self.mode),
+        }
+    }
+}
+
+// Everything belongs to root
+fn permissions(mode: u64) -> Permissions {
+    Permissions {
+        mode,
+        owner_id: 0,
+        group_id: 0,
+        owner_name: "root".to_string(),
+        group_name: "root".to_string(),
+    }
+}
+
+// The 16 colors in the order the VGA numbers them
+fn color(value: u8) -> Color16 {
+    match value & 0x0F {
+        0 => Color16::Black,
+        1 => Color16::Blue,
+        2 => Color16::Green,
+        3 => Color16::Cyan,
+        4 => Color16::Red,
+        5 => Color16::Magenta,
+        6 => Color16::Brown,
+        7 => Color16::LightGrey,
+        8 => Color16::DarkGrey,
+        9 => Color16::LightBlue,
+        10 => Color16::LightGreen,
+        11 => Color16::LightCyan,
+        12 => Color16::LightRed,
+        13 => Color16::Pink,
+        14 => Color16::Yellow,
+        _ => Color16::White,
+    }
+}
+
+// Whole sectors go straight into buf, up to a command's worth at a time - only a sector that's cut off at either end
+// goes through a copy
+fn disk_read(disk: &mut Partition, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+    let size = disk.capacity_sectors() * SECTOR_SIZE;
+    if offset >= size {
+        return Ok(0);
+    }
+    let count = buf.len().min((size - offset) as usize);
+    let mut done = 0;
+    while done < count {
+        let position = offset + done as u64;
+        let start = (position % SECTOR_SIZE) as usize;
+        let whole = (count - done) / SECTOR_SIZE as usize;
+        if start == 0 && whole > 0 {
+            let length = whole.min(MAX_SECTORS) * SECTOR_SIZE as usize;
+            disk.read_blocks(position / SECTOR_SIZE, &mut buf[done..done + length])?;
+            done += length;
+            continue;
+        }
+        let sector = disk.read_block(position / SECTOR_SIZE)?;
+        let length = (SECTOR_SIZE as usize - start).min(count - done);
+        buf[done..done + length].copy_from_slice(&sector[start..start + length]);
+        done += length;
+    }
+    Ok(count)
+}
+
+// Same as reading - sectors that are only partly written get read first, so the rest of them stays the same
+fn disk_write(disk: &mut Partition, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+    let size = disk.capacity_sectors() * SECTOR_SIZE;
+    if offset >= size && !data.is_empty() {
+        return Err(FsError::NoSpace);
+    }
+    let count = data.len().min((size - offset.min(size)) as usize);
+    let mut done = 0;
+    while done < count {
+        let position = offset + done as u64;
+        let start = (position % SECTOR_SIZE) as usize;
+        let whole = (count - done) / SECTOR_SIZE as usize;
+        if start == 0 && whole > 0 {
+            let length = whole.min(MAX_SECTORS) * SECTOR_SIZE as usize;
+            disk.write_blocks(position / SECTOR_SIZE, &data[done..done + length])?;
+            done += length;
+            continue;
+        }
+        let mut sector = disk.read_block(position / SECTOR_SIZE)?;
+        let length = (SECTOR_SIZE as usize - start).min(count - done);
+        sector[start..start + length].copy_from_slice(&data[done..done + length]);
+        disk.write_blocks(position / SECTOR_SIZE, &sector)?;
+        done += length;
+    }
+    Ok(count)
+}
+
+// Whatever has come in on the serial port so far - it doesn't wait for more
+fn serial_read(buf: &mut [u8]) -> usize {
+    let mut count = 0;
+    while count < buf.len() && unsafe { inb(SERIAL_LINE_STATUS) } & 1 != 0 {
+        buf[count] = unsafe { inb(SERIAL_DATA) };
+        count += 1;
+    }
+    count
+}
+
+fn serial_write(data: &[u8]) -> usize {
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL1.lock();
+        for byte in data {
+            serial.send(*byte);
+        }
+    });
+    data.len()
+}
+
+fn speaker_write(data: &[u8]) -> Result<usize, FsError> {
+    let frequency = match core::str::from_utf8(data).ok().and_then(|text| text.trim().parse::<i32>().ok()) {
+        Some(frequency) if frequency >= 0 => frequency,
+        _ => return Err(FsError::InvalidArgument),
+    };
+    interrupts::without_interrupts(|| {
+        let mut speaker = PCSPEAKER.lock();
+        if frequency == 0 {
+            speaker.no_sound();
+        }
+        else {
+            speaker.play_sound(frequency);
+        }
+    });
+    Ok(data.len())
+}
+
+// Every byte is a pixel, going across and then down
+fn framebuffer_write(offset: u64, data: &[u8]) -> Result<usize, FsError> {
+    // Text mode doesn't have pixels - try gterm
+    if MODE.lock().text {
+        return Err(FsError::Busy);
+    }
+    let size = FB_WIDTH * FB_HEIGHT;
+    if offset >= size && !data.is_empty() {
+        return Err(FsError::NoSpace);
+    }
+    let count = data.len().min((size - offset.min(size)) as usize);
+    interrupts::without_interrupts(|| {
+        let writer = ADVANCED_WRITER.lock();
+        for (i, value) in data[..count].iter().enumerate() {
+            let position = offset + i as u64;
+            writer.set_pixel((position % FB_WIDTH) as usize, (position / FB_WIDTH) as usize, color(*value));
+        }
+    });
+    Ok(count)
+}
+
+pub struct DevFileSystem {
+    nodes: Vec<Node>,
+}
+
+impl Default for DevFileSystem {
+    fn default() -> DevFileSystem {
+        DevFileSystem::new()
+    }
+}
+
+impl DevFileSystem {
+    // Disks get looked for once, here - every disk and every partition on them gets a node
+    pub fn new() -> DevFileSystem {
+        let mut nodes = Vec::new();
+        for disk in partition::partitions() {
+            nodes.push(Node { name: disk.name().to_string(), device: Device::Disk(disk), mode: 0o660 });
+        }
+        nodes.push(Node { name: "ttyS0".to_string(), device: Device::Serial, mode: 0o666 });
+        nodes.push(Node { name: "speaker".to_string(), device: Device::Speaker, mode: 0o666 });
+        nodes.push(Node { name: "fb0".to_string(), device: Device::Framebuffer, mode: 0o660 });
+        nodes.push(Node { name: "null".to_string(), device: Device::Null, mode: 0o666 });
+        DevFileSystem { nodes }
+    }
+
+    // Finds the node at path - None is the root
+    fn find(&mut self, path: &str) -> Result<Option<&mut Node>, FsError> {
+        let name = path.trim_matches('/');
+        if name.is_empty() {
+            return Ok(None);
+        }
+        match self.nodes.iter_mut().find(|node| node.name == name) {
+            Some(node) => Ok(Some(node)),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn node(&mut self, path: &str) -> Result<&mut Node, FsError> {
+        match self.find(path)? {
+            Some(node) => Ok(node),
+            None => Err(FsError::IsADirectory),
+        }
+    }
+}
+
+impl FileSystem for DevFileSystem {
+    fn lookup(&mut self, path: &str) -> Result<FileType, FsError> {
+        match self.find(path)? {
+            Some(node) => Ok(node.file_type()),
+            None => Ok(FileType::Directory),
+        }
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Stat, FsError> {
+        match self.find(path)? {
+            Some(node) => Ok(node.stat()),
+            None => Ok(Stat {
+                name: String::new(),
+                file_type: FileType::Directory,
+                size: 0,
+                mtime: rtc::unix_time(),
+                linked_name: None,
+                permissions: permissions(0o755),
+            }),
+        }
+    }
+
+    // Whatever a character device has right now
+    // A whole disk won't fit in memory, so those only get read a piece at a time through a descriptor
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        let size = match self.node(path)?.device {
+            Device::Disk(_) => return Err(FsError::Unsupported),
+            Device::Serial => 4096,
+            _ => 0,
+        };
+        let mut data = vec![0; size];
+        let count = self.read_at(path, 0, &mut data)?;
+        data.truncate(count);
+        Ok(data)
+    }
+
+    fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        match &mut self.node(path)?.device {
+            Device::Disk(disk) => disk_read(disk, offset, buf),
+            Device::Serial => Ok(serial_read(buf)),
+            // Pixels only go one way
+            Device::Framebuffer => Err(FsError::Unsupported),
+            Device::Speaker | Device::Null => Ok(0),
+        }
+    }
+
+    // Same as writing at the start - nothing past what's written changes
+    fn write(&mut self, path: &str, data: Vec<u8>) -> Result<(), FsError> {
+        self.write_at(path, 0, &data)?;
+        Ok(())
+    }
+
+    fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        match &mut self.node(path)?.device {
+            Device::Disk(disk) => disk_write(disk, offset, data),
+            Device::Serial => Ok(serial_write(data)),
+            Device::Speaker => speaker_write(data),
+            Device::Framebuffer => framebuffer_write(offset, data),
+            Device::Null => Ok(data.len()),
+        }
+    }
+
+    fn readdir(&mut self, path: &str) -> Result<Vec<Stat>, FsError> {
+        if self.find(path)?.is_some() {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(self.nodes.iter().map(|node| node.stat()).collect())
+    }
+
+    fn mkdir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn rmdir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+}
@@ -0,0 +1,183 @@
+// Open files - everyone with an id gets their own table of them, and a file descriptor is a spot in that table
+
+use alloc::vec::Vec;
+use alloc::string::String;
+
+// How a file gets opened - these get or'd together
+pub const OPEN_READ: u64 = 1;
+pub const OPEN_WRITE: u64 = 2;
+// Every write goes on the end of the file, wherever the offset is
+pub const OPEN_APPEND: u64 = 4;
+// Makes the file if it isn't there
+pub const OPEN_CREATE: u64 = 8;
+// Throws away everything that's in the file
+pub const OPEN_TRUNCATE: u64 = 16;
+
+// Where a seek is from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+pub struct OpenFile {
+    // Absolute path in the vfs
+    pub path: String,
+    pub flags: u64,
+    pub offset: u64,
+    // Writes normally go straight to the file system, but a truncated file is built up in here and replaces the old one
+    // in one go when it gets closed or synced - so is any file on a file system that can't write part of a file
+    pub data: Option<Vec<u8>>,
+    // Does data have changes that aren't on the file system yet
+    pub dirty: bool,
+    // Devices are never held onto, even when they're opened to be truncated - everything goes straight through
+    pub device: bool,
+}
+
+impl OpenFile {
+    pub fn new(path: String, flags: u64) -> OpenFile {
+        // Truncating is just starting with nothing to write back
+        let truncate = flags & OPEN_WRITE != 0 && flags & OPEN_TRUNCATE != 0;
+        OpenFile {
+            path,
+            flags,
+            offset: 0,
+            data: if truncate { Some(Vec::new()) } else { None },
+            dirty: truncate,
+            device: false,
+        }
+    }
+
+    // There's nothing to truncate on a device
+    pub fn for_device(path: String, flags: u64) -> OpenFile {
+        OpenFile {
+            path,
+            flags,
+            offset: 0,
+            data: None,
+            dirty: false,
+            device: true,
+        }
+    }
+
+    pub fn can_read(&self) -> bool {
+        self.flags & OPEN_READ != 0
+    }
+
+    pub fn can_write(&self) -> bool {
+        self.flags & OPEN_WRITE != 0
+    }
+
+    // Reads out of the data that's been written, None if nothing has been written yet
+    pub fn read_buffered(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let data = match &self.data {
+            Some(data) => data,
+            None => return None,
+        };
+        let start = (self.offset as usize).min(data.len());
+        let count = buf.len().min(data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        self.offset += count as u64;
+        Some(count)
+    }
+
+    // Writes into data at the offset, filling any gap past the end with zeros - data has to already be there
+    pub fn write_buffered(&mut self, bytes: &[u8]) -> usize {
+        let data = match &mut self.data {
+            Some(data) => data,
+            None => return 0,
+        };
+        if self.flags & OPEN_APPEND != 0 {
+            self.offset = data.len() as u64;
+        }
+        let start = self.offset as usize;
+        if data.len() < start + bytes.len() {
+            data.resize(start + bytes.len(), 0);
+        }
+        data[start..start + bytes.len()].copy_from_slice(bytes);
+        self.offset += bytes.len() as u64;
+        self.dirty = true;
+        bytes.len()
+    }
+
+    // Moves the offset, size is how big the file is right now
+    // Going past the end is fine, but going before the start isn't
+    pub fn seek(&mut self, position: SeekFrom, size: u64) -> Option<u64> {
+        let offset = match position {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.offset as i64 + offset,
+            SeekFrom::End(offset) => size as i64 + offset,
+        };
+        if offset < 0 {
+            return None;
+        }
+        self.offset = offset as u64;
+        Some(self.offset)
+    }
+}
+
+pub struct DescriptorTable {
+    files: Vec<Option<OpenFile>>,
+}
+
+impl DescriptorTable {
+    pub fn new() -> DescriptorTable {
+        DescriptorTable { files: Vec::new() }
+    }
+
+    // Puts file in the lowest spot that's free, and gives back that spot
+    pub fn insert(&mut self, file: OpenFile) -> usize {
+        for (i, spot) in self.files.iter_mut().enumerate() {
+            if spot.is_none() {
+                *spot = Some(file);
+                return i;
+            }
+        }
+        self.files.push(Some(file));
+        self.files.len() - 1
+    }
+
+    pub fn get(&mut self, fd: usize) -> Option<&mut OpenFile> {
+        match self.files.get_mut(fd) {
+            Some(Some(file)) => Some(file),
+            _ => None,
+        }
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Option<OpenFile> {
+        match self.files.get_mut(fd) {
+            Some(spot) => spot.take(),
+            None => None,
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut OpenFile> {
+        self.files.iter_mut().filter_map(|spot| spot.as_mut())
+    }
+}
+
+impl Default for DescriptorTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// checks that closed spots get reused, and that writes and seeks past the end work
+#[test_case]
+fn test_descriptor_table() {
+    let mut table = DescriptorTable::new();
+    let first = table.insert(OpenFile::new(String::from("/a"), OPEN_READ));
+    let second = table.insert(OpenFile::new(String::from("/b"), OPEN_WRITE | OPEN_TRUNCATE));
+    assert_eq!((first, second), (0, 1));
+    assert!(table.remove(first).is_some());
+    assert!(table.get(first).is_none());
+    assert_eq!(table.insert(OpenFile::new(String::from("/c"), OPEN_READ)), 0);
+
+    let file = table.get(second).unwrap();
+    assert_eq!(file.write_buffered(b"hello"), 5);
+    assert_eq!(file.seek(SeekFrom::End(2), 5), Some(7));
+    file.write_buffered(b"!");
+    assert_eq!(file.data.as_ref().unwrap().as_slice(), b"hello\0\0!");
+    assert_eq!(file.seek(SeekFrom::Current(-10), 8), None);
+}
@@ -28,7 +28,10 @@ lazy_static! {
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[46].set_handler_fn(ata_interrupt_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()]
+            .set_handler_fn(primary_ata_interrupt_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()]
+            .set_handler_fn(secondary_ata_interrupt_handler);
         idt
     };
 }
@@ -47,13 +50,26 @@ extern "x86-interrupt" fn page_fault_handler(
     hlt_loop();
 }
 
-extern "x86-interrupt" fn ata_interrupt_handler(
+// a drive on one of the ATA buses finished something, which wakes up whatever was waiting for it
+extern "x86-interrupt" fn primary_ata_interrupt_handler(
     _stack_frame: &mut InterruptStackFrame
 ) {
+    crate::ata_block_driver::handle_interrupt(0);
     unsafe {
         PICS.lock()
-            .notify_end_of_interrupt(46);
-    }}
+            .notify_end_of_interrupt(InterruptIndex::PrimaryAta.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame
+) {
+    crate::ata_block_driver::handle_interrupt(1);
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SecondaryAta.as_u8());
+    }
+}
 
 
 
@@ -86,6 +102,8 @@ extern "x86-interrupt" fn timer_interrupt_handler(
     interrupts::without_interrupts(|| {
         TIME_ROUTER.lock().handle();
     });
+    // lets disk transfers that are waiting on an interrupt check if they've timed out
+    crate::ata_block_driver::wake_waiting();
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
@@ -97,6 +115,21 @@ pub fn init_idt() {
     IDT.load();
 }
 
+// makes sure the ATA interrupts get through the PICs - the BIOS doesn't always let them
+// IRQ2 is where the second PIC hangs off the first one
+pub fn unmask_ata() {
+    use x86_64::instructions::port::Port;
+
+    let mut master: Port<u8> = Port::new(0x21);
+    let mut slave: Port<u8> = Port::new(0xA1);
+    unsafe {
+        let mask = master.read();
+        master.write(mask & !0b0000_0100);
+        let mask = slave.read();
+        slave.write(mask & !0b1100_0000);
+    }
+}
+
 // defines a response to a double fault interrupt
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame, _error_code: u64) -> !
@@ -117,6 +150,9 @@ extern "x86-interrupt" fn breakpoint_handler(
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    // IRQ14 and IRQ15, on the second PIC
+    PrimaryAta = PIC_1_OFFSET + 14,
+    SecondaryAta = PIC_1_OFFSET + 15,
 }
 
 // allows access of InterruptIndex as an u8 or usize
@@ -0,0 +1,183 @@
+// Write-ahead journal for the file system
+// Sectors that have to change together get written to a reserved area at the end of the disk first, followed by a header
+// that says where they go. Only after the header is on disk do they get written to where they belong, so if the machine
+// dies partway through, the next boot can finish the job (or throw the journal away if the header never made it)
+
+use alloc::vec::Vec;
+use crate::block_device::BlockDevice;
+use crate::vfs::FsError;
+use ustar_format::{JOURNAL_ID_SIZE, JOURNAL_IDLE, JOURNAL_MAGIC, JOURNAL_TABLE_BLOCKS};
+
+// The layout is part of the disk format, so the host tools know where it is too
+pub use ustar_format::{JOURNAL_BLOCKS, JOURNAL_ENTRIES};
+
+const SECTOR_SIZE: usize = 512;
+
+pub struct Journal {
+    // First block of the journal area - the header
+    start: u64,
+    // Sectors waiting for the next commit - (where they go, what goes there)
+    pending: Vec<(u64, Vec<u8>)>,
+}
+
+impl Journal {
+    pub fn new(start: u64) -> Journal {
+        Journal {
+            start,
+            pending: Vec::new(),
+        }
+    }
+
+    // Puts the journal in the last JOURNAL_BLOCKS blocks of a disk that is sectors long
+    pub fn at_end_of(sectors: u64) -> Journal {
+        Journal::new(sectors.saturating_sub(JOURNAL_BLOCKS))
+    }
+
+    // Nothing past this should be used for anything else
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    // Marks the journal as belonging to a file system with nothing waiting in it
+    pub fn format<D: BlockDevice>(&self, device: &mut D) -> Result<(), FsError> {
+        device.write_blocks(self.start, &idle_header())
+    }
+
+    // Was the journal ever set up by format (or by a commit)?
+    pub fn is_formatted<D: BlockDevice>(&self, device: &mut D) -> Result<bool, FsError> {
+        let header = device.read_block(self.start)?;
+        Ok(header[0..8] == JOURNAL_MAGIC || header[0..8] == JOURNAL_IDLE)
+    }
+
+    // Has a sector for block_id been recorded since the last commit?
+    pub fn contains(&self, block_id: u64) -> bool {
+        self.pending.iter().any(|(id, _)| *id == block_id)
+    }
+
+    // Queues up a sector to be written on the next commit - a later write to the same block replaces an earlier one
+    pub fn record(&mut self, block_id: u64, data: &[u8]) {
+        let mut sector = data.to_vec();
+        sector.resize(SECTOR_SIZE, 0);
+        match self.pending.iter_mut().find(|(id, _)| *id == block_id) {
+            Some(entry) => entry.1 = sector,
+            None => self.pending.push((block_id, sector)),
+        }
+    }
+
+    // Writes everything that was recorded, first to the journal then to where it belongs
+    // It all has to fit in the journal at once to be safe, so more than JOURNAL_ENTRIES sectors is NoSpace, and none of
+    // it gets written - the disk stays how the last commit left it
+    // If the disk fails partway through, whatever didn't make it is thrown away - the next boot replays or drops the
+    // transaction that was going, so the disk is never left half changed
+    pub fn commit<D: BlockDevice>(&mut self, device: &mut D) -> Result<(), FsError> {
+        let transaction = core::mem::replace(&mut self.pending, Vec::new());
+        if transaction.len() > JOURNAL_ENTRIES {
+            return Err(FsError::NoSpace);
+        }
+        if transaction.is_empty() {
+            return Ok(());
+        }
+        // The sectors and where they go
+        let data_start = self.start + 1 + JOURNAL_TABLE_BLOCKS;
+        for (i, (_, data)) in transaction.iter().enumerate() {
+            device.write_blocks(data_start + i as u64, data)?;
+        }
+        let mut table = Vec::with_capacity(transaction.len() * JOURNAL_ID_SIZE);
+        for (block_id, _) in transaction.iter() {
+            table.extend_from_slice(&block_id.to_le_bytes());
+        }
+        let table_blocks = (table.len() + SECTOR_SIZE - 1) / SECTOR_SIZE;
+        table.resize(table_blocks * SECTOR_SIZE, 0);
+        for i in 0..table_blocks {
+            device.write_blocks(self.start + 1 + i as u64, &table[i*SECTOR_SIZE..(i + 1)*SECTOR_SIZE])?;
+        }
+        // Once the header is written the transaction counts, so everything before it has to really be on the disk
+        device.flush()?;
+        let checksum = journal_checksum(transaction.len() as u32, &table, transaction.iter().map(|(_, data)| &data[..]));
+        device.write_blocks(self.start, &journal_header(transaction.len() as u32, checksum))?;
+        device.flush()?;
+        for (block_id, data) in transaction.iter() {
+            device.write_blocks(*block_id, data)?;
+        }
+        // Everything made it, so there's nothing to replay
+        device.flush()?;
+        device.write_blocks(self.start, &idle_header())?;
+        device.flush()
+    }
+
+    // Finishes a transaction that was committed but not finished before a crash
+    // Returns true if there was one. A journal that doesn't check out never got committed, so it gets thrown away
+    pub fn replay<D: BlockDevice>(&self, device: &mut D) -> Result<bool, FsError> {
+        let header = device.read_block(self.start)?;
+        if header[0..8] != JOURNAL_MAGIC {
+            return Ok(false);
+        }
+        let count = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        let checksum = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+        if count as usize > JOURNAL_ENTRIES {
+            device.write_blocks(self.start, &idle_header())?;
+            return Ok(false);
+        }
+        let table_blocks = (count as usize * JOURNAL_ID_SIZE + SECTOR_SIZE - 1) / SECTOR_SIZE;
+        let mut table = Vec::with_capacity(table_blocks * SECTOR_SIZE);
+        for i in 0..table_blocks {
+            table.append(&mut device.read_block(self.start + 1 + i as u64)?);
+        }
+        let data_start = self.start + 1 + JOURNAL_TABLE_BLOCKS;
+        let mut sectors = Vec::with_capacity(count as usize);
+        for i in 0..count as u64 {
+            sectors.push(device.read_block(data_start + i)?);
+        }
+        if journal_checksum(count, &table, sectors.iter().map(|data| &data[..])) != checksum {
+            device.write_blocks(self.start, &idle_header())?;
+            return Ok(false);
+        }
+        for (i, data) in sectors.iter().enumerate() {
+            let mut block_id = [0; JOURNAL_ID_SIZE];
+            block_id.copy_from_slice(&table[i*JOURNAL_ID_SIZE..(i + 1)*JOURNAL_ID_SIZE]);
+            device.write_blocks(u64::from_le_bytes(block_id), data)?;
+        }
+        device.flush()?;
+        device.write_blocks(self.start, &idle_header())?;
+        device.flush()?;
+        Ok(true)
+    }
+}
+
+// What the header is when there's nothing to replay
+fn idle_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(SECTOR_SIZE);
+    header.extend_from_slice(&JOURNAL_IDLE);
+    header.resize(SECTOR_SIZE, 0);
+    header
+}
+
+fn journal_header(count: u32, checksum: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(SECTOR_SIZE);
+    header.extend_from_slice(&JOURNAL_MAGIC);
+    header.extend_from_slice(&count.to_le_bytes());
+    header.extend_from_slice(&checksum.to_le_bytes());
+    header.resize(SECTOR_SIZE, 0);
+    header
+}
+
+// FNV-1a over the count, the target table and all of the sectors
+fn journal_checksum<'a>(count: u32, table: &[u8], sectors: impl Iterator<Item = &'a [u8]>) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    let mut add = |byte: u8| {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    };
+    for byte in count.to_le_bytes().iter() {
+        add(*byte);
+    }
+    for byte in table.iter() {
+        add(*byte);
+    }
+    for sector in sectors {
+        for byte in sector.iter() {
+            add(*byte);
+        }
+    }
+    hash
+}
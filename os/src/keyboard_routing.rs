@@ -15,6 +15,7 @@ use crate::commands::COMMANDRUNNER;
 3 - Song - for quiting early
 */
 
+#[derive(Clone, Copy)]
 pub struct Modes {
     pub terminal: bool,
     pub screenbuffer: bool,
@@ -51,6 +52,7 @@ impl KeyboardRouter {
     }
 
     pub fn handle_scancode(&mut self, scancode: u8, keyboard: &mut Keyboard<layouts::Us104Key,ScancodeSet1>) {
+        *LAST_MODE.lock() = self.mode;
         match scancode{
             // We need to find the right scancode for this (escape)
             129=>self.esc(),
@@ -186,6 +188,11 @@ lazy_static! {
     pub static ref KEYBOARD_ROUTER: Mutex<KeyboardRouter> = {
         Mutex::new(KeyboardRouter::new())
     };
+    // What the mode was when the router got the last key - commands get run by the router while it's locked, so this
+    // is what they can look at instead
+    pub static ref LAST_MODE: Mutex<Modes> = {
+        Mutex::new(Modes::new())
+    };
 }
 
 
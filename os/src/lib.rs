@@ -26,7 +26,18 @@ pub mod keyboard_routing;
 pub mod timer_routing;
 pub mod tetris;
 pub mod ata_block_driver;
+pub mod block_device;
+pub mod block_cache;
 pub mod ustar;
+pub mod journal;
+pub mod partition;
+pub mod rtc;
+pub mod users;
+pub mod vfs;
+pub mod tmpfs;
+pub mod procfs;
+pub mod devfs;
+pub mod fd;
 pub mod speaker;
 pub mod vi;
 pub mod brainf;
@@ -110,6 +121,7 @@ pub fn init() {
     interrupts::init_idt();
     gdt::init();
     unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::unmask_ata();
     x86_64::instructions::interrupts::enable();
 }
 
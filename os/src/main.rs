@@ -17,7 +17,7 @@ use os::memory::{self, BootInfoFrameAllocator};
 use bootloader::{BootInfo, entry_point};
 use x86_64::{VirtAddr};
 use core::panic::PanicInfo;
-use os::task::{Task,keyboard,executor::Executor};
+use os::task::{Task,keyboard,writeback,executor::Executor};
 use x86_64::instructions::interrupts;
 use os::ustar::USTARFS;
 use os::commands::COMMANDRUNNER;
@@ -78,7 +78,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         MODE.lock().text_init();
         println!();
     });
-    USTARFS.lock().init();
+    // Finds the disk and reads the file system in, so / is ready before the shell is
+    lazy_static::initialize(&USTARFS);
     //USTARFS.lock().set_all_files_to_write();
     //USTARFS.lock().write();
     //USTARFS.lock().print_root();
@@ -86,7 +87,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     print!("[user@rust /]# ");
     COMMANDRUNNER.lock().prompt_length = 15;
-    EXECUTOR.lock().spawn(Task::new(keyboard::print_keypresses()));
+    EXECUTOR.lock().spawn(Task::named("keyboard", keyboard::print_keypresses()));
+    EXECUTOR.lock().spawn(Task::named("writeback", writeback::write_back()));
     EXECUTOR.lock().run();
 }
 
@@ -1,5 +1,26 @@
 use x86_64::{PhysAddr, VirtAddr, structures::paging::{ PhysFrame, Size4KiB, FrameAllocator, PageTable, OffsetPageTable}};
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::format;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+lazy_static! {
+    // the memory map from the bootloader, kept around for /proc/memmap
+    static ref MEMORY_MAP: Mutex<Option<&'static MemoryMap>> = Mutex::new(None);
+}
+
+// every region in the memory map as (start, end, what it's used for)
+// empty until the frame allocator has been set up
+pub fn memory_regions() -> Vec<(u64, u64, String)> {
+    match *MEMORY_MAP.lock() {
+        Some(memory_map) => memory_map.iter()
+            .map(|r| (r.range.start_addr(), r.range.end_addr(), format!("{:?}", r.region_type)))
+            .collect(),
+        None => Vec::new(),
+    }
+}
 
 // defines a struct for allocating frames on boot
 pub struct BootInfoFrameAllocator {
@@ -13,6 +34,7 @@ impl BootInfoFrameAllocator {
     /// 
     /// Doesn't matter
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        *MEMORY_MAP.lock() = Some(memory_map);
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
@@ -0,0 +1,319 @@
+// Partition tables
+// Reads the MBR off each disk (and the GPT behind it, if the MBR says there is one) and hands out every partition as
+// its own device, where block 0 is the first block of the partition. A disk with no partition table is used whole, so
+// an image that's just a tar archive still works
+
+use lazy_static::lazy_static;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::format;
+use crate::ata_block_driver::{self, AtaPio};
+use crate::block_device::{check_range, BlockDevice};
+use crate::ustar;
+use crate::vfs::FsError;
+
+// MBR partition types that just point at more partitions - only primary partitions are supported
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+// The MBR type that means the real table is a GPT
+const MBR_PROTECTIVE: u8 = 0xEE;
+// Nobody sane has more than this, and it stops a bad header from making us read the whole disk
+const GPT_MAX_ENTRIES: u64 = 128;
+
+#[derive(Clone)]
+pub struct Partition {
+    driver: AtaPio,
+    // hdb for the whole disk, hdb1 for its first partition and so on
+    name: String,
+    // The slot in the partition table, or None for the whole disk
+    number: Option<u64>,
+    // Where the partition is on the disk, in blocks
+    start: u64,
+    sectors: u64,
+}
+
+impl Partition {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_disk(&self) -> bool {
+        self.number.is_none()
+    }
+
+    // What the drive it's on says it is
+    pub fn model(&self) -> &str {
+        self.driver.properties().model()
+    }
+
+    // The first block of the partition on the disk
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Capacity in sectors
+    pub fn capacity_sectors(&self) -> u64 {
+        self.sectors
+    }
+}
+
+// Blocks are counted from the start of the partition, and it can't reach past its end
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.driver.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        check_range(self, block_id, buf.len())?;
+        self.driver.read_blocks(self.start + block_id, buf)
+    }
+
+    fn write_blocks(&mut self, block_id: u64, data: &[u8]) -> Result<(), FsError> {
+        check_range(self, block_id, data.len())?;
+        self.driver.write_blocks(self.start + block_id, data)
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.driver.flush()
+    }
+}
+
+// The interrupt driven versions, for tasks on the executor
+impl Partition {
+    pub async fn read_blocks_async(&self, block_id: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        check_range(self, block_id, buf.len())?;
+        self.driver.read_blocks_async(self.start + block_id, buf).await
+    }
+
+    pub async fn write_blocks_async(&self, block_id: u64, data: &[u8]) -> Result<(), FsError> {
+        check_range(self, block_id, data.len())?;
+        self.driver.write_blocks_async(self.start + block_id, data).await
+    }
+
+    pub async fn flush_async(&self) -> Result<(), FsError> {
+        self.driver.flush_async().await
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u64 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) as u64
+}
+
+fn read_u64(data: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// A tar header has ustar where an MBR would have its boot code
+fn is_archive(block: &[u8]) -> bool {
+    block.len() >= 262 && &block[257..262] == b"ustar"
+}
+
+lazy_static! {
+    // Drives can't come and go while the machine is on, so they get probed once - the file system at / asks for
+    // them while booting, so that's when it happens
+    static ref DISKS: Vec<(String, AtaPio)> = ata_block_driver::probe_all();
+}
+
+// Every disk that was found, with its name
+pub fn disks() -> Vec<(String, AtaPio)> {
+    DISKS.clone()
+}
+
+// The partitions on a GPT disk as (number, first block, number of blocks)
+fn read_gpt<D: BlockDevice>(driver: &mut D) -> Result<Vec<(u64, u64, u64)>, FsError> {
+    let header = driver.read_block(1)?;
+    if &header[0..8] != b"EFI PART" {
+        return Err(FsError::Corrupted);
+    }
+    let entries_start = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80).min(GPT_MAX_ENTRIES);
+    let entry_size = read_u32(&header, 84);
+    // Entries are at least 128 bytes and always fit evenly into a block
+    if entry_size < 128 || entry_size > 512 || 512 % entry_size != 0 {
+        return Err(FsError::Corrupted);
+    }
+
+    let mut partitions = Vec::new();
+    let mut block: Vec<u8> = Vec::new();
+    for i in 0..entry_count {
+        let offset = i * entry_size;
+        if offset % 512 == 0 {
+            block = driver.read_block(entries_start + offset / 512)?;
+        }
+        let entry = &block[(offset % 512) as usize..(offset % 512 + entry_size) as usize];
+        // No type means the entry isn't used
+        if entry[0..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+        let first = read_u64(entry, 32);
+        let last = read_u64(entry, 40);
+        if last >= first {
+            partitions.push((i + 1, first, last - first + 1));
+        }
+    }
+    Ok(partitions)
+}
+
+// The partitions on a disk as (number, first block, number of blocks), or None if there's no partition table
+// The numbers are the slots in the table, so they don't shift around when one in the middle is empty
+// Anything hanging off the end of the disk gets cut short, and anything that starts past it is left out
+fn read_table<D: BlockDevice>(driver: &mut D) -> Result<Option<Vec<(u64, u64, u64)>>, FsError> {
+    let capacity = driver.block_count();
+    let table = match read_entries(driver)? {
+        Some(table) => table,
+        None => return Ok(None),
+    };
+    Ok(Some(table.into_iter()
+        .filter(|(_, start, _)| *start < capacity)
+        .map(|(number, start, sectors)| (number, start, sectors.min(capacity - start)))
+        .collect()))
+}
+
+// What the MBR (or the GPT behind it) says, whether it fits on the disk or not
+fn read_entries<D: BlockDevice>(driver: &mut D) -> Result<Option<Vec<(u64, u64, u64)>>, FsError> {
+    let mbr = driver.read_block(0)?;
+    if is_archive(&mbr) || mbr[510] != 0x55 || mbr[511] != 0xAA {
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    for i in 0..4 {
+        let entry = &mbr[446 + i * 16..446 + (i + 1) * 16];
+        let kind = entry[4];
+        if kind == MBR_PROTECTIVE {
+            return Ok(Some(read_gpt(driver)?));
+        }
+        let start = read_u32(entry, 8);
+        let sectors = read_u32(entry, 12);
+        if kind == 0 || MBR_EXTENDED.contains(&kind) || sectors == 0 {
+            continue;
+        }
+        partitions.push((i as u64 + 1, start, sectors));
+    }
+    Ok(Some(partitions))
+}
+
+// Every disk on its own, followed by each of its partitions
+// A disk whose partition table can't be read is still there as a whole. The tables are read again every time, so a
+// new one written to a disk through /dev shows up straight away
+pub fn partitions() -> Vec<Partition> {
+    let mut partitions = Vec::new();
+    for (name, mut driver) in disks() {
+        let capacity = driver.capacity_sectors();
+        let table = read_table(&mut driver);
+        partitions.push(Partition { driver: driver.clone(), name: name.clone(), number: None, start: 0, sectors: capacity });
+        if let Ok(Some(table)) = table {
+            for (number, start, sectors) in table {
+                partitions.push(Partition { driver: driver.clone(), name: format!("{}{}", name, number), number: Some(number), start, sectors });
+            }
+        }
+    }
+    partitions
+}
+
+// The name of the disk a partition is on - hda for hda1 and hda10, and a disk is on itself
+// Disks are named with letters only, so whatever digits are on the end are the partition number
+pub fn disk_of(name: &str) -> &str {
+    name.trim_end_matches(|c: char| c.is_ascii_digit())
+}
+
+// Whether a and b are the same disk or partition, or one is a partition of the other
+pub fn overlap(a: &str, b: &str) -> bool {
+    a == b || a == disk_of(b) || b == disk_of(a)
+}
+
+pub fn find(name: &str) -> Result<Partition, FsError> {
+    match partitions().into_iter().find(|p| p.name == name) {
+        Some(partition) => Ok(partition),
+        None => Err(FsError::NotFound),
+    }
+}
+
+// Where the file system at / lives - the first disk or partition that has already been made into an archive, either
+// by imgtool or by mkfs. A blank one or one with something else on it is never picked, since the first write would
+// go over whatever's there. Neither is a partitioned disk as a whole, since its first block is the partition table
+pub fn root_device() -> Result<Partition, FsError> {
+    let mut partitions = partitions();
+    let found = partitions.iter_mut().position(|partition| ustar::is_formatted(partition).unwrap_or(false));
+    match found {
+        Some(i) => Ok(partitions.remove(i)),
+        None => Err(FsError::NotFound),
+    }
+}
+
+// A disk of blocks blocks with an MBR that has entries as (slot, type, first block, number of blocks)
+#[cfg(test)]
+fn mbr_disk(blocks: u64, entries: &[(usize, u8, u32, u32)]) -> crate::block_device::RamDisk {
+    let mut disk = crate::block_device::RamDisk::new(blocks);
+    let mut mbr = alloc::vec![0; 512];
+    for (slot, kind, start, sectors) in entries.iter() {
+        let entry = &mut mbr[446 + slot * 16..446 + (slot + 1) * 16];
+        entry[4] = *kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    }
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    disk.write_blocks(0, &mbr).unwrap();
+    disk
+}
+
+// empty and extended slots are skipped without renumbering, and partitions that don't fit get cut short or dropped
+#[test_case]
+fn test_mbr() {
+    let mut disk = mbr_disk(100, &[(0, 0x83, 1, 20), (1, 0x05, 21, 10), (2, 0x83, 40, 100), (3, 0x83, 200, 10)]);
+    assert_eq!(read_table(&mut disk), Ok(Some(alloc::vec![(1, 1, 20), (3, 40, 60)])));
+
+    // An archive or a disk without the signature has no table at all
+    let mut disk = crate::block_device::RamDisk::new(10);
+    assert_eq!(read_table(&mut disk), Ok(None));
+    disk.write_blocks(0, &ustar_format::Header::new("a.txt", b'0', 0).to_block()).unwrap();
+    assert_eq!(read_table(&mut disk), Ok(None));
+}
+
+// the protective MBR sends it to the GPT, where unused entries are skipped and the same cutting short happens
+#[test_case]
+fn test_gpt() {
+    let mut disk = mbr_disk(100, &[(0, MBR_PROTECTIVE, 1, 99)]);
+    let mut header = alloc::vec![0; 512];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&4u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    disk.write_blocks(1, &header).unwrap();
+    let mut entries = alloc::vec![0; 512];
+    for (i, first, last) in [(0usize, 34u64, 49u64), (2, 50, 149), (3, 150, 160)].iter() {
+        let entry = &mut entries[i * 128..(i + 1) * 128];
+        entry[0] = 1;
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+    }
+    disk.write_blocks(2, &entries).unwrap();
+    assert_eq!(read_table(&mut disk), Ok(Some(alloc::vec![(1, 34, 16), (3, 50, 50)])));
+
+    // A header that isn't one is damaged, and so is an entry size that doesn't fit in a block
+    header[84..88].copy_from_slice(&100u32.to_le_bytes());
+    disk.write_blocks(1, &header).unwrap();
+    assert_eq!(read_table(&mut disk), Err(FsError::Corrupted));
+    disk.write_blocks(1, &[0; 512]).unwrap();
+    assert_eq!(read_table(&mut disk), Err(FsError::Corrupted));
+}
+
+// a disk covers its partitions, but not a disk whose name starts the same or another partition with more digits
+#[test_case]
+fn test_overlap() {
+    assert_eq!(disk_of("hda10"), "hda");
+    assert_eq!(disk_of("hdb"), "hdb");
+    assert!(overlap("hda", "hda"));
+    assert!(overlap("hda", "hda10"));
+    assert!(overlap("hda1", "hda"));
+    assert!(!overlap("hda1", "hda10"));
+    assert!(!overlap("hda1", "hda2"));
+    assert!(!overlap("hda", "hdb1"));
+    assert!(!overlap("hd", "hda"));
+}
@@ -0,0 +1,224 @@
+// A file system that's made up on the spot out of what the kernel is doing
+// It's mounted at /proc, so cat /proc/uptime and friends work the same way as reading any other file
+// Nothing on it can be changed
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::format;
+use core::fmt::Write;
+use x86_64::instructions::interrupts;
+use crate::alloc::string::ToString;
+use crate::allocator;
+use crate::keyboard_routing::{KEYBOARD_ROUTER, LAST_MODE};
+use crate::memory;
+use crate::partition;
+use crate::rtc;
+use crate::task::executor;
+use crate::timer_routing::{self, TIME_ROUTER};
+use crate::vfs::{FileSystem, FileType, FsError, Permissions, Stat};
+
+// Every file, and what makes what's in it
+const FILES: [(&str, fn() -> String); 7] = [
+    ("uptime", uptime),
+    ("heap", heap),
+    ("tasks", tasks),
+    ("keyboard", keyboard),
+    ("timer", timer),
+    ("drives", drives),
+    ("memmap", memmap),
+];
+
+// Seconds since boot, to the hundredth
+fn uptime() -> String {
+    let millis = timer_routing::uptime_millis();
+    format!("{}.{:02}\n", millis / 1000, millis % 1000 / 10)
+}
+
+fn heap() -> String {
+    let used = allocator::heap_used();
+    let free = allocator::heap_free();
+    format!("size: {}\nused: {}\nfree: {}\n", allocator::HEAP_SIZE, used, free)
+}
+
+fn tasks() -> String {
+    let mut result = String::from("id name polls\n");
+    for task in executor::tasks() {
+        let _ = writeln!(result, "{} {} {}", task.id, task.name, task.polls);
+    }
+    result
+}
+
+fn flag(result: &mut String, name: &str, value: bool) {
+    let _ = writeln!(result, "{}: {}", name, value);
+}
+
+fn keyboard() -> String {
+    let mut result = String::new();
+    interrupts::without_interrupts(|| {
+        // Commands get run from inside the keyboard router, so it's usually already locked by whoever is reading this,
+        // and then the mode it had when it got the key is the best there is
+        let mode = match KEYBOARD_ROUTER.try_lock() {
+            Some(router) => router.mode,
+            None => *LAST_MODE.lock(),
+        };
+        flag(&mut result, "terminal", mode.terminal);
+        flag(&mut result, "screenbuffer", mode.screenbuffer);
+        flag(&mut result, "tetris", mode.tetris);
+        flag(&mut result, "tetris_score", mode.tetris_score);
+        flag(&mut result, "song", mode.song);
+        flag(&mut result, "textedit", mode.textedit);
+        flag(&mut result, "brainf", mode.brainf);
+        flag(&mut result, "bfesc", mode.bfesc);
+    });
+    result
+}
+
+fn timer() -> String {
+    let mut result = String::new();
+    // The timer interrupt wants this lock too
+    interrupts::without_interrupts(|| {
+        let router = TIME_ROUTER.lock();
+        let mode = &router.mode;
+        flag(&mut result, "terminal", mode.terminal);
+        flag(&mut result, "tetris", mode.tetris);
+        flag(&mut result, "beep", mode.beep);
+        flag(&mut result, "song", mode.song);
+        flag(&mut result, "vim", mode.vim);
+    });
+    result
+}
+
+fn drives() -> String {
+    let mut result = String::new();
+    for (name, driver) in partition::disks() {
+        let _ = write!(result, "{}:\n{}", name, driver.properties());
+    }
+    result
+}
+
+fn memmap() -> String {
+    let mut result = String::new();
+    for (start, end, kind) in memory::memory_regions() {
+        let _ = writeln!(result, "{:#014x}-{:#014x} {}", start, end, kind);
+    }
+    result
+}
+
+// Everything is owned by root, and anyone can look
+fn permissions(mode: u64) -> Permissions {
+    Permissions {
+        mode,
+        owner_id: 0,
+        group_id: 0,
+        owner_name: "root".to_string(),
+        group_name: "root".to_string(),
+    }
+}
+
+fn file_stat(name: &str, size: u64) -> Stat {
+    Stat {
+        name: name.to_string(),
+        file_type: FileType::File,
+        size,
+        mtime: rtc::unix_time(),
+        linked_name: None,
+        permissions: permissions(0o444),
+    }
+}
+
+pub struct ProcFileSystem {}
+
+impl Default for ProcFileSystem {
+    fn default() -> ProcFileSystem {
+        ProcFileSystem::new()
+    }
+}
+
+impl ProcFileSystem {
+    pub fn new() -> ProcFileSystem {
+        ProcFileSystem {}
+    }
+
+    // Finds what makes the file at path - None is the root
+    fn find(&self, path: &str) -> Result<Option<fn() -> String>, FsError> {
+        let name = path.trim_matches('/');
+        if name.is_empty() {
+            return Ok(None);
+        }
+        match FILES.iter().find(|(file, _)| *file == name) {
+            Some((_, make)) => Ok(Some(*make)),
+            None => Err(FsError::NotFound),
+        }
+    }
+}
+
+impl FileSystem for ProcFileSystem {
+    fn lookup(&mut self, path: &str) -> Result<FileType, FsError> {
+        match self.find(path)? {
+            Some(_) => Ok(FileType::File),
+            None => Ok(FileType::Directory),
+        }
+    }
+
+    // The size is however long the file is right now
+    fn stat(&mut self, path: &str) -> Result<Stat, FsError> {
+        match self.find(path)? {
+            Some(make) => Ok(file_stat(path.trim_matches('/'), make().len() as u64)),
+            None => Ok(Stat {
+                name: String::new(),
+                file_type: FileType::Directory,
+                size: 0,
+                mtime: rtc::unix_time(),
+                linked_name: None,
+                permissions: permissions(0o555),
+            }),
+        }
+    }
+
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        match self.find(path)? {
+            Some(make) => Ok(make().into_bytes()),
+            None => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn write(&mut self, _path: &str, _data: Vec<u8>) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn readdir(&mut self, path: &str) -> Result<Vec<Stat>, FsError> {
+        if self.find(path)?.is_some() {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(FILES.iter().map(|(name, make)| file_stat(name, make().len() as u64)).collect())
+    }
+
+    fn mkdir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn rmdir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+}
+
+// every file can be read, and none of them can be changed
+#[test_case]
+fn test_procfs() {
+    let mut fs = ProcFileSystem::new();
+    assert_eq!(fs.lookup("/memmap"), Ok(FileType::File));
+    assert!(fs.read("/uptime").is_ok());
+    assert!(fs.read("/heap").unwrap().starts_with(b"size: "));
+    assert_eq!(fs.read("/nope"), Err(FsError::NotFound));
+    assert_eq!(fs.write("/uptime", Vec::new()), Err(FsError::PermissionDenied));
+    assert_eq!(fs.lookup("/"), Ok(FileType::Directory));
+
+    // the keyboard file still works from a command, while the router is busy running it
+    let router = KEYBOARD_ROUTER.lock();
+    assert!(fs.read("/keyboard").unwrap().starts_with(b"terminal: "));
+    drop(router);
+}
@@ -0,0 +1,201 @@
+// Reads the wall clock time out of the CMOS real time clock
+// https://wiki.osdev.org/CMOS
+
+use x86::io::inb; use x86::io::outb;
+use x86_64::instructions::interrupts;
+use core::fmt;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0A;
+const REGISTER_STATUS_B: u8 = 0x0B;
+
+// Status A - set while the clock is updating its registers, which can be half changed at the time
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+// Status B - 24 hour time instead of 12, and plain binary numbers instead of BCD
+const HOURS_24: u8 = 0x02;
+const BINARY_MODE: u8 = 0x04;
+// In 12 hour mode the top bit of the hours is set for PM
+const HOUR_PM: u8 = 0x80;
+
+// The clock only has two digits for the year
+const CENTURY: u16 = 2000;
+
+// A point in time - the clock in QEMU (and most machines) is set to UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+// The registers as they come off the clock, before they get decoded
+#[derive(PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        outb(CMOS_ADDRESS, register);
+        inb(CMOS_DATA)
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REGISTER_STATUS_A) & UPDATE_IN_PROGRESS != 0
+}
+
+fn read_raw() -> RawTime {
+    while update_in_progress() {}
+    RawTime {
+        second: read_register(REGISTER_SECONDS),
+        minute: read_register(REGISTER_MINUTES),
+        hour: read_register(REGISTER_HOURS),
+        day: read_register(REGISTER_DAY),
+        month: read_register(REGISTER_MONTH),
+        year: read_register(REGISTER_YEAR),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+// Gets the current time off the clock
+pub fn now() -> DateTime {
+    interrupts::without_interrupts(|| {
+        // An update can still start partway through reading, so keep going until two reads in a row agree
+        let mut last = read_raw();
+        loop {
+            let current = read_raw();
+            if current == last {
+                break;
+            }
+            last = current;
+        }
+        let status = read_register(REGISTER_STATUS_B);
+        let pm = last.hour & HOUR_PM != 0;
+        let mut hour = last.hour & !HOUR_PM;
+        let (second, minute, day, month, year) = if status & BINARY_MODE == 0 {
+            hour = from_bcd(hour);
+            (from_bcd(last.second), from_bcd(last.minute), from_bcd(last.day), from_bcd(last.month), from_bcd(last.year))
+        }
+        else {
+            (last.second, last.minute, last.day, last.month, last.year)
+        };
+        // 12 AM is midnight and 12 PM is noon
+        if status & HOURS_24 == 0 {
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+        DateTime {
+            year: CENTURY + year as u16,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    })
+}
+
+// Seconds since 1970 - what goes in the mtime of a file
+pub fn unix_time() -> u64 {
+    now().to_unix()
+}
+
+fn is_leap_year(year: u64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 => if is_leap_year(year) { 29 } else { 28 },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    pub fn to_unix(&self) -> u64 {
+        let mut days = 0;
+        for year in 1970..self.year as u64 {
+            days += if is_leap_year(year) { 366 } else { 365 };
+        }
+        for month in 1..self.month as u64 {
+            days += days_in_month(self.year as u64, month);
+        }
+        days += self.day as u64 - 1;
+        ((days * 24 + self.hour as u64) * 60 + self.minute as u64) * 60 + self.second as u64
+    }
+
+    pub fn from_unix(time: u64) -> DateTime {
+        let mut days = time / 86400;
+        let seconds = time % 86400;
+        let mut year = 1970;
+        loop {
+            let length = if is_leap_year(year) { 366 } else { 365 };
+            if days < length {
+                break;
+            }
+            days -= length;
+            year += 1;
+        }
+        let mut month = 1;
+        while days >= days_in_month(year, month) {
+            days -= days_in_month(year, month);
+            month += 1;
+        }
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: days as u8 + 1,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// checks that converting to seconds and back gives the same time
+#[test_case]
+fn test_unix_time_round_trip() {
+    let time = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 58 };
+    assert_eq!(time.to_unix(), 1709251198);
+    assert_eq!(DateTime::from_unix(time.to_unix()), time);
+    assert_eq!(DateTime::from_unix(0), DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 });
+}
+
+// checks that the clock gives back something that makes sense
+#[test_case]
+fn test_now() {
+    let time = now();
+    assert!(time.year >= 2020);
+    assert!(time.month >= 1 && time.month <= 12);
+    assert!(time.day >= 1 && time.day <= 31);
+    assert!(time.hour < 24 && time.minute < 60 && time.second < 60);
+}
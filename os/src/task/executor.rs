@@ -1,5 +1,5 @@
 use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc,task::Wake};
+use alloc::{collections::BTreeMap, sync::Arc,task::Wake, vec::Vec};
 use crossbeam_queue::ArrayQueue;
 use core::task::{Context, Poll,Waker};
 use x86_64::instructions::interrupts::{self, enable_interrupts_and_hlt};
@@ -12,6 +12,26 @@ use spin::Mutex;
 // unsafe{EXECUTOR.force_unlock()}
 // EXECUTOR.lock().spawn(Task::new(FUNCTION_NAME()));
 
+// what procfs gets told about a task
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: &'static str,
+    // how many times it's been run
+    pub polls: u64,
+}
+
+// every task that hasn't finished yet, from every executor
+// kept outside of the executor so it can be looked at while a task is running
+lazy_static! {
+    static ref TASK_LIST: Mutex<BTreeMap<TaskId, TaskInfo>> = Mutex::new(BTreeMap::new());
+}
+
+// the tasks that are still around, oldest first
+pub fn tasks() -> Vec<TaskInfo> {
+    TASK_LIST.lock().values().cloned().collect()
+}
+
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
@@ -68,6 +88,7 @@ impl Executor {
 
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        TASK_LIST.lock().insert(task_id, TaskInfo { id: task_id.0, name: task.name, polls: 0 });
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
@@ -111,11 +132,15 @@ impl Executor {
                 .entry(task_id)
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
+            if let Some(info) = TASK_LIST.lock().get_mut(&task_id) {
+                info.polls += 1;
+            }
             // runs task and removes it if it's finished
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    TASK_LIST.lock().remove(&task_id);
                 }
                 Poll::Pending => {}
             }
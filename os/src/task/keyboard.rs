@@ -5,8 +5,12 @@ use futures_util::{stream::Stream,task::AtomicWaker,stream::StreamExt};
 use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
 use crate::println;
 use crate::keyboard_routing::KEYBOARD_ROUTER;
+use crate::task::writeback;
 use x86_64::instructions::interrupts;
 
+// what the keyboard sends when enter goes down
+const ENTER_PRESSED: u8 = 0x1C;
+
 static WAKER: AtomicWaker = AtomicWaker::new();
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
@@ -18,6 +22,11 @@ pub async fn print_keypresses() {
 
     // waits for next keypress then hands it over to the KEYBOARD_ROUTER to handle
     while let Some(scancode) = scancodes.next().await {
+        // enter runs a command, which could want the disk while it's being written in the background - so that waits
+        // until it's done, but everything typed before then still shows up
+        if scancode == ENTER_PRESSED {
+            writeback::Idle.await;
+        }
         interrupts::without_interrupts(|| {
             KEYBOARD_ROUTER.lock().handle_scancode(scancode,&mut keyboard);
         });
@@ -4,6 +4,7 @@ use core::task::{Context, Poll};
 use core::sync::atomic::{AtomicU64, Ordering};
 pub mod keyboard;
 pub mod executor;
+pub mod writeback;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
@@ -18,14 +19,22 @@ impl TaskId {
 // a task is a wrapper for a async defined function.
 pub struct Task {
     id: TaskId,
+    // What shows up in /proc/tasks
+    name: &'static str,
     future: Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
 }
 
 impl Task {
     // creates a new task with a unique id and a new future
     pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task::named("task", future)
+    }
+
+    // same as new, but with a name to tell it apart from the others
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            name,
             future: Box::pin(future),
         }
     }
@@ -0,0 +1,111 @@
+// writes whatever is waiting in the cache of the file system at / out to its disk, in the background
+// the drive raises an interrupt when it's ready for the next sector, and the executor runs everything else in between,
+// so typing and the cursor keep going while sync and defrag are writing
+use core::{future::Future, pin::Pin, task::{Poll, Context}};
+use core::sync::atomic::{AtomicBool, Ordering};
+use futures_util::task::AtomicWaker;
+use alloc::vec::Vec;
+use crate::println;
+use crate::partition::Partition;
+use crate::ustar::USTARFS;
+use crate::vfs::FsError;
+use x86_64::instructions::interrupts;
+
+// someone asked for a write-back that hasn't started yet
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+// one is going on right now
+static WRITING: AtomicBool = AtomicBool::new(false);
+static WAKER: AtomicWaker = AtomicWaker::new();
+// whoever is waiting for it to be done
+static IDLE_WAKER: AtomicWaker = AtomicWaker::new();
+
+// gets the write-back task going - everything that's in the cache by the time it runs goes out
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+    WAKER.wake();
+}
+
+// whether there's anything left to do, or still being done
+pub fn is_busy() -> bool {
+    REQUESTED.load(Ordering::SeqCst) || WRITING.load(Ordering::SeqCst)
+}
+
+// the task that does the writing, started once at boot
+pub async fn write_back() {
+    loop {
+        Requested.await;
+        WRITING.store(true, Ordering::SeqCst);
+        REQUESTED.store(false, Ordering::SeqCst);
+        if let Err(err) = write_out().await {
+            println!("\nCouldn't write everything to the disk: {}", err);
+        }
+        WRITING.store(false, Ordering::SeqCst);
+        IDLE_WAKER.wake();
+    }
+}
+
+// writes the cache out a batch at a time, oldest first, until there's nothing left in it
+// the file system is only locked while a batch is being handed over, so everything else can still use it
+pub async fn write_out() -> Result<(), FsError> {
+    let root = match USTARFS.as_ref() {
+        Some(root) => root,
+        None => return Ok(()),
+    };
+    loop {
+        // the timer might want the file system too, for saving tetris scores
+        let batch = interrupts::without_interrupts(|| root.lock().start_write_back());
+        let (disk, runs) = match batch {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+        let result = write_runs(&disk, &runs).await;
+        interrupts::without_interrupts(|| root.lock().finish_write_back(result))?;
+    }
+}
+
+async fn write_runs(disk: &Partition, runs: &[(u64, Vec<u8>)]) -> Result<(), FsError> {
+    for (start, data) in runs.iter() {
+        disk.write_blocks_async(*start, data).await?;
+    }
+    // the next batch can't get to the disk before this one has
+    disk.flush_async().await
+}
+
+// waits for request to be called
+struct Requested;
+
+impl Future for Requested {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if REQUESTED.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        // same as the scancode stream - it could be requested in between checking and registering
+        WAKER.register(&cx.waker());
+        if REQUESTED.load(Ordering::SeqCst) {
+            WAKER.take();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+// waits until nothing is being written in the background
+pub struct Idle;
+
+impl Future for Idle {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if !is_busy() {
+            return Poll::Ready(());
+        }
+        IDLE_WAKER.register(&cx.waker());
+        if !is_busy() {
+            IDLE_WAKER.take();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
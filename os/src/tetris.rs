@@ -11,7 +11,8 @@ use rand_pcg::Lcg128Xsl64;
 use rand_core::{SeedableRng,RngCore};
 use alloc::string::String;
 use alloc::string::ToString;
-use crate::ustar::USTARFS;
+use crate::vfs::{FsError, VFS};
+use crate::fd::{OPEN_WRITE, OPEN_CREATE, OPEN_TRUNCATE};
 use serde::{Serialize, Deserialize};
 use alloc::vec::Vec;
 use postcard::{from_bytes, to_allocvec};
@@ -511,21 +512,29 @@ impl Tetris {
     }
 
     // Handles serde deseralization
+    // No score file (or one that's been mangled) just means nobody has played yet
     fn read_highscores(&self) -> Vec<HighScoreItem> {
-        if let Some(saved_scores) = USTARFS.lock().read_file("/os/tetris.txt".to_string(), None) {
-            let result: Vec<HighScoreItem> = from_bytes(saved_scores.deref()).unwrap();
-            result
-        }
-        else {
-            panic!("Tetris score file not found");
+        let saved_scores = match VFS.lock().read_file("/os/tetris.txt".to_string(), None) {
+            Ok(saved_scores) => saved_scores,
+            Err(_) => return Vec::new(),
+        };
+        match from_bytes(saved_scores.deref()) {
+            Ok(result) => result,
+            Err(_) => Vec::new(),
         }
     }
 
     // Handles serde serialization
-    fn write_highscores(&self, highscores: &Vec<HighScoreItem>) {
+    fn write_highscores(&self, highscores: &Vec<HighScoreItem>) -> Result<(), FsError> {
         let output = to_allocvec(highscores).unwrap();
-        USTARFS.lock().remove_file("/os/tetris.txt".to_string(),None);
-        USTARFS.lock().write_file("/os/tetris.txt".to_string(), output, None);
+        let mut vfs = VFS.lock();
+        // This runs from the timer, so it isn't any task's - it gets an id of its own just for the save
+        let id = vfs.get_id();
+        let saved = vfs.open("/os/tetris.txt".to_string(), OPEN_WRITE | OPEN_CREATE | OPEN_TRUNCATE, id)
+            .and_then(|fd| vfs.write(fd, &output, id).map(|_| fd))
+            .and_then(|fd| vfs.close(fd, id));
+        vfs.release_id(id)?;
+        saved
     }
 
     // Handles the endgame loop - game end animation and then scoreboard
@@ -561,13 +570,17 @@ impl Tetris {
             },
             _ => {
                 if self.get() == 9 {
-                    self.write_highscores(&self.highscores);
+                    let saved = self.write_highscores(&self.highscores);
                     unsafe {TIME_ROUTER.force_unlock()};
                     KEYBOARD_ROUTER.lock().mode.terminal = true;
                     KEYBOARD_ROUTER.lock().mode.tetris_score = false;
                     TIME_ROUTER.lock().mode.terminal = true;
                     TIME_ROUTER.lock().mode.tetris = false;
                     ADVANCED_WRITER.lock().wipe_buffer();
+                    // The screen only goes back to text here, so this is the first place it can be said
+                    if let Err(err) = saved {
+                        print!("\nCouldn't save the high scores: {}", err);
+                    }
                     println!();
                 }
             }
@@ -1,5 +1,6 @@
 use lazy_static::lazy_static;
 use spin::Mutex;
+use core::sync::atomic::{AtomicU64, Ordering};
 use crate::{
     tetris::TETRIS,
     rng::RNGSEED,
@@ -38,6 +39,19 @@ impl Modes {
     }
 }
 
+// The PIT is never reprogrammed, so the timer goes off every 65536 of its 1193182 ticks a second (about 18.2 times)
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_DIVISOR: u64 = 65536;
+
+// How many times the timer has gone off since boot
+// Not in the TimeRouter, so it can be read without worrying about the interrupt handler holding the lock
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// How long it's been since boot, in milliseconds
+pub fn uptime_millis() -> u64 {
+    TICKS.load(Ordering::Relaxed) * PIT_DIVISOR * 1000 / PIT_FREQUENCY
+}
+
 pub struct TimeRouter {
     pub mode: Modes,
 }
@@ -49,6 +63,7 @@ impl TimeRouter {
 
     // Called on every timer interrupt
     pub fn handle(&mut self) {
+        TICKS.fetch_add(1, Ordering::Relaxed);
         if self.mode.terminal {
             MODE.lock().blink_current();
             RNGSEED.lock().inc();
@@ -0,0 +1,470 @@
+// A file system that only lives in memory - everything on it is gone on a reboot
+// It's mounted at /tmp, so scratch files don't end up in the archive on the disk
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use crate::alloc::string::ToString;
+use crate::rtc;
+use crate::users;
+use crate::vfs::{FileSystem, FileType, FsError, Permissions, Stat};
+
+// How much of the heap to leave alone - the rest of the OS still needs some
+const HEAP_RESERVE: u64 = 2 * 1024 * 1024;
+// How many links to follow before deciding it's a loop
+const MAX_LINK_DEPTH: usize = 16;
+
+enum Contents {
+    File(Vec<u8>),
+    Directory,
+    // Where it points
+    Symlink(String),
+}
+
+struct Node {
+    contents: Contents,
+    // Seconds since 1970
+    mtime: u64,
+    permissions: Permissions,
+}
+
+impl Node {
+    // Something new, owned by whoever is making it
+    fn new(contents: Contents, mode: u64) -> Node {
+        let user = users::current_user();
+        Node {
+            contents,
+            mtime: rtc::unix_time(),
+            permissions: Permissions {
+                mode,
+                owner_id: user.uid,
+                group_id: user.gid,
+                owner_name: users::user_name(user.uid),
+                group_name: users::group_name(user.gid),
+            },
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        match self.contents {
+            Contents::File(_) => FileType::File,
+            Contents::Directory => FileType::Directory,
+            Contents::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    // Can the current user do access (users::READ, WRITE and/or EXECUTE) to it
+    fn can(&self, access: u64) -> bool {
+        users::can_access(self.permissions.mode, self.permissions.owner_id, self.permissions.group_id, access)
+    }
+
+    fn stat(&self, name: &str) -> Stat {
+        let (size, linked_name) = match &self.contents {
+            Contents::File(data) => (data.len() as u64, None),
+            Contents::Directory => (0, None),
+            Contents::Symlink(target) => (0, Some(target.to_string())),
+        };
+        Stat {
+            name: name.to_string(),
+            file_type: self.file_type(),
+            size,
+            mtime: self.mtime,
+            linked_name,
+            permissions: self.permissions.clone(),
+        }
+    }
+}
+
+// Paths come in as /a/b, maybe with a / on the end
+fn normalize(path: &str) -> String {
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        "/".to_string()
+    }
+    else if !path.starts_with('/') {
+        format!("/{}", path)
+    }
+    else {
+        path.to_string()
+    }
+}
+
+// The directory something is in - / is its own parent
+fn parent(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(index) => path[..index].to_string(),
+    }
+}
+
+fn short_name(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(index) => &path[index + 1..],
+        None => path,
+    }
+}
+
+// Is path the directory dir, or something inside of it
+fn is_inside(path: &str, dir: &str) -> bool {
+    dir == "/" || path == dir || (path.starts_with(dir) && path[dir.len()..].starts_with('/'))
+}
+
+// Where a link in dir pointing at target ends up, with the . and .. taken out
+fn resolve_target(dir: &str, target: &str) -> String {
+    let full = if target.starts_with('/') {
+        target.to_string()
+    }
+    else {
+        format!("{}/{}", dir, target)
+    };
+    let mut parts: Vec<&str> = Vec::new();
+    for part in full.split('/') {
+        match part {
+            "" | "." => {},
+            ".." => {
+                parts.pop();
+            },
+            _ => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+pub struct TmpFileSystem {
+    // Everything on it by its full path, without a / on the end - / itself is always there
+    nodes: BTreeMap<String, Node>,
+}
+
+impl Default for TmpFileSystem {
+    fn default() -> TmpFileSystem {
+        TmpFileSystem::new()
+    }
+}
+
+impl TmpFileSystem {
+    pub fn new() -> TmpFileSystem {
+        let mut nodes = BTreeMap::new();
+        // Anyone can make things in it, like /tmp on anything else
+        nodes.insert("/".to_string(), Node::new(Contents::Directory, 0o777));
+        TmpFileSystem { nodes }
+    }
+
+    // Follows symlinks until it gets to something that isn't one
+    fn follow(&self, path: &str) -> Result<String, FsError> {
+        let mut path = normalize(path);
+        for _ in 0..MAX_LINK_DEPTH {
+            match self.nodes.get(&path) {
+                Some(Node { contents: Contents::Symlink(target), .. }) => path = resolve_target(&parent(&path), target),
+                Some(_) => return Ok(path),
+                None => return Err(FsError::NotFound),
+            }
+        }
+        Err(FsError::InvalidArgument)
+    }
+
+    fn node(&self, path: &str) -> Result<&Node, FsError> {
+        match self.nodes.get(path) {
+            Some(node) => Ok(node),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    // The directory that something new at path would go in, which the current user has to be able to change
+    fn check_parent(&self, path: &str) -> Result<(), FsError> {
+        if path == "/" {
+            return Err(FsError::Busy);
+        }
+        let dir = self.node(&self.follow(&parent(path))?)?;
+        match dir.contents {
+            Contents::Directory => {},
+            _ => return Err(FsError::NotADirectory),
+        }
+        if !dir.can(users::WRITE | users::EXECUTE) {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // Puts something new at path, which has to be empty
+    fn create(&mut self, path: &str, node: Node) -> Result<(), FsError> {
+        let path = normalize(path);
+        self.check_parent(&path)?;
+        if self.nodes.contains_key(&path) {
+            return Err(FsError::AlreadyExists);
+        }
+        self.nodes.insert(path, node);
+        Ok(())
+    }
+
+    // Is there room on the heap for size more bytes
+    fn has_room(&self, size: u64) -> bool {
+        (crate::allocator::heap_free() as u64) >= size + HEAP_RESERVE
+    }
+}
+
+impl FileSystem for TmpFileSystem {
+    fn lookup(&mut self, path: &str) -> Result<FileType, FsError> {
+        Ok(self.node(&normalize(path))?.file_type())
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Stat, FsError> {
+        let target = self.follow(path)?;
+        let name = short_name(&normalize(path)).to_string();
+        Ok(self.node(&target)?.stat(&name))
+    }
+
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        let node = self.node(&self.follow(path)?)?;
+        if !node.can(users::READ) {
+            return Err(FsError::PermissionDenied);
+        }
+        match &node.contents {
+            Contents::File(data) => Ok(data.to_vec()),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    // Only copies out the part that was asked for
+    fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let node = self.node(&self.follow(path)?)?;
+        if !node.can(users::READ) {
+            return Err(FsError::PermissionDenied);
+        }
+        let data = match &node.contents {
+            Contents::File(data) => data,
+            _ => return Err(FsError::IsADirectory),
+        };
+        let start = (offset as usize).min(data.len());
+        let count = buf.len().min(data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn write(&mut self, path: &str, data: Vec<u8>) -> Result<(), FsError> {
+        if !self.has_room(data.len() as u64) {
+            return Err(FsError::NoSpace);
+        }
+        // Writing through a link writes what it points at
+        let path = match self.follow(path) {
+            Ok(path) => path,
+            Err(FsError::NotFound) => normalize(path),
+            Err(err) => return Err(err),
+        };
+        match self.nodes.get_mut(&path) {
+            Some(node) => {
+                match node.contents {
+                    Contents::File(_) => {},
+                    Contents::Directory => return Err(FsError::IsADirectory),
+                    // A link to nothing
+                    Contents::Symlink(_) => return Err(FsError::NotFound),
+                }
+                if !node.can(users::WRITE) {
+                    return Err(FsError::PermissionDenied);
+                }
+                node.contents = Contents::File(data);
+                node.mtime = rtc::unix_time();
+                Ok(())
+            },
+            None => self.create(&path, Node::new(Contents::File(data), 0o644)),
+        }
+    }
+
+    // Only the part that changed gets copied in
+    fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        if !self.has_room(data.len() as u64) {
+            return Err(FsError::NoSpace);
+        }
+        let path = self.follow(path)?;
+        let node = match self.nodes.get_mut(&path) {
+            Some(node) => node,
+            None => return Err(FsError::NotFound),
+        };
+        if !node.can(users::WRITE) {
+            return Err(FsError::PermissionDenied);
+        }
+        let contents = match &mut node.contents {
+            Contents::File(contents) => contents,
+            _ => return Err(FsError::IsADirectory),
+        };
+        let start = offset as usize;
+        if contents.len() < start + data.len() {
+            contents.resize(start + data.len(), 0);
+        }
+        contents[start..start + data.len()].copy_from_slice(data);
+        node.mtime = rtc::unix_time();
+        Ok(data.len())
+    }
+
+    fn readdir(&mut self, path: &str) -> Result<Vec<Stat>, FsError> {
+        let dir = self.follow(path)?;
+        let node = self.node(&dir)?;
+        match node.contents {
+            Contents::Directory => {},
+            _ => return Err(FsError::NotADirectory),
+        }
+        if !node.can(users::READ) {
+            return Err(FsError::PermissionDenied);
+        }
+        let mut result = Vec::new();
+        for (child, node) in self.nodes.iter() {
+            if child != "/" && parent(child) == dir {
+                result.push(node.stat(short_name(child)));
+            }
+        }
+        Ok(result)
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), FsError> {
+        self.create(path, Node::new(Contents::Directory, 0o755))
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let path = normalize(path);
+        if let Contents::Directory = self.node(&path)?.contents {
+            return Err(FsError::IsADirectory);
+        }
+        self.check_parent(&path)?;
+        self.nodes.remove(&path);
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        let path = normalize(path);
+        match self.node(&path)?.contents {
+            Contents::Directory => {},
+            _ => return Err(FsError::NotADirectory),
+        }
+        self.check_parent(&path)?;
+        if self.nodes.keys().any(|child| child != &path && is_inside(child, &path)) {
+            return Err(FsError::Busy);
+        }
+        self.nodes.remove(&path);
+        Ok(())
+    }
+
+    // Moves everything under from too, if it's a directory
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let from = normalize(from);
+        let to = normalize(to);
+        self.node(&from)?;
+        self.check_parent(&from)?;
+        self.check_parent(&to)?;
+        if self.nodes.contains_key(&to) {
+            return Err(FsError::AlreadyExists);
+        }
+        if is_inside(&to, &from) {
+            return Err(FsError::InvalidArgument);
+        }
+        let moving: Vec<String> = self.nodes.keys().filter(|path| is_inside(path, &from)).cloned().collect();
+        for path in moving {
+            if let Some(node) = self.nodes.remove(&path) {
+                self.nodes.insert(format!("{}{}", to, &path[from.len()..]), node);
+            }
+        }
+        Ok(())
+    }
+
+    // Only symlinks - a hard link would need the two names to share their data
+    fn link(&mut self, target: &str, link: &str, symbolic: bool) -> Result<(), FsError> {
+        if !symbolic {
+            return Err(FsError::Unsupported);
+        }
+        self.create(link, Node::new(Contents::Symlink(target.to_string()), 0o777))
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, FsError> {
+        match &self.node(&normalize(path))?.contents {
+            Contents::Symlink(target) => Ok(target.to_string()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    // Only the owner (or root) can change the permission bits
+    fn chmod(&mut self, path: &str, mode: u64) -> Result<(), FsError> {
+        let path = self.follow(path)?;
+        let user = users::current_user();
+        let node = match self.nodes.get_mut(&path) {
+            Some(node) => node,
+            None => return Err(FsError::NotFound),
+        };
+        if user.uid != 0 && user.uid != node.permissions.owner_id {
+            return Err(FsError::PermissionDenied);
+        }
+        node.permissions.mode = mode & 0o7777;
+        Ok(())
+    }
+
+    // Only root can give things away
+    fn chown(&mut self, path: &str, owner_id: u64) -> Result<(), FsError> {
+        let path = self.follow(path)?;
+        if users::current_user().uid != 0 {
+            return Err(FsError::PermissionDenied);
+        }
+        let node = match self.nodes.get_mut(&path) {
+            Some(node) => node,
+            None => return Err(FsError::NotFound),
+        };
+        node.permissions.owner_id = owner_id;
+        node.permissions.owner_name = users::user_name(owner_id);
+        Ok(())
+    }
+
+    // Root can change the group to anything, the owner can only change it to their own group
+    fn chgrp(&mut self, path: &str, group_id: u64) -> Result<(), FsError> {
+        let path = self.follow(path)?;
+        let user = users::current_user();
+        let node = match self.nodes.get_mut(&path) {
+            Some(node) => node,
+            None => return Err(FsError::NotFound),
+        };
+        if user.uid != 0 && (user.uid != node.permissions.owner_id || user.gid != group_id) {
+            return Err(FsError::PermissionDenied);
+        }
+        node.permissions.group_id = group_id;
+        node.permissions.group_name = users::group_name(group_id);
+        Ok(())
+    }
+}
+
+// files and directories can be made, read, moved and removed
+#[test_case]
+fn test_tmpfs() {
+    let mut fs = TmpFileSystem::new();
+    assert_eq!(fs.mkdir("/a"), Ok(()));
+    assert_eq!(fs.write("/a/b.txt", b"hello".to_vec()), Ok(()));
+    assert_eq!(fs.read("/a/b.txt"), Ok(b"hello".to_vec()));
+    assert_eq!(fs.mkdir("/a"), Err(FsError::AlreadyExists));
+    assert_eq!(fs.write("/c/d.txt", Vec::new()), Err(FsError::NotFound));
+    assert_eq!(fs.rename("/a", "/e"), Ok(()));
+    assert_eq!(fs.read("/e/b.txt"), Ok(b"hello".to_vec()));
+    assert_eq!(fs.rename("/e", "/e/f"), Err(FsError::InvalidArgument));
+    assert_eq!(fs.rmdir("/e"), Err(FsError::Busy));
+    assert_eq!(fs.unlink("/e/b.txt"), Ok(()));
+    assert_eq!(fs.rmdir("/e"), Ok(()));
+    assert_eq!(fs.readdir("/").map(|list| list.len()), Ok(0));
+}
+
+// writing at an offset only changes that part, and a gap past the end gets zeros
+#[test_case]
+fn test_tmpfs_write_at() {
+    let mut fs = TmpFileSystem::new();
+    assert_eq!(fs.write("/a.txt", b"hello".to_vec()), Ok(()));
+    assert_eq!(fs.write_at("/a.txt", 1, b"ipp"), Ok(3));
+    assert_eq!(fs.write_at("/a.txt", 7, b"!"), Ok(1));
+    assert_eq!(fs.read("/a.txt"), Ok(b"hippo\0\0!".to_vec()));
+    assert_eq!(fs.write_at("/b.txt", 0, b"b"), Err(FsError::NotFound));
+}
+
+// symlinks get followed, and a loop of them doesn't hang
+#[test_case]
+fn test_tmpfs_symlinks() {
+    let mut fs = TmpFileSystem::new();
+    assert_eq!(fs.write("/a.txt", b"a".to_vec()), Ok(()));
+    assert_eq!(fs.link("a.txt", "/l", true), Ok(()));
+    assert_eq!(fs.read("/l"), Ok(b"a".to_vec()));
+    assert_eq!(fs.lookup("/l"), Ok(FileType::Symlink));
+    assert_eq!(fs.link("/y", "/x", true), Ok(()));
+    assert_eq!(fs.link("/x", "/y", true), Ok(()));
+    assert_eq!(fs.read("/x"), Err(FsError::InvalidArgument));
+}
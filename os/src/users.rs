@@ -0,0 +1,117 @@
+// Keeps track of who is using the OS, and what they're allowed to touch
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use alloc::string::String;
+use crate::alloc::string::ToString;
+
+// What someone wants to do with a file - these match up with the rwx bits of a mode
+pub const READ: u64 = 4;
+pub const WRITE: u64 = 2;
+pub const EXECUTE: u64 = 1;
+
+#[derive(Debug, Clone)]
+pub struct User {
+    pub uid: u64,
+    pub gid: u64,
+    pub name: String,
+}
+
+// Users that have a name - anyone else can still be used by their number
+// weed is who owned everything before there were users
+const KNOWN_USERS: [(&str, u64, u64); 2] = [
+    ("root", 0, 0),
+    ("weed", 420, 420),
+];
+
+impl User {
+    pub fn root() -> User {
+        User { uid: 0, gid: 0, name: "root".to_string() }
+    }
+
+    // Finds a user by name or by number - a user that's only a number is in the group with the same number
+    pub fn lookup(name: &str) -> Option<User> {
+        for (known, uid, gid) in KNOWN_USERS.iter() {
+            if *known == name {
+                return Some(User { uid: *uid, gid: *gid, name: name.to_string() });
+            }
+        }
+        match name.parse::<u64>() {
+            Ok(uid) => Some(User { uid, gid: uid, name: user_name(uid) }),
+            Err(_) => None,
+        }
+    }
+}
+
+// The name that goes in headers for a user id
+pub fn user_name(uid: u64) -> String {
+    for (known, known_uid, _) in KNOWN_USERS.iter() {
+        if *known_uid == uid {
+            return known.to_string();
+        }
+    }
+    uid.to_string()
+}
+
+// Groups are named after the user with the same number
+pub fn group_name(gid: u64) -> String {
+    for (known, _, known_gid) in KNOWN_USERS.iter() {
+        if *known_gid == gid {
+            return known.to_string();
+        }
+    }
+    gid.to_string()
+}
+
+// Finds a group id by name or number
+pub fn lookup_group(name: &str) -> Option<u64> {
+    for (known, _, gid) in KNOWN_USERS.iter() {
+        if *known == name {
+            return Some(*gid);
+        }
+    }
+    name.parse::<u64>().ok()
+}
+
+// Can the current user do access (READ, WRITE and/or EXECUTE) to something with this mode and owner
+// root can do anything
+pub fn can_access(mode: u64, owner_id: u64, group_id: u64, access: u64) -> bool {
+    let user = CURRENT_USER.lock();
+    if user.uid == 0 {
+        return true;
+    }
+    let bits = if user.uid == owner_id {
+        (mode >> 6) & 7
+    }
+    else if user.gid == group_id {
+        (mode >> 3) & 7
+    }
+    else {
+        mode & 7
+    };
+    bits & access == access
+}
+
+pub fn current_user() -> User {
+    CURRENT_USER.lock().clone()
+}
+
+pub fn switch_user(user: User) {
+    *CURRENT_USER.lock() = user;
+}
+
+lazy_static! {
+    pub static ref CURRENT_USER: Mutex<User> = Mutex::new(User::root());
+}
+
+// checks the owner, group and everyone else bits are each used for the right people
+#[test_case]
+fn test_can_access() {
+    switch_user(User::lookup("weed").unwrap());
+    assert!(can_access(0o640, 420, 0, READ | WRITE));
+    assert!(!can_access(0o640, 0, 420, WRITE));
+    assert!(can_access(0o640, 0, 420, READ));
+    assert!(!can_access(0o640, 0, 0, READ));
+    switch_user(User::root());
+    assert!(can_access(0o000, 420, 420, READ | WRITE | EXECUTE));
+}
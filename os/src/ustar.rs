@@ -2,9 +2,12 @@
 
 use lazy_static::lazy_static;
 use spin::{Mutex};
-use crate::ata_block_driver::AtaPio;
-use alloc::vec::Vec;
+use crate::partition::{self, Partition};
+use crate::block_device::BlockDevice;
+use crate::block_cache::{BlockCache, CACHE_BLOCKS};
+use crate::journal::{Journal, JOURNAL_BLOCKS, JOURNAL_ENTRIES};
 use alloc::vec;
+use alloc::vec::Vec;
 use alloc::string::String;
 use hashbrown::HashMap;
 use core::option::Option;
@@ -14,6 +17,13 @@ use core::u64;
 use alloc::format;
 use crate::alloc::string::ToString;
 use crate::println;
+use crate::rtc;
+use crate::users;
+use crate::vfs::{FileSystem, FileType, FsError, Permissions, Stat, TrashEntry};
+use ustar_format::{blocks_for_size, fix_checksum, is_header, long_name_headers, parse_octal, tombstone_block, trash_headers,
+    Entry, Header, Reader};
+
+pub use ustar_format::FsckProblem;
 
 // Note to me tomorrow - we're going to use Arc<Mutex<File>> and Directory because
 // It gives me interior mutability (Mutex), and shared ownership (Arc). This is important
@@ -49,6 +59,27 @@ trait USTARItem {
 
     // Gets the size - important for writing 
     fn get_size(&self) -> u64;
+
+    // Long names need extra headers in front of the normal one - this is how many blocks of them the item has room for on disk
+    // (block_id is the first one of them), and how many its current name needs
+    fn get_extra_blocks(&self) -> u64;
+    fn set_extra_blocks(&mut self, blocks: u64);
+    fn needed_extra_blocks(&self) -> u64;
+
+    // How many blocks this takes up on disk - long name headers, the header and the data
+    fn get_block_count(&self) -> u64 {
+        self.get_extra_blocks() + 1 + blocks_for_size(self.get_size())
+    }
+
+    // Who owns it and who can do what with it
+    fn get_permissions(&self) -> Permissions;
+    fn set_permissions(&mut self, permissions: Permissions);
+
+    // Can the current user do access (users::READ, WRITE and/or EXECUTE) to it
+    fn can(&self, access: u64) -> bool {
+        let permissions = self.get_permissions();
+        users::can_access(permissions.mode, permissions.owner_id, permissions.group_id, access)
+    }
 }
 
 pub struct Directory {
@@ -64,16 +95,19 @@ pub struct Directory {
     block_id: u64,
     // Should we write this entire file to disk?
     write: bool,
+    // Blocks of long name headers in front of the real header
+    extra_blocks: u64,
+    // Did the long names come from GNU headers? New stuff gets PAX ones
+    gnu_long_names: bool,
 
     // Stuff needed by the USTAR filesystem
     // https://wiki.osdev.org/USTAR
     name: String,
-    mode: String,
+    mode: u64, // Just the permission bits - rwxrwxrwx
     owner_id: u64, 
     group_id: u64,
     size: u64, // Should always be 0
     time: String,
-    checksum: u64, // 256 + the sum of all the bytes in this header except the checksum field.
     type_flag: u8, // Should always be 5
     linked_name: String,
     owner_name: String, 
@@ -87,40 +121,24 @@ impl Directory {
     // For when you're making a new directory going in disk
     // Where does it go, and what is it's name (must include parent folders too)
     fn new(block_id: u64, name: String) -> Directory {
-        // Handle name
-        let name_variable = name.into_bytes();
-        let mut name = String::with_capacity(100);
-        for i in name_variable {
-            name.push(i as char);            
-        }
-        // Mode
-        let mode = String::from("100777");
-        // User and group ID
-        let owner_id = 420;
-        let group_id = 420;
+        // Mode - rwxr-xr-x
+        let mode = 0o755;
+        // User and group ID - whoever is making it
+        let user = users::current_user();
+        let owner_id = user.uid;
+        let group_id = user.gid;
         // Size
         let size =0;
-        let mut time = String::with_capacity(11);
-        // Skip over the null byte - storing this as a string because we don't care how it works
-        for _ in 136..147 {
-            time.push('0');      
-        }
-        // Header checksum
-        let header = 0;
+        // Made right now
+        let time = current_time();
         // You get to be headerless for a bit - until someone tries to write
         // Type (should always be 5)
         let type_flag = 5;
         // Linked file name - same name as the normal
         let linked_name = name.clone();
         // Owner and group name
-        let mut owner_name = String::with_capacity(32);
-        owner_name.push_str("weed");
-        let mut group_name = String::with_capacity(32);
-        group_name.push_str("weed");
-        for _ in 0..28 {
-            owner_name.push('\0');
-            group_name.push('\0');
-        }
+        let owner_name = user.name.to_string();
+        let group_name = users::group_name(user.gid);
         // Device major and minor version - not parsing because it probably doesn't matter
         let device_major_number = 0;
         let device_minor_number = 0;
@@ -139,7 +157,6 @@ impl Directory {
             group_id,
             size,
             time,
-            checksum: header,
             type_flag,
             linked_name,
             owner_name,
@@ -150,6 +167,8 @@ impl Directory {
 
             block_id,
             write: false,
+            extra_blocks: 0,
+            gnu_long_names: false,
 
             subdirectories,
             contents: files,
@@ -160,29 +179,22 @@ impl Directory {
 
     // For when you need a new directory not backed by disk.
     fn new_directory(name: String) -> Directory {
-        // Mode
-        let mode = String::from("100777");
+        // Mode - nobody but root can change these, since they don't have a header to keep it in
+        let mode = 0o755;
         // User and group ID
-        let owner_id = 420;
-        let group_id = 420;
+        let owner_id = 0;
+        let group_id = 0;
         // Size
         let size = 0;
         // Time
         let time = String::from("");
-        let header = 0;
         // Type (should always be 5)
         let type_flag = 5;
         // Linked file name - same name as the normal
         let linked_name = name.clone();
         // Owner and group name
-        let mut owner_name = String::with_capacity(32);
-        owner_name.push_str("weed");
-        let mut group_name = String::with_capacity(32);
-        group_name.push_str("weed");
-        for _ in 0..28 {
-            owner_name.push('\0');
-            group_name.push('\0');
-        }
+        let owner_name = users::user_name(0);
+        let group_name = users::group_name(0);
         // Device major and minor version - not parsing because it probably doesn't matter
         let device_major_number = 0;
         let device_minor_number = 0;
@@ -204,7 +216,6 @@ impl Directory {
             group_id,
             size,
             time,
-            checksum: header,
             type_flag,
             linked_name,
             owner_name,
@@ -215,6 +226,8 @@ impl Directory {
 
             block_id,
             write: false,
+            extra_blocks: 0,
+            gnu_long_names: false,
 
             subdirectories,
             contents: files,
@@ -223,320 +236,60 @@ impl Directory {
     }
 
     // Only should be used for initialization from the disk
-    pub fn from_block(block: Vec<u8>, block_id: u64) -> Directory {
-        // Handle name
-        let mut name = String::with_capacity(100);
-        for i in 0..100 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            if chr != '\0' {
-                name.push(chr);
-            }
-        }
-        // Mode
-        let mode = String::from("100777");
-        // User and group ID
-        let owner_id = 420;
-        let group_id = 420;
-        // Size
-        let mut size = String::with_capacity(10);
-        // Skip over the null and 0 byte
-        for i in 125..135 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            size.push(chr);      
-        }
-        let size = u64::from_str_radix(size.as_str(), 8);
-        let size = match size {
-            Ok(i) => i,
-            Err(_) => 0,
-        };
-        // Time
-        let mut time = String::with_capacity(11);
-        // Skip over the null byte - storing this as a string because we don't care how it works
-        for i in 136..147 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            time.push(chr);      
-        }
-        // Header checksum
-        let mut header = 0;
-        // 6223-48-49-48-52-48-53-32+32+32+32+32+32+32+32+32 (example for the hello world file, convert it to octal)
-        for (i, n) in block.iter().enumerate() {
-            if i > 147 && i < 155 {
-                header += 32;
-            }
-            else {
-                header += *n as u64;                
-            }
-        }
-        // Type (should always be 5)
-        let type_flag = 5;
-        // Linked file name - same name as the normal
-        let linked_name = name.clone();
-        // Owner and group name
-        let mut owner_name = String::with_capacity(32);
-        owner_name.push_str("weed");
-        let mut group_name = String::with_capacity(32);
-        group_name.push_str("weed");
-        for _ in 0..28 {
-            owner_name.push('\0');
-            group_name.push('\0');
-        }
-        // Device major and minor version - not parsing because it probably doesn't matter
-        let device_major_number = 0;
-        let device_minor_number = 0;
-        // Filename prefix
-        let mut filename_prefix = String::with_capacity(155);
-        for i in 345..500 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            filename_prefix.push(chr);
+    pub fn from_block(block: Vec<u8>, block_id: u64) -> Result<Directory, FsError> {
+        match Header::parse(&block) {
+            Some(header) => Ok(Directory::from_header(header, block_id)),
+            None => Err(FsError::Corrupted),
         }
-        // Setup directory specific Variables
-        let subdirectories = Vec::new();
-        let files = Vec::new();
-        Directory { 
-            name,
-            mode,
-            owner_id,
-            group_id,
-            size,
-            time,
-            checksum: header,
-            type_flag,
-            linked_name,
-            owner_name,
-            group_name,
-            device_minor_number,
-            device_major_number,
-            prefix: filename_prefix,
+    }
 
-            block_id,
-            write: false,
+    pub fn from_header(header: Header, block_id: u64) -> Directory {
+        let mut folder = Directory::new_directory(String::new());
+        folder.set_header(header, block_id);
+        folder
+    }
 
-            subdirectories,
-            contents: files,
-            parent: Weak::new(),
-        }
+    // Takes over the header of folder - for when a directory that was only implied by a path shows up for real
+    fn take_header_from(&mut self, folder: &mut Directory) {
+        self.set_header(folder.header(), folder.block_id);
+        self.extra_blocks = folder.extra_blocks;
+        self.gnu_long_names = folder.gnu_long_names;
     }
 
-    pub fn reinit_from_block(&mut self, block: Vec<u8>, block_id: u64) {
-        // Handle name
-        let mut name = String::with_capacity(100);
-        for i in 0..100 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            if chr != '\0' {
-                name.push(chr);
-            }
-        }
-        // Mode
-        let mode = String::from("100777");
-        // User and group ID
-        let owner_id = 420;
-        let group_id = 420;
-        // Size
-        let mut size = String::with_capacity(10);
-        // Skip over the null and 0 byte
-        for i in 125..135 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            size.push(chr);      
-        }
-        let size = u64::from_str_radix(size.as_str(), 8);
-        let size = match size {
-            Ok(i) => i,
-            Err(_) => 0,
-        };
-        // Time
-        let mut time = String::with_capacity(11);
-        // Skip over the null byte - storing this as a string because we don't care how it works
-        for i in 136..147 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            time.push(chr);      
-        }
-        // Header checksum
-        let mut header = 0;
-        // 6223-48-49-48-52-48-53-32+32+32+32+32+32+32+32+32 (example for the hello world file, convert it to octal)
-        for (i, n) in block.iter().enumerate() {
-            if i > 147 && i < 155 {
-                header += 32;
-            }
-            else {
-                header += *n as u64;                
-            }
-        }
-        // Type (should always be 5)
-        let type_flag = 5;
+    // Everything but the directory specific stuff comes from header
+    pub fn set_header(&mut self, header: Header, block_id: u64) {
+        self.name = header.name;
+        self.mode = header.mode;
+        self.owner_id = header.owner_id;
+        self.group_id = header.group_id;
+        self.size = header.size;
+        self.time = format!("{:011o}", header.mtime);
         // Linked file name - same name as the normal
-        let linked_name = name.clone();
-        // Owner and group name
-        let mut owner_name = String::with_capacity(32);
-        owner_name.push_str("weed");
-        let mut group_name = String::with_capacity(32);
-        group_name.push_str("weed");
-        for _ in 0..28 {
-            owner_name.push('\0');
-            group_name.push('\0');
-        }
-        // Device major and minor version - not parsing because it probably doesn't matter
-        let device_major_number = 0;
-        let device_minor_number = 0;
-        // Filename prefix
-        let mut filename_prefix = String::with_capacity(155);
-        for i in 345..500 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            filename_prefix.push(chr);
-        }
-        // Setup directory specific Variables
-        self.name = name;
-        self.mode = mode;
-        self.owner_id = owner_id;
-        self.group_id = group_id;
-        self.time = time;
-        self.size = size;
-        self.checksum = header;
-        self.type_flag = type_flag;
-        self.linked_name = linked_name;
-        self.owner_name = owner_name;
-        self.group_name = group_name;
-        self.device_minor_number = device_minor_number;
-        self.device_major_number = device_major_number;
-        self.prefix = filename_prefix;
+        self.linked_name = self.name.clone();
+        self.owner_name = header.owner_name;
+        self.group_name = header.group_name;
         self.block_id = block_id;
         self.write = false;
     }
 
-    #[allow(clippy::all)]
-    pub fn to_block(&mut self) -> Vec<u8> {
-        let mut block = Vec::with_capacity(512);
-
-        // Filename
-        block.extend(unsafe { self.name.as_mut_vec().iter().cloned() } );
-        // Made it so that white space is not part of the representation of a file
-        while block.len() < 100 {
-            block.push(0);
-        }
-
-        // Mode - 0000777\0
-        let mut mode = vec![48u8, 48u8, 48u8, 48u8, 55u8, 55u8, 55u8, 0u8];
-        block.append(&mut mode);
-
-        // Owner and group ID - 0000420\0
-        let mut id = vec![48u8, 48u8, 48u8, 48u8, 52u8, 50u8, 48u8, 0u8];
-        block.append(&mut id);
-        let mut id = vec![48u8, 48u8, 48u8, 48u8, 52u8, 50u8, 48u8, 0u8];
-        block.append(&mut id);
-
-        // Size (octal numbers)
-        block.push(48);
-        let size = format!("{:o}", self.size);
-        let mut size = size.into_bytes();
-        size.reverse(); 
-        for i in (0..10).rev() {
-            let chr = match size.get(i) {
-                Some(chr) => *chr,
-                None => 48,
-            };
-            block.push(chr);
-        }
-        block.push(0);
-
-        // Time (string)
-        block.extend(unsafe { self.time.as_mut_vec().iter().cloned() } );
-        block.push(0);
-
-
-        // Checksum (octal numbers)
-        let checksum = format!("{:o}", self.checksum);
-        let mut checksum = checksum.into_bytes();
-        checksum.reverse(); 
-        for i in (0..6).rev() {
-            let chr = match checksum.get(i) {
-                Some(chr) => *chr,
-                None => 48,
-            };
-            block.push(chr);
-        }
-        block.push(0);
-        block.push(32);
-
-
-        // Type - 0 for file, 5 for folder
-        block.push(b'5'); 
-
-        // Linked name - we don't support links, so don't care about this - supposed to be 0
-        for _ in 0..100 {
-            block.push(0);
-        }
-        // Ustar indicators
-        let mut ustar = vec![b'u', b's', b't', b'a', b'r', 0u8];
-        block.append(&mut ustar);
-        // 00
-        block.push(48);
-        block.push(48);
-
-        // user name
-        block.extend(unsafe { self.owner_name.as_mut_vec().iter().cloned() } );
-        // Group name
-        block.extend(unsafe { self.group_name.as_mut_vec().iter().cloned() } );
-
-        // Device major and minor number - 0000000\0
-        let mut num = vec![48u8, 48u8, 48u8, 48u8, 48u8, 48u8, 48u8, 0u8];
-        block.append(&mut num);
-        let mut num = vec![48u8, 48u8, 48u8, 48u8, 48u8, 48u8, 48u8, 0u8];
-        block.append(&mut num);
-
-        block.extend(unsafe { self.prefix.as_mut_vec().iter().cloned() } );
-
-        for _ in 0..12 {
-            block.push(0);
-        }
-
-        // Regenerate the checksum - otherwise archivemount sees it as corrupted
-        let mut header = 0;
-        for (i, n) in block.iter().enumerate() {
-            if i > 147 && i < 155 {
-                header += 32;
-            }
-            else {
-                header += *n as u64;                
-            }
-        }
-
-        self.checksum = header; 
-        let checksum = format!("{:o}", header);
-        
-        let mut checksum = checksum.into_bytes();
-        checksum.reverse(); 
-        for i in (0..6).rev() {
-            let chr = match checksum.get(i) {
-                Some(chr) => *chr,
-                None => 48,
-            };
-            block[(6 - i) + 147] =  chr;
+    // What goes on the disk for it
+    fn header(&self) -> Header {
+        Header {
+            name: self.name.to_string(),
+            mode: self.mode,
+            owner_id: self.owner_id,
+            group_id: self.group_id,
+            size: self.size,
+            mtime: parse_octal(self.time.as_bytes()),
+            type_flag: b'5',
+            linked_name: String::new(),
+            owner_name: self.owner_name.to_string(),
+            group_name: self.group_name.to_string(),
         }
+    }
 
-        block
+    pub fn to_block(&self) -> Vec<u8> {
+        self.header().to_block()
     }
 
 
@@ -601,7 +354,9 @@ impl USTARItem for Directory {
 
 
     fn get_writable_representation(&mut self) -> Vec<u8> {
-        self.to_block()
+        let mut res = long_name_headers(&self.name, "", self.gnu_long_names, self.extra_blocks);
+        res.extend(self.to_block());
+        res
     }
 
     fn get_block_id(&self) -> u64 {
@@ -615,29 +370,64 @@ impl USTARItem for Directory {
     fn get_size(&self) -> u64 {
         self.size
     }
+
+    fn get_extra_blocks(&self) -> u64 {
+        self.extra_blocks
+    }
+
+    fn set_extra_blocks(&mut self, blocks: u64) {
+        self.extra_blocks = blocks;
+    }
+
+    fn get_permissions(&self) -> Permissions {
+        Permissions {
+            mode: self.mode,
+            owner_id: self.owner_id,
+            group_id: self.group_id,
+            owner_name: self.owner_name.to_string(),
+            group_name: self.group_name.to_string(),
+        }
+    }
+
+    fn set_permissions(&mut self, permissions: Permissions) {
+        self.mode = permissions.mode;
+        self.owner_id = permissions.owner_id;
+        self.group_id = permissions.group_id;
+        self.owner_name = permissions.owner_name;
+        self.group_name = permissions.group_name;
+    }
+
+    fn needed_extra_blocks(&self) -> u64 {
+        long_name_headers(&self.name, "", self.gnu_long_names, 0).len() as u64 / 512
+    }
 }
 
 
 pub struct File {
     data: Vec<u8>,
+    // Is data actually in memory? Files are only read off the disk when someone needs them
+    loaded: bool,
 
     //Additional needed stuff not from the USTAR filesystem.
     // What block is this hosted on? (we need this for writing to disk)
     block_id: u64,
     // Should we write this entire file to disk?
     write: bool,
+    // Blocks of long name headers in front of the real header
+    extra_blocks: u64,
+    // Did the long names come from GNU headers? New stuff gets PAX ones
+    gnu_long_names: bool,
 
     // Stuff needed by the USTAR filesystem
     // https://wiki.osdev.org/USTAR
     pub name: String,
-    mode: String,
+    mode: u64, // Just the permission bits - rwxrwxrwx
     owner_id: u64, 
     group_id: u64,
     size: u64,
     time: String,
-    checksum: u64, // 256 + the sum of all the bytes in this header except the checksum field.
-    type_flag: u8, // Should always be 0
-    linked_name: String,
+    type_flag: u8, // 0 for a normal file, 1 for a hard link, 2 for a symlink
+    linked_name: String, // What a link points at
     owner_name: String, 
     group_name: String,
     device_major_number: u64,
@@ -649,40 +439,24 @@ impl File {
     // For when you're making a new file going in disk
     // Where does it go, and what is it's name (must include parent folders too)
     fn new(block_id: u64, name: String) -> File {
-        // Handle name
-        let name_variable = name.into_bytes();
-        let mut name = String::with_capacity(100);
-        for i in name_variable {
-            name.push(i as char);
-        }
-        // Mode
-        let mode = String::from("100777");
-        // User and group ID
-        let owner_id = 420;
-        let group_id = 420;
+        // Mode - rw-r--r--
+        let mode = 0o644;
+        // User and group ID - whoever is making it
+        let user = users::current_user();
+        let owner_id = user.uid;
+        let group_id = user.gid;
         // Size
         let size =0;
-        let mut time = String::with_capacity(11);
-        // Skip over the null byte - storing this as a string because we don't care how it works
-        for _ in 136..147 {
-            time.push('0');      
-        }
-        // Header checksum
-        let header = 0;
+        // Made right now
+        let time = current_time();
         // You get to be headerless for a bit - until someone tries to write
-        // Type (should always be 5)
-        let type_flag = 5;
-        // Linked file name - same name as the normal
-        let linked_name = name.clone();
+        // Type - a normal file unless someone turns it into a link
+        let type_flag = 0;
+        // Linked file name - only links use this
+        let linked_name = String::new();
         // Owner and group name
-        let mut owner_name = String::with_capacity(32);
-        owner_name.push_str("weed");
-        let mut group_name = String::with_capacity(32);
-        group_name.push_str("weed");
-        for _ in 0..28 {
-            owner_name.push('\0');
-            group_name.push('\0');
-        }
+        let owner_name = user.name.to_string();
+        let group_name = users::group_name(user.gid);
         // Device major and minor version - not parsing because it probably doesn't matter
         let device_major_number = 0;
         let device_minor_number = 0;
@@ -694,13 +468,13 @@ impl File {
         // Setup directory specific Variables
         let data = Vec::new();
         File { 
+            loaded: true,
             name,
             mode,
             owner_id,
             group_id,
             size,
             time,
-            checksum: header,
             type_flag,
             linked_name,
             owner_name,
@@ -711,6 +485,8 @@ impl File {
 
             block_id,
             write: false,
+            extra_blocks: 0,
+            gnu_long_names: false,
 
             data,
         }
@@ -718,219 +494,53 @@ impl File {
 
 
 
-    pub fn from_block(block: Vec<u8>, block_id: u64) -> File {
-        // Handle name
-        let mut name = String::with_capacity(100);
-        for i in 0..100 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            if chr != '\0' {
-                name.push(chr);
-            }
-        }
-        // Mode
-        let mode = String::from("100777");
-        // User and group ID
-        let owner_id = 420;
-        let group_id = 420;
-        // Size
-        let mut size = String::with_capacity(10);
-        // Skip over the null and 0 byte
-        for i in 125..135 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            size.push(chr);      
-        }
-        let size = u64::from_str_radix(size.as_str(), 8);
-        let size = match size {
-            Ok(i) => i,
-            Err(_) => 0,
-        };
-        // Time
-        let mut time = String::with_capacity(11);
-        // Skip over the null byte - storing this as a string because we don't care how it works
-        for i in 136..147 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            time.push(chr);      
-        }
-        // Header checksum
-        let mut header = 0;
-        // 6223-48-49-48-52-48-53-32+32+32+32+32+32+32+32+32 (example for the hello world file, convert it to octal)
-        for (i, n) in block.iter().enumerate() {
-            if i > 147 && i < 155 {
-                header += 32;
-            }
-            else {
-                header += *n as u64;                
-            }
-        }
-        // Type (should always be 0)
-        let type_flag = 0;
-        // Linked file name - same name as the normal
-        let linked_name = name.clone();
-        // Owner and group name
-        let mut owner_name = String::with_capacity(32);
-        owner_name.push_str("weed");
-        let mut group_name = String::with_capacity(32);
-        group_name.push_str("weed");
-        for _ in 0..28 {
-            owner_name.push('\0');
-            group_name.push('\0');
-        }
-        // Device major and minor version - not parsing because it probably doesn't matter
-        let device_major_number = 0;
-        let device_minor_number = 0;
-        // Filename prefix
-        let mut filename_prefix = String::with_capacity(155);
-        for i in 345..500 {
-            let chr = match block.get(i) {
-                Some(chr) => *chr as char,
-                None => '\0',
-            };
-            filename_prefix.push(chr);
-        }
-        // Setup empty data
-        let data = Vec::new();
-        File {
-            name,
-            mode,
-            owner_id,
-            group_id,
-            size,
-            time,
-            checksum: header,
-            type_flag,
-            linked_name,
-            owner_name,
-            group_name,
-            device_minor_number,
-            device_major_number,
-            prefix: filename_prefix,
-
-            block_id,
-            write: false,
-
-            data,
+    pub fn from_block(block: Vec<u8>, block_id: u64) -> Result<File, FsError> {
+        match Header::parse(&block) {
+            Some(header) => Ok(File::from_header(header, block_id)),
+            None => Err(FsError::Corrupted),
         }
     }
 
-    #[allow(clippy::all)]
-    pub fn to_block(&mut self) -> Vec<u8> {
-        let mut block = Vec::with_capacity(512);
-
-        // Filename
-        block.extend(unsafe { self.name.as_mut_vec().iter().cloned() } );
-        // Made it so that white space is not part of the representation of a file
-        while block.len() < 100 {
-            block.push(0);
-        }
-
-        // Mode - 0000777\0
-        let mut mode = vec![48u8, 48u8, 48u8, 48u8, 55u8, 55u8, 55u8, 0u8];
-        block.append(&mut mode);
-
-        // Owner and group ID - 0000420\0
-        let mut id = vec![48u8, 48u8, 48u8, 48u8, 52u8, 50u8, 48u8, 0u8];
-        block.append(&mut id);
-        let mut id = vec![48u8, 48u8, 48u8, 48u8, 52u8, 50u8, 48u8, 0u8];
-        block.append(&mut id);
-
-        // Size (octal numbers)
-        block.push(48);
-        let size = format!("{:o}", self.size);
-        let mut size = size.into_bytes();
-        size.reverse(); 
-        for i in (0..10).rev() {
-            let chr = match size.get(i) {
-                Some(chr) => *chr,
-                None => 48,
-            };
-            block.push(chr);
-        }
-        block.push(0);
-
-        // Time (string)
-        block.extend(unsafe { self.time.as_mut_vec().iter().cloned() } );
-        block.push(0);
-
-
-        // Checksum (octal numbers)
-        let checksum = format!("{:o}", self.checksum);
-        let mut checksum = checksum.into_bytes();
-        checksum.reverse(); 
-        for i in (0..6).rev() {
-            let chr = match checksum.get(i) {
-                Some(chr) => *chr,
-                None => 48,
-            };
-            block.push(chr);
-        }
-        block.push(0);
-        block.push(32);
-
-
-        // Type - 0 for file, 5 for folder
-        block.push(b'0'); 
-
-        // Linked name - we don't support links, so don't care about this - supposed to be 0
-        for _ in 0..100 {
-            block.push(0);
-        }
-        // Ustar indicators
-        let mut ustar = vec![b'u', b's', b't', b'a', b'r', 0u8];
-        block.append(&mut ustar);
-        // 00
-        block.push(48);
-        block.push(48);
-
-        // user name
-        block.extend(unsafe { self.owner_name.as_mut_vec().iter().cloned() } );
-        // Group name
-        block.extend(unsafe { self.group_name.as_mut_vec().iter().cloned() } );
-
-        // Device major and minor number - 0000000\0
-        let mut num = vec![48u8, 48u8, 48u8, 48u8, 48u8, 48u8, 48u8, 0u8];
-        block.append(&mut num);
-        let mut num = vec![48u8, 48u8, 48u8, 48u8, 48u8, 48u8, 48u8, 0u8];
-        block.append(&mut num);
-
-        block.extend(unsafe { self.prefix.as_mut_vec().iter().cloned() } );
-
-        for _ in 0..12 {
-            block.push(0);
-        }
-        // Regenerate the checksum - otherwise archivemount sees it as corrupted
-        let mut header = 0;
-        for (i, n) in block.iter().enumerate() {
-            if i > 147 && i < 155 {
-                header += 32;
-            }
-            else {
-                header += *n as u64;                
-            }
-        }
-
-        self.checksum = header; 
-        let checksum = format!("{:o}", header);
-        
-        let mut checksum = checksum.into_bytes();
-        checksum.reverse(); 
-        for i in (0..6).rev() {
-            let chr = match checksum.get(i) {
-                Some(chr) => *chr,
-                None => 48,
-            };
-            block[(6 - i) + 147] =  chr;
-        }
-
-        block
+    // The data isn't read in here - that happens when it's needed
+    pub fn from_header(header: Header, block_id: u64) -> File {
+        // Type - 0 for a normal file, 1 for a hard link, 2 for a symlink
+        let type_flag = match header.type_flag {
+            b'1' => 1,
+            b'2' => 2,
+            _ => 0,
+        };
+        let mut file = File::new(block_id, header.name);
+        file.loaded = false;
+        file.mode = header.mode;
+        file.owner_id = header.owner_id;
+        file.group_id = header.group_id;
+        file.size = header.size;
+        file.time = format!("{:011o}", header.mtime);
+        file.type_flag = type_flag;
+        file.linked_name = header.linked_name;
+        file.owner_name = header.owner_name;
+        file.group_name = header.group_name;
+        file
+    }
+
+    // What goes on the disk for it - the linked name is supposed to be empty for everything that isn't a link
+    fn header(&self) -> Header {
+        Header {
+            name: self.name.to_string(),
+            mode: self.mode,
+            owner_id: self.owner_id,
+            group_id: self.group_id,
+            size: self.size,
+            mtime: self.get_mtime(),
+            type_flag: b'0' + self.type_flag,
+            linked_name: if self.is_link() { self.linked_name.to_string() } else { String::new() },
+            owner_name: self.owner_name.to_string(),
+            group_name: self.group_name.to_string(),
+        }
+    }
+
+    pub fn to_block(&self) -> Vec<u8> {
+        self.header().to_block()
     }
 
     fn get_data(&self) -> Vec<u8> {
@@ -940,6 +550,13 @@ impl File {
     fn set_data(&mut self, data: Vec<u8>) {
         self.data = data.clone();
         self.size = data.len() as u64;
+        self.loaded = true;
+    }
+
+    // Throws away the data in memory - it can be read back off the disk later
+    fn unload_data(&mut self) {
+        self.data = Vec::new();
+        self.loaded = false;
     }
 
     fn get_short_name(&self) -> String {
@@ -950,6 +567,20 @@ impl File {
         }
         result.to_string()
     }
+
+    fn is_link(&self) -> bool {
+        self.type_flag == 1 || self.type_flag == 2
+    }
+
+    // Seconds since 1970 when this was last changed
+    fn get_mtime(&self) -> u64 {
+        parse_octal(self.time.as_bytes())
+    }
+
+    // Marks it as changed right now
+    fn touch(&mut self) {
+        self.time = current_time();
+    }
 }
 
 impl USTARItem for File {
@@ -996,18 +627,16 @@ impl USTARItem for File {
     }
 
     fn get_writable_representation(&mut self) -> Vec<u8> {
-        let mut size = self.size + 512;
-        if size % 512 == 0 {
-            size /= 512; 
-        }
-        else {
-            size = (size - size % 512) / 512 + 1; 
+        let linked_name = if self.is_link() { self.linked_name.as_str() } else { "" };
+        let mut res = long_name_headers(&self.name, linked_name, self.gnu_long_names, self.extra_blocks);
+        // If the data isn't in memory it's still sitting on disk where it belongs, so only the header needs writing
+        if !self.loaded {
+            res.extend(self.to_block());
+            return res;
         }
-        size *= 512;
-        let mut res = Vec::with_capacity(size as usize);
         res.extend(self.to_block());
         res.extend(self.data.clone());
-        while res.capacity() != res.len() {
+        while res.len() % 512 != 0 {
             res.push(0);
         }
         res
@@ -1024,125 +653,340 @@ impl USTARItem for File {
     fn get_size(&self) -> u64 {
         self.size
     }
+
+    fn get_extra_blocks(&self) -> u64 {
+        self.extra_blocks
+    }
+
+    fn set_extra_blocks(&mut self, blocks: u64) {
+        self.extra_blocks = blocks;
+    }
+
+    fn get_permissions(&self) -> Permissions {
+        Permissions {
+            mode: self.mode,
+            owner_id: self.owner_id,
+            group_id: self.group_id,
+            owner_name: self.owner_name.to_string(),
+            group_name: self.group_name.to_string(),
+        }
+    }
+
+    fn set_permissions(&mut self, permissions: Permissions) {
+        self.mode = permissions.mode;
+        self.owner_id = permissions.owner_id;
+        self.group_id = permissions.group_id;
+        self.owner_name = permissions.owner_name;
+        self.group_name = permissions.group_name;
+    }
+
+    fn needed_extra_blocks(&self) -> u64 {
+        let linked_name = if self.is_link() { self.linked_name.as_str() } else { "" };
+        long_name_headers(&self.name, linked_name, self.gnu_long_names, 0).len() as u64 / 512
+    }
 }
 
-pub struct USTARFileSystem {
-    block_driver: AtaPio,
+// The disk is a partition unless something else is asked for, like a RAM disk in the tests
+pub struct USTARFileSystem<D: BlockDevice = BlockCache<Partition>> {
+    // The disk, or the partition on it, that the archive is on
+    block_driver: D,
     files: Vec<Arc<Mutex<dyn USTARItem + Send + Sync>>>,
     current_dirs: HashMap<u64, Arc<Mutex<Directory>>>,
     current_dirs_tracker: u64,
     root: Arc<Mutex<Directory>>,
     block_used_ptr: u64,
+    // Deleted space that can be handed out again - (first block, number of blocks), sorted and merged
+    // Everything at or past block_used_ptr is free too, but that isn't tracked here
+    free_extents: Vec<(u64, u64)>,
+    // Files that have their data in memory, least recently used first
+    loaded_files: Vec<Arc<Mutex<File>>>,
+    // How many bytes of file data are in memory
+    cached_bytes: u64,
+    // Headers and anything else that has to change together go through here, so a crash can't leave the archive half written
+    // It lives at the very end of the disk, and allocate keeps the archive from growing into it
+    journal: Journal,
+    // Where the end of the archive is on disk, as of the last commit
+    disk_end: u64,
+    // Space from deleted items - it can't be handed out until the tombstones for it are on disk
+    pending_free: Vec<(u64, u64)>,
+    // Things that were removed but are still on disk, oldest first. Their space stays used until the trash gets emptied, 
+    // or until nothing else fits
+    trash: Vec<Trashed>,
 }
 
-impl USTARFileSystem {
-    fn new() -> USTARFileSystem {
-        let driver = AtaPio::try_new();
-        let files = Vec::new();
-        let current_dirs = HashMap::new();
-        let root = Arc::new(Mutex::new(Directory::new_directory("/".to_string())));
-        root.lock().parent = Arc::downgrade(&root);
+// Something in the trash - the header from the disk, with the path it used to be at as its name, and when it was removed
+enum Trashed {
+    File(File, u64),
+    Directory(Directory, u64),
+}
 
-        USTARFileSystem {
-            block_driver: driver,
-            files,
-            current_dirs,
-            current_dirs_tracker: 1,
-            root: Arc::new(Mutex::new(Directory::new_directory("/".to_string()))),
-            block_used_ptr: 0,
+impl Trashed {
+    fn item(&self) -> &dyn USTARItem {
+        match self {
+            Trashed::File(file, _) => file,
+            Trashed::Directory(folder, _) => folder,
         }
     }
 
-    
-    pub fn init(&mut self) {
-        unsafe {
-            // Read in all the files/directories
-            // First mainly process directories to build the structure of the VFS, then place files in it
-            // Initialize (read the data) for the files in the 2nd pass
-            // Well actually, do this differently
-            // Process any directories that are relative to root 
-            // Basically keep on going through, check if a path can be accessed (and thus subdirectories can be added)
-            // Can fill the files array early - probably on first run
-            // Actually another thing we need to keep track of 
-            // There might be a world where we can create directories not backed by disk - this makes sense - fill in the 
-            // Disk info as we get it - just add a method to the directory that lets us mutate it based on the entry if found
-            // End planning comment block
-
-            // Main file acquiescence loop
-            let mut end = false;
-            let mut counter: u32 = 0;
-            while !end {
-                let block = self.block_driver.read_lba(counter, 1);
-
-                if self.check_magic_value(&block) {
-                    let type_flag = self.get_typeflag_(&block);
-                    if type_flag == 0 {
-                        // Most of this handles the size of the file
-                        let mut file = File::from_block(block, counter as u64);
-                        counter += 1;
-                        let mut size = file.size;
-
-                        if size % 512 == 0 {
-                            size /= 512; 
-                        }
-                        else {
-                            size = (size - size % 512) / 512 + 1; 
-                        }
-                        let size_orig = size;
-                        // This code let us read more than one block in at a time, but it was causing corruption/errors, which I do not like
-                        /*
-                        let mut blocks_written = 0;
-                        if size % 255 == 0 {
-                            blocks_written = size / 255; 
-                        }
-                        else {
-                            blocks_written = (size - size % 255) / 255 + 1; 
-                        }
-                        */
-                        let mut data = Vec::with_capacity(size_orig as usize);
+    // Without the / on the end of directories
+    fn path(&self) -> String {
+        self.item().get_name().trim_end_matches('/').to_string()
+    }
 
-                        for _ in 0..size {
-                            data.append(&mut self.block_driver.read_lba(counter, 1));
-                            counter += 1;
-                        }
-                        /*
-                        for i in 0..blocks_written {
-                            if size >= 255 {
-                                data.append(&mut self.block_driver.read_lba(counter, 1));
-                                size -= 1;
-                            }
-                            else {
-                                data.append(&mut self.block_driver.read_lba(counter, size as u8));
-                            }
-                        }
-                        counter += size_orig as u32;
-                        */
-                        data.truncate(file.size as usize);
-                        file.set_data(data);
-                        // Should handle things like generating the directory structure and putting it in the block vector
-                        if file.name != "defrag" {
-                            self.place_file_in_vfs(file);
-                        }
+    fn removed(&self) -> u64 {
+        match self {
+            Trashed::File(_, removed) | Trashed::Directory(_, removed) => *removed,
+        }
+    }
+
+    fn entry(&self) -> TrashEntry {
+        let file_type = match self {
+            Trashed::File(file, _) if file.type_flag == 2 => FileType::Symlink,
+            Trashed::File(..) => FileType::File,
+            Trashed::Directory(..) => FileType::Directory,
+        };
+        TrashEntry {
+            path: self.path(),
+            file_type,
+            size: self.item().get_size(),
+            removed: self.removed(),
+        }
+    }
+}
+
+// How much file data to keep in memory before throwing out files that haven't been used in a while
+const FILE_CACHE_SIZE: u64 = 4 * 1024 * 1024;
+// How much of the heap to leave alone when deciding if there's room for more file data
+const HEAP_RESERVE: u64 = 2 * 1024 * 1024;
+// How many blocks defrag moves at a time
+const COPY_BLOCKS: u64 = 128;
+// How many links to follow before deciding it's a loop
+const MAX_LINK_DEPTH: usize = 16;
+
+// The time field of a header for right now - seconds since 1970 in octal
+fn current_time() -> String {
+    format!("{:011o}", rtc::unix_time())
+}
+
+// What stat and readdir say about a file - hard links look like the file they point at
+fn file_stat(file: &File) -> Stat {
+    let symbolic = file.type_flag == 2;
+    Stat {
+        name: file.get_short_name(),
+        file_type: if symbolic { FileType::Symlink } else { FileType::File },
+        size: file.size,
+        mtime: file.get_mtime(),
+        linked_name: if symbolic { Some(file.linked_name.to_string()) } else { None },
+        permissions: file.get_permissions(),
+    }
+}
+
+fn directory_stat(folder: &Directory) -> Stat {
+    Stat {
+        name: folder.get_short_name().replace("/", ""),
+        file_type: FileType::Directory,
+        size: folder.size,
+        mtime: parse_octal(folder.time.as_bytes()),
+        linked_name: None,
+        permissions: folder.get_permissions(),
+    }
+}
+
+// Has device been made into an archive? Either it already has something in it, or it's empty and mkfs (or imgtool)
+// marked its journal. A blank disk or one with some other file system on it is left alone
+pub fn is_formatted<D: BlockDevice>(device: &mut D) -> Result<bool, FsError> {
+    let first = device.read_block(0)?;
+    if is_header(&first) {
+        return Ok(true);
+    }
+    if first.iter().any(|b| *b != 0) {
+        return Ok(false);
+    }
+    Journal::at_end_of(device.block_count()).is_formatted(device)
+}
+
+impl USTARFileSystem {
+    // The file system on the root device, already read in. There's nothing for it to sit on without a disk, and the
+    // shell can get by without one - the same goes for one that can't be read, since anything written to it could land
+    // on top of what's already there. Either way / goes in memory instead
+    fn new() -> Option<USTARFileSystem> {
+        let device = match partition::root_device() {
+            Ok(device) => device,
+            Err(_) => {
+                println!("No disk was found, so the file system is disabled - / only lasts until the next reboot");
+                return None;
+            },
+        };
+        let mut fs = USTARFileSystem::open(device);
+        if let Err(err) = fs.init() {
+            println!("Couldn't load the file system on {}: {} - / only lasts until the next reboot", fs.device(), err);
+            return None;
+        }
+        Some(fs)
+    }
+
+    // The archive on a disk or partition, with a cache in front of it - nothing gets to the disk until sync
+    pub fn open(device: Partition) -> USTARFileSystem {
+        USTARFileSystem::on(BlockCache::new(device, CACHE_BLOCKS))
+    }
+
+    // The name of the disk or partition the archive is on
+    pub fn device(&self) -> &str {
+        self.block_driver.device().name()
+    }
+
+    // How many blocks the cache holds onto, and how many of those haven't been written yet
+    pub fn cache_usage(&self) -> (usize, usize) {
+        (self.block_driver.capacity(), self.block_driver.dirty_blocks())
+    }
+
+    // Shrinking it writes out whatever doesn't fit anymore
+    pub fn set_cache_capacity(&mut self, blocks: usize) -> Result<(), FsError> {
+        self.block_driver.set_capacity(blocks)
+    }
+
+    // The oldest batch of writes waiting in the cache, and the disk it goes on, for the write-back task
+    pub fn start_write_back(&mut self) -> Option<(Partition, Vec<(u64, Vec<u8>)>)> {
+        let runs = self.block_driver.start_write_back()?;
+        Some((self.block_driver.device().clone(), runs))
+    }
+
+    pub fn finish_write_back(&mut self, result: Result<(), FsError>) -> Result<(), FsError> {
+        self.block_driver.finish_write_back(result)
+    }
+}
+
+impl<D: BlockDevice> USTARFileSystem<D> {
+    // A file system for the archive on device - nothing is read until init
+    pub fn on(device: D) -> USTARFileSystem<D> {
+        let journal = Journal::at_end_of(device.block_count());
+        let files = Vec::new();
+        let current_dirs = HashMap::new();
+        let root = Arc::new(Mutex::new(Directory::new_directory("/".to_string())));
+        root.lock().parent = Arc::downgrade(&root);
+
+        USTARFileSystem {
+            block_driver: device,
+            files,
+            current_dirs,
+            current_dirs_tracker: 1,
+            root: Arc::new(Mutex::new(Directory::new_directory("/".to_string()))),
+            block_used_ptr: 0,
+            free_extents: Vec::new(),
+            loaded_files: Vec::new(),
+            cached_bytes: 0,
+            journal,
+            disk_end: 0,
+            pending_free: Vec::new(),
+            trash: Vec::new(),
+        }
+    }
+
+    // Gives back the disk, for when the file system is done with it
+    pub fn into_device(self) -> D {
+        self.block_driver
+    }
+
+    // Makes the device into an empty archive, throwing away whatever was on it
+    pub fn format(&mut self) -> Result<(), FsError> {
+        if self.block_driver.block_count() <= JOURNAL_BLOCKS + 2 {
+            return Err(FsError::NoSpace);
+        }
+        self.block_driver.write_blocks(0, &[0; 1024])?;
+        self.journal.format(&mut self.block_driver)?;
+        self.block_driver.sync()
+    }
+
+    
+    pub fn init(&mut self) -> Result<(), FsError> {
+        // Read in all the files/directories
+        // First mainly process directories to build the structure of the VFS, then place files in it
+        // Initialize (read the data) for the files in the 2nd pass
+        // Well actually, do this differently
+        // Process any directories that are relative to root 
+        // Basically keep on going through, check if a path can be accessed (and thus subdirectories can be added)
+        // Can fill the files array early - probably on first run
+        // Actually another thing we need to keep track of 
+        // There might be a world where we can create directories not backed by disk - this makes sense - fill in the 
+        // Disk info as we get it - just add a method to the directory that lets us mutate it based on the entry if found
+        // End planning comment block
+
+        // Tar is 512 byte blocks all the way down
+        if self.block_driver.block_size() != 512 {
+            return Err(FsError::Unsupported);
+        }
+
+        // Whatever is on there isn't ours to write over
+        if !is_formatted(&mut self.block_driver)? {
+            return Err(FsError::NotFormatted);
+        }
+
+        // Finish off whatever was being written when the machine went down
+        self.journal.replay(&mut self.block_driver)?;
+
+        // Main file acquiescence loop
+        let mut reader = Reader::new();
+        loop {
+            let entry = match reader.next(|block_id| self.block_driver.read_block(block_id))? {
+                Some(entry) => entry,
+                None => break,
+            };
+            match entry {
+                Entry::Item(item) if item.is_directory() => {
+                    let (trashed, removed) = (item.trashed, item.removed);
+                    let mut folder = Directory::from_header(item.header, item.block_id);
+                    folder.extra_blocks = item.extra_blocks;
+                    folder.gnu_long_names = item.gnu_long_names;
+                    if trashed {
+                        self.trash.push(Trashed::Directory(folder, removed));
                     }
-                    else if type_flag == 5 {
-                        let folder = Directory::from_block(block, counter as u64);
-                        counter += 1;
-                        if folder.name != "defrag" {
-                            self.place_folder_in_vfs(folder);
-                        }
+                    else if folder.name != "defrag" {
+                        self.place_folder_in_vfs(folder);
                     }
                     else {
-                        // Unsupported type - hope that it's only one block
-                        counter += 1;
+                        self.release(folder.block_id, folder.get_extra_blocks() + 1);
                     }
-                }
-                else {
-                    end = true;
-                }
-
+                },
+                // Links are files with no data, that point at something else
+                Entry::Item(item) => {
+                    let (trashed, removed) = (item.trashed, item.removed);
+                    // Only the header gets read here, the data is read in the first time someone wants it
+                    let mut file = File::from_header(item.header, item.block_id);
+                    file.extra_blocks = item.extra_blocks;
+                    file.gnu_long_names = item.gnu_long_names;
+                    // Should handle things like generating the directory structure and putting it in the block vector
+                    if trashed {
+                        self.trash.push(Trashed::File(file, removed));
+                    }
+                    else if file.name != "defrag" {
+                        self.place_file_in_vfs(file);
+                    }
+                    else {
+                        // Deleted files get turned into free space
+                        self.release(file.block_id, file.get_block_count());
+                    }
+                },
+                Entry::Global { block_id, blocks } => {
+                    // Everything in it is already read in, so its space can be used for something else
+                    self.write_tombstone(block_id, blocks);
+                    self.release(block_id, blocks);
+                },
+                Entry::Damaged { block_id, .. } => {
+                    println!("\nThe header at block {} is damaged, skipping it - try fsck", block_id);
+                },
+                // Unsupported type - it's skipped over along with whatever data it has
+                Entry::Unsupported { .. } => {},
+            }
+        }
+        self.block_used_ptr = reader.position();
+        self.disk_end = reader.position();
+        self.trash.sort_by_key(|trashed| trashed.removed());
+        // Deleted stuff right before the end of the archive can just go back to being unused space
+        if let Some(&(start, length)) = self.free_extents.last() {
+            if start + length == self.block_used_ptr {
+                self.free_extents.pop();
+                self.block_used_ptr = start;
             }
-            self.block_used_ptr = counter as u64;
-            // Somehow sort the thing
         }
         for f in self.files.iter() {
             if !self.is_absolute(&f.lock().get_name()) {
@@ -1152,27 +996,7 @@ impl USTARFileSystem {
                 f.lock().should_write();
             }
         }
-        self.write();
-    }
-
-    #[allow(clippy::all)]
-    fn check_magic_value(&self, block: &Vec<u8>) -> bool {
-        let val = [b'u', b's', b't', b'a', b'r', 0, b'0', b'0'];
-        let mut magic = true;
-        for i in 257..265 {
-            if (*block)[i] == val[i - 257] {
-                
-            }
-            else {
-                magic = false;
-            }
-        }
-        magic
-    }
-
-    #[allow(clippy::all)]
-    fn get_typeflag_(&self, block: &Vec<u8>) -> u8 {
-        (*block)[156] - 48
+        self.write()
     }
 
     fn split_path(&self, path: &str) -> Vec<String> {
@@ -1190,11 +1014,13 @@ impl USTARFileSystem {
         result
     }
 
-    fn place_file_in_vfs(&mut self, file: File) {
+    fn place_file_in_vfs(&mut self, file: File) -> Arc<Mutex<File>> {
         let parent_dir = self.generate_path_if_does_not_exist(&(file.name));
         let file = Arc::new(Mutex::new(file));
         parent_dir.lock().contents.push(Arc::clone(&file));
-        self.files.push(file);
+        let result = Arc::clone(&file);
+        self.files.push(result);
+        file
     }
 
     fn place_folder_in_vfs(&mut self, mut folder: Directory) {
@@ -1203,7 +1029,7 @@ impl USTARFileSystem {
        let mut parent = parent_dir.lock();
        for i in parent.subdirectories.iter() {
            if folder.name == i.lock().name {
-               i.lock().reinit_from_block(folder.to_block(), folder.block_id);
+               i.lock().take_header_from(&mut folder);
                let result = Arc::clone(i);
                self.files.push(result);
                return
@@ -1287,42 +1113,97 @@ impl USTARFileSystem {
         }
     }
 
-    pub fn defragment(&mut self) {
+    // Free space gets reused on its own, so this is optional - it just packs everything together at the start of the disk
+    pub fn defragment(&mut self) -> Result<(), FsError> {
         // Remove all files named defrag than move the rest of the files (blockwise), so that it's still valid USTAR
-        self.write();
+        // Everything gets packed over the top of the trash, so it has to be emptied first
+        self.empty_trash()?;
+        // Go through everything in the order it's on disk - that way things only ever move backwards, 
+        // so nothing gets written on top of something that hasn't been moved yet
+        self.files.sort_by_key(|i| i.lock().get_block_id());
         let mut counter = 0;
-        for i in self.files.iter() {
-            let mut item = i.lock();
-            if item.get_name() != "defrag" {
-                item.set_block_id(counter);
-                let mut size = item.get_size();
-                if size % 512 == 0 {
-                    size /= 512; 
+        for index in 0..self.files.len() {
+            let item = Arc::clone(&self.files[index]);
+            let (old_id, size) = {
+                let item = item.lock();
+                (item.get_block_id(), item.get_block_count())
+            };
+            if old_id == counter {
+                counter += size;
+                continue;
+            }
+            // Everything from counter up to the item is one tombstone, so if the item fits in there its data can be copied 
+            // straight across. If it doesn't, the copy would write over the item itself, so it has to go through the journal
+            let overlaps = counter + size > old_id;
+            if overlaps && size as usize + 3 > JOURNAL_ENTRIES {
+                // Too big to move in one go, so it stays where it is
+                counter = old_id + size;
+                continue;
+            }
+            // Good for debugging
+            //println!("Defragging {} with size {}", item.lock().get_name(), size);
+            // Copy the data straight across on disk, so files don't have to be read into memory to move them
+            // It goes a chunk at a time, which is a lot quicker than a block at a time without needing much memory
+            let mut j = 1;
+            while j < size {
+                let count = (size - j).min(COPY_BLOCKS);
+                let mut data = vec![0; (count * 512) as usize];
+                self.block_driver.read_blocks(old_id + j, &mut data)?;
+                if overlaps {
+                    for (k, block) in data.chunks(512).enumerate() {
+                        self.journal.record(counter + j + k as u64, block);
+                    }
                 }
                 else {
-                    size = (size - size % 512) / 512 + 1; 
+                    self.block_driver.write_blocks(counter + j, &data)?;
                 }
-                size += 1;
-                // Good for debugging
-                //println!("Defragging {} with size {}", item.get_name(), size);
-                counter += size;
+                j += count;
+            }
+            {
+                let mut item = item.lock();
+                item.set_block_id(counter);
                 item.should_write();
             }
+            counter += size;
+            // Whatever is left behind becomes free space running up to the next item
+            if index + 1 < self.files.len() {
+                let next = self.files[index + 1].lock().get_block_id();
+                self.write_tombstone(counter, next - counter);
+            }
             else {
-
+                self.block_used_ptr = counter;
+            }
+            // Each item is moved in its own transaction
+            self.write()?;
+        }
+        // Anything that couldn't be moved leaves a gap in front of it
+        self.free_extents.clear();
+        let mut end = 0;
+        for i in self.files.iter() {
+            let item = i.lock();
+            if item.get_block_id() > end {
+                self.free_extents.push((end, item.get_block_id() - end));
             }
+            end = item.get_block_id() + item.get_block_count();
         }
-        self.block_used_ptr = counter;
-        self.write();
+        Ok(())
     }
     
 
-    pub fn write(&mut self) {
+    pub fn write(&mut self) -> Result<(), FsError> {
+        self.make_room_for_long_names()?;
         //Write any changes
         for i in self.files.iter() {
             let mut item = i.lock();
             if item.get_should_write() {
-                let mut data = item.get_writable_representation();
+                // Deleted items only need their header changed - to one that covers all of their space
+                let mut data = if item.get_name() == "defrag" {
+                    tombstone_block(item.get_block_count())
+                }
+                else {
+                    item.get_writable_representation()
+                };
+                let header_blocks = item.get_extra_blocks() as usize + 1;
                 while data.len() % 512 != 0 {
                     data.push(0);
                 }
@@ -1336,28 +1217,236 @@ impl USTARFileSystem {
                 }
                 // Good for debugging
                 //println!("Writing {} at {} with size {}", item.get_name(), id, size);
-                // Each write request/sector
-                for i in 0..size {
-                    let mut data_to_write = Vec::with_capacity(256);
-                    for j in 0..256 {
-                        data_to_write.push(((data[i*512 + j*2 + 1] as u16) << 8) | data[i*512 + j*2] as u16); 
-                    } 
-                    unsafe { self.block_driver.write(id as u32, 1, data_to_write)};
-                    id += 1;
+                // Data goes into space that is free as far as the disk is concerned (or is the same as what's already there), 
+                // so it can be written straight away - except on top of the end of archive blocks, or if it's already in the journal
+                let journaled: Vec<bool> = (0..size).map(|i| {
+                    let id = id + i as u64;
+                    i < header_blocks || (id >= self.disk_end && id < self.disk_end + 2) || self.journal.contains(id)
+                }).collect();
+                let mut i = 0;
+                while i < size {
+                    if journaled[i] {
+                        self.journal.record(id, &data[i*512..(i + 1)*512]);
+                        i += 1;
+                        id += 1;
+                        continue;
+                    }
+                    // Everything up to the next sector that has to go through the journal is written in one go
+                    let mut end = i + 1;
+                    while end < size && !journaled[end] {
+                        end += 1;
+                    }
+                    self.block_driver.write_blocks(id, &data[i*512..end*512])?;
+                    id += (end - i) as u64;
+                    i = end;
                 }
             }
         }
+        // Deleted items have had their tombstones written, and their space is in free_extents, so forget about them
+        self.files.retain(|i| i.lock().get_name() != "defrag");
+        let mut freed = 0;
+        self.loaded_files.retain(|i| {
+            let file = i.lock();
+            if file.name == "defrag" {
+                freed += file.size;
+                false
+            }
+            else {
+                true
+            }
+        });
+        self.cached_bytes -= freed;
+        self.commit()?;
+        // The tombstones are on disk now, so the space can be reused
+        if !self.pending_free.is_empty() {
+            let pending_free = core::mem::replace(&mut self.pending_free, Vec::new());
+            for (block_id, blocks) in pending_free {
+                self.release(block_id, blocks);
+            }
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    // Anything that got renamed to something too long for the headers in front of it has to move somewhere with more room
+    fn make_room_for_long_names(&mut self) -> Result<(), FsError> {
+        for i in self.files.clone().iter() {
+            let (needed, extra_blocks, block_id, blocks) = {
+                let item = i.lock();
+                if item.get_name() == "defrag" || item.get_block_id() == u64::MAX {
+                    continue;
+                }
+                (item.needed_extra_blocks(), item.get_extra_blocks(), item.get_block_id(), item.get_block_count())
+            };
+            if needed <= extra_blocks {
+                continue;
+            }
+            let data_blocks = blocks - extra_blocks - 1;
+            let new_id = self.allocate(needed + 1 + data_blocks)?;
+            // The data gets copied across on disk - a file that has changed in memory gets written over it anyway
+            for j in 0..data_blocks {
+                let block = self.block_driver.read_block(block_id + extra_blocks + 1 + j)?;
+                let target = new_id + needed + 1 + j;
+                if (target >= self.disk_end && target < self.disk_end + 2) || self.journal.contains(target) {
+                    self.journal.record(target, &block);
+                }
+                else {
+                    self.block_driver.write_blocks(target, &block)?;
+                }
+            }
+            self.write_tombstone(block_id, blocks);
+            self.pending_free.push((block_id, blocks));
+            let mut item = i.lock();
+            item.set_block_id(new_id);
+            item.set_extra_blocks(needed);
+            item.should_write();
+        }
+        Ok(())
+    }
+
+    // Writes the end of the archive and everything in the journal
+    fn commit(&mut self) -> Result<(), FsError> {
         // Write two null 
-        unsafe { self.block_driver.write(self.block_used_ptr as u32, 1, Vec::new())};
-        unsafe { self.block_driver.write(self.block_used_ptr as u32 + 1, 1, Vec::new())};
+        self.journal.record(self.block_used_ptr, &[0; 512]);
+        self.journal.record(self.block_used_ptr + 1, &[0; 512]);
+        self.journal.commit(&mut self.block_driver)?;
+        self.disk_end = self.block_used_ptr;
+        Ok(())
+    }
+
+    // Makes sure a file's data is in memory, reading it off the disk if it isn't
+    fn load_file(&mut self, file: &Arc<Mutex<File>>) -> Result<(), FsError> {
+        let (loaded, block_id, size) = {
+            let file = file.lock();
+            // The data starts after the headers
+            (file.loaded, file.block_id + file.extra_blocks, file.size)
+        };
+        if loaded {
+            // Move it to the back of the line for getting thrown out
+            if let Some(index) = self.loaded_files.iter().position(|f| Arc::ptr_eq(f, file)) {
+                let file = self.loaded_files.remove(index);
+                self.loaded_files.push(file);
+            }
+            return Ok(());
+        }
+        self.make_room(size);
+        let blocks = blocks_for_size(size);
+        let mut data = vec![0; (blocks * 512) as usize];
+        // The driver splits it up into whatever the disk can manage
+        self.block_driver.read_blocks(block_id + 1, &mut data)?;
+        data.truncate(size as usize);
+        file.lock().set_data(data);
+        self.track_loaded(file);
+        Ok(())
+    }
+
+    // Keeps track of a file that has its data in memory, so it can be thrown out later
+    fn track_loaded(&mut self, file: &Arc<Mutex<File>>) {
+        self.cached_bytes += file.lock().size;
+        self.loaded_files.push(Arc::clone(file));
+    }
+
+    // Throws out the data of files that haven't been used in a while until there's space for size more bytes
+    // Files that haven't been written yet are skipped, because the disk doesn't have their data
+    fn make_room(&mut self, size: u64) {
+        let mut index = 0;
+        while index < self.loaded_files.len() && (self.cached_bytes + size > FILE_CACHE_SIZE || (crate::allocator::heap_free() as u64) < size + HEAP_RESERVE) {
+            let mut file = self.loaded_files[index].lock();
+            if file.write {
+                drop(file);
+                index += 1;
+                continue;
+            }
+            self.cached_bytes -= file.size;
+            file.unload_data();
+            drop(file);
+            self.loaded_files.remove(index);
+        }
+    }
+
+    // Writes a "defrag" header that covers blocks blocks, so that free space is still valid USTAR
+    fn write_tombstone(&mut self, block_id: u64, blocks: u64) {
+        self.journal.record(block_id, &tombstone_block(blocks));
     }
-    /*
-    // Features to add - 
-    // Copy
-    // Move
-    // Rename
-    // (for files)
 
+    // Finds somewhere to put blocks blocks - reuses deleted space if there's a big enough piece, otherwise
+    // takes it from the end of the archive
+    fn allocate(&mut self, blocks: u64) -> Result<u64, FsError> {
+        for i in 0..self.free_extents.len() {
+            let (start, length) = self.free_extents[i];
+            if length == blocks {
+                self.free_extents.remove(i);
+                return Ok(start);
+            }
+            else if length > blocks {
+                // Whatever is left over needs its own tombstone so the archive can still be read past it
+                self.free_extents[i] = (start + blocks, length - blocks);
+                self.write_tombstone(start + blocks, length - blocks);
+                return Ok(start);
+            }
+        }
+        // The end of the archive still needs its two null blocks after this, and none of it can run into the journal
+        if self.block_used_ptr + blocks + 2 > self.journal.start() {
+            // Out of room - the oldest thing in the trash goes for good, and then it's tried again
+            if self.trash.is_empty() {
+                return Err(FsError::NoSpace);
+            }
+            self.purge_oldest_trash();
+            return self.allocate(blocks);
+        }
+        let start = self.block_used_ptr;
+        self.block_used_ptr += blocks;
+        Ok(start)
+    }
+
+    // Gets rid of the oldest thing in the trash so its space can be used straight away
+    // Its headers get replaced in the journal, so anything written there before the next commit goes through the journal
+    // too - otherwise a crash could leave new data where the old headers should be
+    fn purge_oldest_trash(&mut self) {
+        let trashed = self.trash.remove(0);
+        let item = trashed.item();
+        let (block_id, blocks, header_blocks) = (item.get_block_id(), item.get_block_count(), item.get_extra_blocks() + 1);
+        self.write_tombstone(block_id, blocks);
+        for i in 1..header_blocks {
+            self.journal.record(block_id + i, &[0; 512]);
+        }
+        self.release(block_id, blocks);
+    }
+
+    // Marks blocks as free so they can be handed out again, merging it with any free space next to it
+    // The tombstone for it has to already be on disk. Free space always gets one tombstone covering all of it, 
+    // so that data can be written into it without breaking the archive if the machine goes down before the header is written
+    fn release(&mut self, block_id: u64, blocks: u64) {
+        let mut start = block_id;
+        let mut length = blocks;
+        let mut i = 0;
+        while i < self.free_extents.len() {
+            let (other_start, other_length) = self.free_extents[i];
+            if other_start + other_length == start {
+                start = other_start;
+                length += other_length;
+                self.free_extents.remove(i);
+            }
+            else if start + length == other_start {
+                length += other_length;
+                self.free_extents.remove(i);
+            }
+            else {
+                i += 1;
+            }
+        }
+        if start + length == self.block_used_ptr {
+            // Right before the end of the archive, so just move the end back
+            self.block_used_ptr = start;
+            return;
+        }
+        if start != block_id || length != blocks {
+            self.write_tombstone(start, length);
+        }
+        let index = self.free_extents.iter().position(|&(other_start, _)| other_start > start).unwrap_or(self.free_extents.len());
+        self.free_extents.insert(index, (start, length));
+    }
+    /*
     // Directory based seeking functions (will handle things like ls in the future)
     // First, call the get_id function - once your program has such an ID it can do things
     // Like control which directory is active (at least for it)
@@ -1380,22 +1469,97 @@ impl USTARFileSystem {
     }
 
     
-    pub fn list_files(&self, id: u64) -> Vec<String> {
+    pub fn list_files(&self, id: u64) -> Result<Vec<String>, FsError> {
         let current_dir = self.current_dirs[&id].lock();
+        if !current_dir.can(users::READ) {
+            return Err(FsError::PermissionDenied);
+        }
         let mut result = Vec::with_capacity(current_dir.contents.len());
         for i in current_dir.contents.iter() {
             result.push(i.lock().get_short_name());
         }
-        result
+        Ok(result)
     }
 
-    pub fn list_subdirectories(&self, id: u64) -> Vec<String> {
+    // Everything in a directory - files first, then directories
+    pub fn list_directory(&mut self, path: String, id: Option<u64>) -> Result<Vec<Stat>, FsError> {
+        let dir = self.find_directory(path, id)?;
+        let (contents, subdirectories) = {
+            let dir = dir.lock();
+            if !dir.can(users::READ) {
+                return Err(FsError::PermissionDenied);
+            }
+            (dir.contents.clone(), dir.subdirectories.clone())
+        };
+        let mut result = Vec::with_capacity(contents.len() + subdirectories.len());
+        for i in contents {
+            let (name, type_flag) = {
+                let file = i.lock();
+                (file.get_short_name(), file.type_flag)
+            };
+            let file = if type_flag == 1 {
+                match self.follow_links(Arc::clone(&i)) {
+                    Some(file) => file,
+                    None => i,
+                }
+            }
+            else {
+                i
+            };
+            let mut stat = file_stat(&file.lock());
+            stat.name = name;
+            result.push(stat);
+        }
+        for i in subdirectories {
+            result.push(directory_stat(&i.lock()));
+        }
+        Ok(result)
+    }
+
+    // What's at path, following links
+    pub fn stat(&mut self, path: String, id: Option<u64>) -> Result<Stat, FsError> {
+        if let Some(file) = self.resolve_file(path.to_string(), id) {
+            let file = file.lock();
+            return Ok(file_stat(&file));
+        }
+        let dir = self.find_directory(path, id)?;
+        let stat = directory_stat(&dir.lock());
+        Ok(stat)
+    }
+
+    // What kind of thing is at path - if it's a link, that's what it says instead of what it points at
+    pub fn lookup(&mut self, path: String, id: Option<u64>) -> Result<FileType, FsError> {
+        if let Some(file) = self.resolve_link(path.to_string(), id) {
+            let type_flag = file.lock().type_flag;
+            return Ok(if type_flag == 2 { FileType::Symlink } else { FileType::File });
+        }
+        self.find_directory(path, id)?;
+        Ok(FileType::Directory)
+    }
+
+    // Where the symlink at path points
+    pub fn read_link(&mut self, path: String, id: Option<u64>) -> Result<String, FsError> {
+        let file = match self.resolve_link(path.to_string(), id) {
+            Some(file) => file,
+            None => return Err(self.missing(&path, id, false)),
+        };
+        let file = file.lock();
+        if file.type_flag != 2 {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(file.linked_name.to_string())
+    }
+
+    pub fn list_subdirectories(&self, id: u64) -> Result<Vec<String>, FsError> {
         let current_dir = self.current_dirs[&id].lock();
+        if !current_dir.can(users::READ) {
+            return Err(FsError::PermissionDenied);
+        }
         let mut result = Vec::with_capacity(current_dir.subdirectories.len());
         for i in current_dir.subdirectories.iter() {
             result.push(i.lock().get_short_name());
         }
-        result
+        Ok(result)
     }
 
     pub fn cwd(&self, id: u64) -> String {
@@ -1409,38 +1573,22 @@ impl USTARFileSystem {
     }
 
     fn resolve_directory_absolute(&self, path: String) -> Option<Arc<Mutex<Directory>>> {
-        let mut current_dir = Arc::clone(&self.root);
-        for i in self.split_path(&path).iter() {
-            let newref = Arc::clone(&current_dir);
-            let subdirs = &newref.lock().subdirectories;
-            let mut changed = false;
-            for d in subdirs.iter() {
-                let mut child_path = self.split_path(&d.lock().name);
-                let last_item = match child_path.pop() {
-                    Some(item) => item,
-                    None => "".to_string(),
-                };
-    
-                if *i == last_item {
-                    let d_clone = Arc::clone(d);
-                    changed = true;
-                    current_dir = d_clone;
-                    break;
-                }
-            }         
-            if !changed {
-                return None;
-            }
-        }
-        Some(current_dir)    
+        self.walk_directory(Arc::clone(&self.root), &path, 0)
     }
 
     fn resolve_directory_relative(&self, path: String, id: u64) -> Option<Arc<Mutex<Directory>>> {
-        let mut current_dir = Arc::clone(match self.current_dirs.get(&id) {
+        let current_dir = Arc::clone(match self.current_dirs.get(&id) {
             Some(current_dir) => current_dir,
             None => &self.root,
         });
-        for i in self.split_path(&path).iter() {
+        self.walk_directory(current_dir, &path, 0)
+    }
+
+    // Goes down path one piece at a time starting at start, following symlinks to directories along the way
+    // depth is how many symlinks deep we already are, so that a loop gives up instead of going forever
+    fn walk_directory(&self, start: Arc<Mutex<Directory>>, path: &str, depth: usize) -> Option<Arc<Mutex<Directory>>> {
+        let mut current_dir = start;
+        for i in self.split_path(path).iter() {
             if i == ".." {
                 let parent_dir = current_dir.lock().parent.upgrade();
                 let parent = match parent_dir {
@@ -1453,25 +1601,45 @@ impl USTARFileSystem {
                 // Do nothing - this is the same path
             }
             else {
-                let newref = Arc::clone(&current_dir);
-                let subdirs = &newref.lock().subdirectories;
-                let mut changed = false;
-                for d in subdirs.iter() {
-                    let mut child_path = self.split_path(&d.lock().name);
-                    let last_item = match child_path.pop() {
-                        Some(item) => item,
-                        None => "".to_string(),
-                    };
-        
-                    if *i == last_item {
-                        let d_clone = Arc::clone(d);
-                        changed = true;
-                        current_dir = d_clone;
-                        break;
+                let (subdir, link) = {
+                    let dir = current_dir.lock();
+                    let mut subdir = None;
+                    for d in dir.subdirectories.iter() {
+                        let mut child_path = self.split_path(&d.lock().name);
+                        let last_item = match child_path.pop() {
+                            Some(item) => item,
+                            None => "".to_string(),
+                        };
+                        if *i == last_item {
+                            subdir = Some(Arc::clone(d));
+                            break;
+                        }
+                    }
+                    let mut link = None;
+                    if subdir.is_none() {
+                        for f in dir.contents.iter() {
+                            let f = f.lock();
+                            if f.type_flag == 2 && f.get_short_name() == *i {
+                                link = Some(f.linked_name.to_string());
+                                break;
+                            }
+                        }
                     }
-                }         
-                if !changed {
-                    return None;
+                    (subdir, link)
+                };
+                match (subdir, link) {
+                    (Some(subdir), _) => current_dir = subdir,
+                    (None, Some(target)) => {
+                        if depth >= MAX_LINK_DEPTH {
+                            return None;
+                        }
+                        let base = if self.is_absolute(&target) { Arc::clone(&self.root) } else { Arc::clone(&current_dir) };
+                        current_dir = match self.walk_directory(base, &target, depth + 1) {
+                            Some(dir) => dir,
+                            None => return None,
+                        };
+                    },
+                    (None, None) => return None,
                 }
             }
         }
@@ -1513,40 +1681,183 @@ impl USTARFileSystem {
         (part1, name)
     }
     
+    // Finds the file at path, following it if it's a link
     fn resolve_file(&mut self, path: String, id: Option<u64>) -> Option<Arc<Mutex<File>>> {
+        match self.resolve_link(path, id) {
+            Some(file) => self.follow_links(file),
+            None => None,
+        }
+    }
+
+    // Finds the file at path without following it - if it's a link, this is the link itself
+    fn resolve_link(&mut self, path: String, id: Option<u64>) -> Option<Arc<Mutex<File>>> {
         let (path, file) = self.split_last_and_first(path);
-        if self.is_absolute(&path) && !path.is_empty() {
-            let parent_dir = match self.resolve_directory_absolute(path) {
-                Some(thing) => thing,
-                None => return None,
-            };
-            let parent = parent_dir.lock();
-            for i in parent.contents.iter() {
-                if i.lock().get_short_name() == file {
-                    return Some(Arc::clone(i));
-                }
-            }
-            None
+        let parent_dir = if self.is_absolute(&path) && !path.is_empty() {
+            self.resolve_directory_absolute(path)
         }
         else {
             let id = match id {
                 Some(id) => id,
                 None => self.get_id(),
             };
-            let parent_dir = match self.resolve_directory_relative(path,id) {
-                Some(thing) => thing,
+            self.resolve_directory_relative(path,id)
+        };
+        let parent_dir = match parent_dir {
+            Some(thing) => thing,
+            None => return None,
+        };
+        let parent = parent_dir.lock();
+        for i in parent.contents.iter() {
+            if i.lock().get_short_name() == file {
+                return Some(Arc::clone(i));
+            }
+        }
+        None
+    }
+
+    // Finds the file at path (following links), or works out why it's not there
+    fn find_file(&mut self, path: String, id: Option<u64>) -> Result<Arc<Mutex<File>>, FsError> {
+        match self.resolve_file(path.to_string(), id) {
+            Some(file) => Ok(file),
+            None => Err(self.missing(&path, id, false)),
+        }
+    }
+
+    fn find_directory(&mut self, path: String, id: Option<u64>) -> Result<Arc<Mutex<Directory>>, FsError> {
+        match self.resolve_directory(path.to_string(), id) {
+            Some(dir) => Ok(dir),
+            None => Err(self.missing(&path, id, true)),
+        }
+    }
+
+    // Works out why nothing usable was found at path - either it's the wrong kind of thing, something on the way 
+    // to it is a file instead of a directory, or it just isn't there
+    fn missing(&mut self, path: &str, id: Option<u64>, wanted_directory: bool) -> FsError {
+        if wanted_directory && self.resolve_link(path.to_string(), id).is_some() {
+            return FsError::NotADirectory;
+        }
+        if !wanted_directory && self.resolve_directory(path.to_string(), id).is_some() {
+            return FsError::IsADirectory;
+        }
+        let mut path = path.trim_end_matches('/').to_string();
+        loop {
+            let (first, _) = self.split_last_and_first(path);
+            let first = first.trim_end_matches('/').to_string();
+            if first.is_empty() {
+                return FsError::NotFound;
+            }
+            if self.resolve_file(first.to_string(), id).is_some() {
+                return FsError::NotADirectory;
+            }
+            if self.resolve_directory(first.to_string(), id).is_some() {
+                return FsError::NotFound;
+            }
+            path = first;
+        }
+    }
+
+    // Finds the file at path, starting from start
+    fn find_entry(&self, start: Arc<Mutex<Directory>>, path: &str) -> Option<Arc<Mutex<File>>> {
+        let (first, last) = self.split_last_and_first(path.to_string());
+        let parent_dir = match self.walk_directory(start, &first, 0) {
+            Some(thing) => thing,
+            None => return None,
+        };
+        let parent = parent_dir.lock();
+        for i in parent.contents.iter() {
+            if i.lock().get_short_name() == last {
+                return Some(Arc::clone(i));
+            }
+        }
+        None
+    }
+
+    // Follows links until it gets to an actual file - None if the link is broken or goes around in a loop
+    fn follow_links(&self, file: Arc<Mutex<File>>) -> Option<Arc<Mutex<File>>> {
+        let mut file = file;
+        for _ in 0..MAX_LINK_DEPTH {
+            let (type_flag, target, name) = {
+                let file = file.lock();
+                (file.type_flag, file.linked_name.to_string(), file.name.to_string())
+            };
+            let next = match type_flag {
+                // Hard links always point at a path from the root of the archive
+                1 => self.find_entry(Arc::clone(&self.root), &target),
+                // Symlinks can also be relative to the directory they're in
+                2 => {
+                    let base = if self.is_absolute(&target) {
+                        Some(Arc::clone(&self.root))
+                    }
+                    else {
+                        let (first, _) = self.split_last_and_first(name);
+                        self.resolve_directory_absolute(first)
+                    };
+                    match base {
+                        Some(base) => self.find_entry(base, &target),
+                        None => None,
+                    }
+                },
+                _ => return Some(file),
+            };
+            file = match next {
+                Some(next) => next,
                 None => return None,
             };
-            let parent = parent_dir.lock();
-            for i in parent.contents.iter() {
-                if i.lock().get_short_name() == file {
-                    return Some(Arc::clone(i));
-                }
+        }
+        None
+    }
+
+    // Gets every file in folder and everything under it
+    fn collect_files(&self, folder: Arc<Mutex<Directory>>, files: &mut Vec<Arc<Mutex<File>>>) {
+        let (contents, subdirectories) = {
+            let folder = folder.lock();
+            (folder.contents.clone(), folder.subdirectories.clone())
+        };
+        files.extend(contents);
+        for i in subdirectories {
+            self.collect_files(i, files);
+        }
+    }
+
+    // Hard link targets in archives made by tar don't start with a /
+    fn hard_link_target(&self, target: &str) -> String {
+        if self.is_absolute(target) {
+            target.to_string()
+        }
+        else {
+            format!("/{}", target)
+        }
+    }
+
+    // All of the hard links that point at the file called name
+    fn hard_links_to(&self, name: &str) -> Vec<Arc<Mutex<File>>> {
+        let mut files = Vec::new();
+        self.collect_files(Arc::clone(&self.root), &mut files);
+        files.retain(|f| {
+            let f = f.lock();
+            f.type_flag == 1 && self.hard_link_target(&f.linked_name) == name
+        });
+        files
+    }
+
+    // Keeps hard links pointing at the right thing when it moves
+    // old and new are either file names, or directory names (ending in /) to fix the links to everything inside of it
+    fn retarget_hard_links(&self, old: &str, new: &str) {
+        let mut files = Vec::new();
+        self.collect_files(Arc::clone(&self.root), &mut files);
+        for i in files.iter() {
+            let mut file = i.lock();
+            if file.type_flag != 1 {
+                continue;
+            }
+            let target = self.hard_link_target(&file.linked_name);
+            if target == old || (old.ends_with('/') && target.starts_with(old)) {
+                file.linked_name = format!("{}{}", new, &target[old.len()..]);
+                file.should_write();
             }
-            None
         }
     }
-    
+
     // Kept for backwards compatability
     pub fn up_directory(&mut self, id: u64) {
         let current_dirs = match self.current_dirs.remove_entry(&id) {
@@ -1562,150 +1873,455 @@ impl USTARFileSystem {
         self.current_dirs.insert(id, Arc::clone(upgraded_pointer));
     }
 
-    pub fn change_directory(&mut self, directory: String, id: u64) -> bool {
-        let dir_to_change = self.resolve_directory(directory, Some(id));
-        let current_dirs = match self.current_dirs.remove_entry(&id) {
-            Some((_, current_dirs)) => current_dirs,
-            None => return false,
-        };
-        match dir_to_change {
-            Some(dir) => {
-                self.current_dirs.insert(id, Arc::clone(&dir));
-                true
-            },
-            None => {
-                self.current_dirs.insert(id, Arc::clone(&current_dirs));
-                false
-            },
+    pub fn change_directory(&mut self, directory: String, id: u64) -> Result<(), FsError> {
+        let dir = self.find_directory(directory, Some(id))?;
+        // Going into a directory needs execute permission on it
+        if !dir.lock().can(users::EXECUTE) {
+            return Err(FsError::PermissionDenied);
         }
+        self.current_dirs.insert(id, dir);
+        Ok(())
     }
     
-    // If a file doesn't exist, returns None
-    pub fn read_file(&mut self, file: String, id: Option<u64>) -> Option<Vec<u8>> {
-        let file = self.resolve_file(file, id);
-        match file {
-            Some(file) => return Some(file.lock().get_data()),
-            None => return None,
-        };
+    pub fn read_file(&mut self, file: String, id: Option<u64>) -> Result<Vec<u8>, FsError> {
+        let file = self.find_file(file, id)?;
+        if !file.lock().can(users::READ) {
+            return Err(FsError::PermissionDenied);
+        }
+        self.load_file(&file)?;
+        let data = file.lock().get_data();
+        Ok(data)
     }
     
+    // Reads as much of a file as fits in buf, starting at offset, without copying the rest of it
+    pub fn read_file_at(&mut self, file: String, offset: u64, buf: &mut [u8], id: Option<u64>) -> Result<usize, FsError> {
+        let file = self.find_file(file, id)?;
+        if !file.lock().can(users::READ) {
+            return Err(FsError::PermissionDenied);
+        }
+        self.load_file(&file)?;
+        let file = file.lock();
+        let start = (offset as usize).min(file.data.len());
+        let count = buf.len().min(file.data.len() - start);
+        buf[..count].copy_from_slice(&file.data[start..start + count]);
+        Ok(count)
+    }
+
+    // Writes data into a file at offset, and says how much was written
+    // As long as it fits in the blocks the file already has, only the blocks that change get written and the header goes
+    // through the journal - otherwise the file has to move, so the whole thing gets written somewhere new
+    pub fn write_file_at(&mut self, path: String, offset: u64, data: &[u8], id: Option<u64>) -> Result<usize, FsError> {
+        let file = self.find_file(path.to_string(), id)?;
+        if !file.lock().can(users::WRITE) {
+            return Err(FsError::PermissionDenied);
+        }
+        let (block_id, size) = {
+            let file = file.lock();
+            // The data starts after the headers
+            (file.block_id + file.extra_blocks + 1, file.size)
+        };
+        let end = offset + data.len() as u64;
+        if end > blocks_for_size(size) * 512 {
+            let mut contents = self.read_file(path.to_string(), id)?;
+            if (contents.len() as u64) < end {
+                contents.resize(end as usize, 0);
+            }
+            contents[offset as usize..end as usize].copy_from_slice(data);
+            self.write_file(path, contents, id)?;
+            return Ok(data.len());
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        // The blocks at either end might only be partly written, so what's already there gets read in first
+        let first = offset / 512;
+        let last = (end - 1) / 512;
+        let mut blocks = vec![0; ((last - first + 1) * 512) as usize];
+        self.block_driver.read_blocks(block_id + first, &mut blocks)?;
+        let start = (offset - first * 512) as usize;
+        blocks[start..start + data.len()].copy_from_slice(data);
+        // A crash part way through leaves some of the new data there and some of the old, but the header always says
+        // how big the file really is
+        self.block_driver.write_blocks(block_id + first, &blocks)?;
+
+        let header = {
+            let mut file = file.lock();
+            if file.loaded {
+                if file.data.len() < end as usize {
+                    file.data.resize(end as usize, 0);
+                }
+                file.data[offset as usize..end as usize].copy_from_slice(data);
+            }
+            if end > size {
+                file.size = end;
+                if file.loaded {
+                    self.cached_bytes += end - size;
+                }
+            }
+            file.touch();
+            file.to_block()
+        };
+        self.journal.record(block_id - 1, &header);
+        self.commit()?;
+        Ok(data.len())
+    }
+
     // If a file doesn't exist, running this function will create it
     // Doesn't append to the data, but flat out replaces it - changes in allocation need to defrag
     // Does not account for if you write nothing, you're on your own
-    pub fn write_file(&mut self, file: String, data: Vec<u8>, id: Option<u64>) {
+    pub fn write_file(&mut self, file: String, data: Vec<u8>, id: Option<u64>) -> Result<(), FsError> {
         let file_string = file.to_string();
         let file = self.resolve_file(file, id);
+        // Whatever is being replaced - it only goes away once there's somewhere for the new one to go
+        let mut replaced = None;
         let mut file_data = match file {
             Some(file) => {
-                self.remove_file(file_string, id);
-                File::from_block(file.lock().to_block(), self.block_used_ptr)
+                if !file.lock().can(users::WRITE) {
+                    return Err(FsError::PermissionDenied);
+                }
+                let (header, gnu_long_names) = {
+                    let file = file.lock();
+                    (file.header(), file.gnu_long_names)
+                };
+                replaced = Some(file);
+                let mut file = File::from_header(header, 0);
+                file.gnu_long_names = gnu_long_names;
+                file.touch();
+                file
             },
             None => {
+                match self.missing(&file_string, id, false) {
+                    FsError::NotFound => {},
+                    err => return Err(err),
+                }
+                if !self.nearest_directory(&file_string, id).lock().can(users::WRITE | users::EXECUTE) {
+                    return Err(FsError::PermissionDenied);
+                }
+                // A symlink that doesn't point at anything just gets replaced
+                replaced = self.resolve_link(file_string.to_string(), id);
                 if let Some(id) = id {
                     let mut cwd = self.cwd(id);
                     cwd.push_str(&file_string);
-                    File::new(self.block_used_ptr, cwd)
+                    File::new(0, cwd)
                 }
                 else {
-                    File::new(self.block_used_ptr, file_string)
+                    File::new(0, file_string)
                 }
 
             },
         };
-        let mut size = data.len();
-        if size % 512 == 0 {
-            size /= 512; 
-        }
-        else {
-            size = (size - size % 512) / 512 + 1; 
-        }
-        self.block_used_ptr += size as u64 + 1;
+        let extra_blocks = file_data.needed_extra_blocks();
+        let block_id = self.allocate(extra_blocks + blocks_for_size(data.len() as u64) + 1)?;
+        // The old file only goes away on disk when the new one gets written
+        if let Some(replaced) = replaced {
+            self.unlink_file(&replaced);
+        }
+        self.make_room(data.len() as u64);
+        file_data.set_block_id(block_id);
+        file_data.set_extra_blocks(extra_blocks);
         file_data.set_data(data);
         file_data.should_write();
-        self.place_file_in_vfs(file_data);
-        self.write();
+        let file = self.place_file_in_vfs(file_data);
+        self.track_loaded(&file);
+        self.write()
     }
-    /*
-    pub fn move_file(&self, file: String, data: Vec<u8>, id: u64) {
 
+    // Figures out where something should go when it gets moved or copied to new_path
+    // If new_path is a directory that already exists, the item goes inside of it and keeps its name,
+    // otherwise new_path is treated as the full new path (the parent directory has to exist)
+    // Returns the new parent directory and the new short name
+    fn resolve_destination(&mut self, new_path: String, short_name: String, id: Option<u64>) -> Result<(Arc<Mutex<Directory>>, String), FsError> {
+        if let Some(directory) = self.resolve_directory(new_path.to_string(), id) {
+            return Ok((directory, short_name));
+        }
+        let (first, last) = self.split_last_and_first(new_path);
+        if last.is_empty() || last == "." || last == ".." {
+            return Err(FsError::InvalidArgument);
+        }
+        // An empty parent path means the current directory
+        let first = if first.is_empty() { ".".to_string() } else { first };
+        let directory = self.find_directory(first, id)?;
+        Ok((directory, last))
     }
 
-    pub fn copy_file(&self, file: String, data: Vec<u8>, id: u64) {
+    // Checks that short_name can be used for something new in directory
+    fn check_new_name(&self, directory: &Arc<Mutex<Directory>>, short_name: &str) -> Result<(), FsError> {
+        if short_name.is_empty() || short_name.contains('/') {
+            return Err(FsError::InvalidArgument);
+        }
+        if self.name_taken(directory, short_name) {
+            return Err(FsError::AlreadyExists);
+        }
+        Ok(())
+    }
 
+    // Checks if a directory already has a file or subdirectory with the given short name
+    fn name_taken(&self, directory: &Arc<Mutex<Directory>>, short_name: &str) -> bool {
+        let directory = directory.lock();
+        let dir_name = format!("{}/", short_name);
+        for i in directory.contents.iter() {
+            if i.lock().get_short_name() == short_name {
+                return true;
+            }
+        }
+        for i in directory.subdirectories.iter() {
+            if i.lock().get_short_name() == dir_name {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Moves a file into directory under short_name - the data stays where it is on disk, only the header changes
+    fn relocate_file(&mut self, file: Arc<Mutex<File>>, directory: Arc<Mutex<Directory>>, short_name: String) -> Result<(), FsError> {
+        self.check_new_name(&directory, &short_name)?;
+        // Take it out of the directory it's currently in
+        let old_name = file.lock().name.to_string();
+        let (first, _) = self.split_last_and_first(old_name.to_string());
+        if let Some(old_directory) = self.resolve_directory_absolute(first) {
+            old_directory.lock().contents.retain(|f| !Arc::ptr_eq(f, &file));
+        }
+        let mut dir_lock = directory.lock();
+        let new_name = format!("{}{}", dir_lock.name, short_name);
+        {
+            let mut file_lock = file.lock();
+            file_lock.name = new_name.to_string();
+            file_lock.should_write();
+        }
+        dir_lock.contents.push(file);
+        drop(dir_lock);
+        self.retarget_hard_links(&old_name, &new_name);
+        self.write()
+    }
+
+    // Makes a new file in directory with the same data and header info as file
+    fn copy_file_into(&mut self, file: Arc<Mutex<File>>, directory: Arc<Mutex<Directory>>, short_name: String) -> Result<(), FsError> {
+        self.load_file(&file)?;
+        let (header, data, linked_name, gnu_long_names) = {
+            let file = file.lock();
+            (file.header(), file.get_data(), file.linked_name.to_string(), file.gnu_long_names)
+        };
+        let mut file_data = File::from_header(header, 0);
+        file_data.name = format!("{}{}", directory.lock().name, short_name);
+        file_data.linked_name = linked_name;
+        file_data.gnu_long_names = gnu_long_names;
+        file_data.touch();
+        let extra_blocks = file_data.needed_extra_blocks();
+        let block_id = self.allocate(extra_blocks + blocks_for_size(data.len() as u64) + 1)?;
+        self.make_room(data.len() as u64);
+        file_data.set_block_id(block_id);
+        file_data.set_extra_blocks(extra_blocks);
+        file_data.set_data(data);
+        file_data.should_write();
+        let file = self.place_file_in_vfs(file_data);
+        self.track_loaded(&file);
+        Ok(())
     }
 
-    pub fn rename_file(&self, file: String, data: Vec<u8>, id: u64) {
+    // Moves a file, new_path can either be a directory to put it in or the new path for the file
+    // If the file doesn't exist, or something is already at the new path, nothing changes
+    pub fn move_file(&mut self, file: String, new_path: String, id: Option<u64>) -> Result<(), FsError> {
+        let file = match self.resolve_link(file.to_string(), id) {
+            Some(file) => file,
+            None => return Err(self.missing(&file, id, false)),
+        };
+        let short_name = file.lock().get_short_name();
+        let (directory, short_name) = self.resolve_destination(new_path, short_name, id)?;
+        if !self.can_change_parent_of(&file.lock().name) || !directory.lock().can(users::WRITE | users::EXECUTE) {
+            return Err(FsError::PermissionDenied);
+        }
+        self.relocate_file(file, directory, short_name)
+    }
 
+    // Copies a file, new_path works the same way as it does for move_file
+    pub fn copy_file(&mut self, file: String, new_path: String, id: Option<u64>) -> Result<(), FsError> {
+        let file = self.find_file(file, id)?;
+        let short_name = file.lock().get_short_name();
+        let (directory, short_name) = self.resolve_destination(new_path, short_name, id)?;
+        self.check_new_name(&directory, &short_name)?;
+        if !file.lock().can(users::READ) || !directory.lock().can(users::WRITE | users::EXECUTE) {
+            return Err(FsError::PermissionDenied);
+        }
+        self.copy_file_into(file, directory, short_name)?;
+        self.write()
     }
-    */
-    // Removes a file if it exists, does nothing if it doesn't
-    pub fn remove_file(&mut self, file: String, id: Option<u64>) {
-        if let Some(file) =  self.resolve_file(file, id) {
-            println!("{}",  file.lock().name);
-            let (first, _) = self.split_last_and_first(file.lock().name.to_string());
-            if let Some(directory) = self.resolve_directory( first, id) {
-                file.lock().name = "defrag".to_string();
-                file.lock().should_write();
-                let mut dir_lock = directory.lock();
-                let contents =  &mut dir_lock.contents;
-                for (i, d) in contents.iter().enumerate() {
-                    if d.lock().name == "defrag" {
-                        contents.remove(i);
-                        break;
-                    }
+
+    // 2nd parameter should not be a path, only the new name - the file stays in the same directory
+    pub fn rename_file(&mut self, file: String, new_name: String, id: Option<u64>) -> Result<(), FsError> {
+        let file = match self.resolve_link(file.to_string(), id) {
+            Some(file) => file,
+            None => return Err(self.missing(&file, id, false)),
+        };
+        let (first, _) = self.split_last_and_first(file.lock().name.to_string());
+        let directory = match self.resolve_directory_absolute(first) {
+            Some(directory) => directory,
+            None => return Err(FsError::NotFound),
+        };
+        if !directory.lock().can(users::WRITE | users::EXECUTE) {
+            return Err(FsError::PermissionDenied);
+        }
+        self.relocate_file(file, directory, new_name)
+    }
+    // Removes a file if it exists
+    // Removing a link only removes the link, not what it points at
+    pub fn remove_file(&mut self, file: String, id: Option<u64>) -> Result<(), FsError> {
+        let file = match self.resolve_link(file.to_string(), id) {
+            Some(file) => file,
+            None => return Err(self.missing(&file, id, false)),
+        };
+        let name = file.lock().name.to_string();
+        if !self.can_change_parent_of(&name) {
+            return Err(FsError::PermissionDenied);
+        }
+        let links = self.hard_links_to(&name);
+        match links.first() {
+            // Hard links still need the data, so the first one takes over the file instead
+            Some(link) => {
+                let link = Arc::clone(link);
+                let link_name = link.lock().name.to_string();
+                self.unlink_file(&link);
+                let (first, last) = self.split_last_and_first(link_name);
+                match self.resolve_directory_absolute(first) {
+                    Some(directory) => self.relocate_file(file, directory, last),
+                    None => self.write(),
                 }
-                drop(dir_lock);
-                self.write();
-            }
-/*            file.lock().name = "defrag".to_string();
-            file.lock().should_write();
-            println!("{}", file.lock().get_writable_representation().len());
-            self.write();*/
+            },
+            None => {
+                self.detach_file(&file);
+                self.trash_file(&file);
+                self.write()
+            },
         }
     }
-    /*
-    pub fn remove_file_absolute_path(&self, path: String) {
-        
+
+    // Takes a file out of the directory it's in
+    fn detach_file(&mut self, file: &Arc<Mutex<File>>) {
+        let (first, _) = self.split_last_and_first(file.lock().name.to_string());
+        if let Some(directory) = self.resolve_directory_absolute(first) {
+            directory.lock().contents.retain(|f| !Arc::ptr_eq(f, file));
+        }
     }
 
-    // Probably should use directory moving to implement this
-    // If it doesn't exist, nothing happens
-    pub fn rename_directory(&self, dir: String, new_name: String, id: u64) -> bool {
+    // Takes a file out of the vfs without writing anything, so the removal can go to disk together with whatever comes next
+    fn unlink_file(&mut self, file: &Arc<Mutex<File>>) {
+        self.detach_file(file);
+        self.free_file(file);
+    }
 
+    // Forgets about a file, and gives its space back once its tombstone is written
+    fn free_file(&mut self, file: &Arc<Mutex<File>>) {
+        let (block_id, blocks) = {
+            let mut file = file.lock();
+            file.name = "defrag".to_string();
+            file.should_write();
+            (file.block_id, file.get_block_count())
+        };
+        self.pending_free.push((block_id, blocks));
+    }
+
+    // Like free_file, except the file goes in the trash and keeps its space until it's taken back out
+    // Its trash headers get written with the next commit. Anything whose path doesn't fit in them is freed instead
+    fn trash_file(&mut self, file: &Arc<Mutex<File>>) {
+        let removed = rtc::unix_time();
+        let (headers, block_id) = {
+            let file = file.lock();
+            let linked_name = if file.is_link() { file.linked_name.to_string() } else { String::new() };
+            let block_id = file.block_id;
+            // The trashed file is what init would read back off the disk
+            let headers = trash_headers(file.to_block(), &file.name, &linked_name, file.extra_blocks, removed).and_then(|headers| {
+                let mut trashed = File::from_block(headers[headers.len() - 512..].to_vec(), block_id).ok()?;
+                trashed.name = file.name.to_string();
+                trashed.linked_name = file.linked_name.to_string();
+                trashed.extra_blocks = file.extra_blocks;
+                Some((headers, trashed))
+            });
+            (headers, block_id)
+        };
+        let (headers, trashed) = match headers {
+            Some(headers) => headers,
+            None => {
+                println!("\n{} doesn't fit in the trash, so it's gone for good", file.lock().name);
+                return self.free_file(file);
+            },
+        };
+        for (i, sector) in headers.chunks(512).enumerate() {
+            self.journal.record(block_id + i as u64, sector);
+        }
+        self.trash.push(Trashed::File(trashed, removed));
+        // Nothing else should see it from here on, and write() forgets about it
+        let mut file = file.lock();
+        file.name = "defrag".to_string();
+        file.write = false;
     }
 
-    // 2nd parameter should not be complete path, but only the new name
-    pub fn rename_directory_absolute_path(&self, path: String, new_name: String) -> bool {
+    // Makes a link at link_path that points at target. Symlinks just keep the path they were given, so they can point at
+    // a directory, or at something that doesn't exist yet. Hard links have to point at a file
+    pub fn create_link(&mut self, target: String, link_path: String, symbolic: bool, id: Option<u64>) -> Result<(), FsError> {
+        let (type_flag, linked_name) = if symbolic {
+            (2, target.to_string())
+        }
+        else {
+            let file = self.find_file(target.to_string(), id)?;
+            let name = file.lock().name.to_string();
+            (1, name)
+        };
+        if linked_name.is_empty() {
+            return Err(FsError::InvalidArgument);
+        }
+        let (_, short_name) = self.split_last_and_first(target);
+        let (directory, short_name) = self.resolve_destination(link_path, short_name, id)?;
+        self.check_new_name(&directory, &short_name)?;
+        if !directory.lock().can(users::WRITE | users::EXECUTE) {
+            return Err(FsError::PermissionDenied);
+        }
+        let mut link = File::new(0, format!("{}{}", directory.lock().name, short_name));
+        link.type_flag = type_flag;
+        link.linked_name = linked_name;
+        // Long names and targets need room for their own headers
+        link.extra_blocks = link.needed_extra_blocks();
+        link.block_id = self.allocate(link.extra_blocks + 1)?;
+        link.should_write();
+        self.place_file_in_vfs(link);
+        self.write()
+    }
+    /*
+    pub fn remove_file_absolute_path(&self, path: String) {
         
     }
     */
     // Creates a directory unless there exists a file or directory with a similar name
-    pub fn create_directory(&mut self, file: String, id: u64) -> bool {
+    pub fn create_directory(&mut self, file: String, id: u64) -> Result<(), FsError> {
         if self.is_absolute(&file) {
             return self.create_directory_absolute_path(file);
         }
         let mut file = file.replace("/", "");
         file.push('/');
-        let current_dir_arc = &self.current_dirs[&id];
+        let current_dir_arc = Arc::clone(&self.current_dirs[&id]);
         let mut current_dir = current_dir_arc.lock();
         for i in current_dir.contents.iter() {
             if i.lock().get_short_name() == file && i.lock().block_id != u64::MAX {
-                return false;
+                return Err(FsError::AlreadyExists);
             }
         }
         for i in current_dir.subdirectories.iter() {
             if i.lock().get_short_name() == file {
-                return false;
+                return Err(FsError::AlreadyExists);
             }
         }
+        if !current_dir.can(users::WRITE | users::EXECUTE) {
+            return Err(FsError::PermissionDenied);
+        }
         // Check if subfolder exists - if so, update it instead of replacing it 
-        let mut folder = Directory::new(self.block_used_ptr, format!("{}{}", current_dir.name, file));
+        let mut folder = Directory::new(0, format!("{}{}", current_dir.name, file));
+        folder.extra_blocks = folder.needed_extra_blocks();
+        folder.block_id = self.allocate(folder.extra_blocks + 1)?;
         for i in current_dir.subdirectories.iter() {
             if folder.name == i.lock().name {
-                i.lock().reinit_from_block(folder.to_block(), folder.block_id);
+                i.lock().take_header_from(&mut folder);
                 let result = Arc::clone(i);
                 self.files.push(result);
-                return true;
+                return Ok(());
             }
         }
         folder.should_write();
@@ -1714,12 +2330,17 @@ impl USTARFileSystem {
         current_dir.subdirectories.push(Arc::clone(&folder));
         self.files.push(folder);
         drop(current_dir);
-        self.block_used_ptr += 1;
-        self.write();
-        true
+        self.write()
     }
     
-    pub fn create_directory_absolute_path(&mut self, path: String) -> bool {
+    pub fn create_directory_absolute_path(&mut self, path: String) -> Result<(), FsError> {
+        // Checked up front, because the parents get made on the way there
+        if self.lookup(path.to_string(), None).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        if let FsError::NotADirectory = self.missing(&path, None, true) {
+            return Err(FsError::NotADirectory);
+        }
         let id = self.get_id();
         let dir = self.generate_path_if_does_not_exist(&path);
         self.current_dirs.remove_entry(&id);
@@ -1729,80 +2350,619 @@ impl USTARFileSystem {
             Some(i) => i,
             None => "".to_string(),
         };
-        self.create_directory(name, id)    
+        let result = self.create_directory(name, id);
+        self.current_dirs.remove(&id);
+        result
     }
     
-    fn remove_directory_recursive(&mut self, folder: Arc<Mutex<Directory>>) {
+    // Puts a directory and everything in it in the trash
+    fn trash_directory_recursive(&mut self, folder: Arc<Mutex<Directory>>) {
+        let (contents, subdirectories) = {
+            let folder = folder.lock();
+            (folder.contents.clone(), folder.subdirectories.clone())
+        };
+        for i in contents.iter() {
+            self.trash_file(i);
+        }
+        for i in subdirectories {
+            self.trash_directory_recursive(i);
+        }
+        self.trash_directory(&folder);
+    }
+
+    // The same as trash_file, for a directory on its own
+    fn trash_directory(&mut self, folder: &Arc<Mutex<Directory>>) {
         let mut folder = folder.lock();
+        let removed = rtc::unix_time();
+        // Directories that aren't backed by disk don't have anything to keep
+        let headers = if folder.block_id == u64::MAX {
+            None
+        }
+        else {
+            let block_id = folder.block_id;
+            trash_headers(folder.to_block(), &folder.name, "", folder.extra_blocks, removed).and_then(|headers| {
+                let mut trashed = Directory::from_block(headers[headers.len() - 512..].to_vec(), block_id).ok()?;
+                trashed.name = folder.name.to_string();
+                trashed.extra_blocks = folder.extra_blocks;
+                Some((headers, trashed))
+            })
+        };
+        match headers {
+            Some((headers, trashed)) => {
+                for (i, sector) in headers.chunks(512).enumerate() {
+                    self.journal.record(folder.block_id + i as u64, sector);
+                }
+                self.trash.push(Trashed::Directory(trashed, removed));
+                folder.write = false;
+            },
+            None => {
+                if folder.block_id != u64::MAX {
+                    println!("\n{} doesn't fit in the trash, so it's gone for good", folder.name.trim_end_matches('/'));
+                    self.pending_free.push((folder.block_id, folder.extra_blocks + 1));
+                }
+                folder.should_write();
+            },
+        }
         folder.name = "defrag".to_string();
+    }
+
+    // Removes a directory if it exists
+    pub fn remove_directory(&mut self, file: String, id: Option<u64>) -> Result<(), FsError> {
+        // A symlink to a directory isn't a directory - rm gets rid of those
+        if self.resolve_link(file.to_string(), id).is_some() {
+            return Err(FsError::NotADirectory);
+        }
+        if let Some(dir) =  self.resolve_directory(file.to_string(), id) {
+            let upgraded = dir.lock().parent.upgrade();
+            match upgraded {
+                Some(parent) => {
+                    if Arc::ptr_eq(&parent, &dir) {
+                        return Err(FsError::Busy);
+                    }
+                    if !parent.lock().can(users::WRITE | users::EXECUTE) {
+                        return Err(FsError::PermissionDenied);
+                    }
+                },
+                // The root directory can't go anywhere
+                None => return Err(FsError::Busy),
+            }
+            let upgraded = dir.lock().parent.upgrade();
+            if let Some(parent) = upgraded {
+                parent.lock().subdirectories.retain(|d| !Arc::ptr_eq(d, &dir));
+            }
+            self.trash_directory_recursive(dir);
+            return self.write();
+        }
+        Err(self.missing(&file, id, true))
+    }
+
+    // Everything in the trash, oldest first
+    pub fn trash(&self) -> Vec<TrashEntry> {
+        self.trash.iter().map(|trashed| trashed.entry()).collect()
+    }
+
+    // Puts back whatever was last removed from path - if it was a directory, everything that was in it comes back too
+    // Anything whose path has been taken again since stays in the trash
+    pub fn undelete(&mut self, path: String) -> Result<(), FsError> {
+        let path = path.trim_end_matches('/').to_string();
+        // The trash is oldest first, so this is the newest
+        let index = match self.trash.iter().rposition(|trashed| trashed.path() == path) {
+            Some(index) => index,
+            None => return Err(FsError::NotFound),
+        };
+        let directory = matches!(self.trash[index], Trashed::Directory(..));
+        if self.lookup(path.to_string(), None).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        match self.missing(&path, None, directory) {
+            FsError::NotFound => {},
+            err => return Err(err),
+        }
+        if !self.nearest_directory(&path, None).lock().can(users::WRITE | users::EXECUTE) {
+            return Err(FsError::PermissionDenied);
+        }
+        let trashed = self.trash.remove(index);
+        self.restore(trashed);
+        if directory {
+            let inside = format!("{}/", path);
+            let mut contents = Vec::new();
+            let mut i = self.trash.len();
+            // Newest first, so that's what wins if the same path is in there more than once
+            while i > 0 {
+                i -= 1;
+                if self.trash[i].path().starts_with(&inside) {
+                    contents.push(self.trash.remove(i));
+                }
+            }
+            // Directories go before what's in them
+            contents.sort_by_key(|trashed| trashed.path().len());
+            for trashed in contents {
+                if self.lookup(trashed.path(), None).is_ok() {
+                    self.trash.push(trashed);
+                }
+                else {
+                    self.restore(trashed);
+                }
+            }
+            self.trash.sort_by_key(|trashed| trashed.removed());
+        }
+        self.write()
+    }
+
+    // Puts something from the trash back where it was, with the same blocks - its header gets written on the next commit
+    fn restore(&mut self, trashed: Trashed) {
+        match trashed {
+            Trashed::File(mut file, _) => {
+                file.should_write();
+                self.place_file_in_vfs(file);
+            },
+            Trashed::Directory(mut folder, _) => {
+                folder.should_write();
+                self.place_folder_in_vfs(folder);
+            },
+        }
+    }
+
+    // Gets rid of everything in the trash for good, so its space can be used again
+    pub fn empty_trash(&mut self) -> Result<(), FsError> {
+        let trash = core::mem::replace(&mut self.trash, Vec::new());
+        for trashed in trash {
+            let item = trashed.item();
+            let (block_id, blocks) = (item.get_block_id(), item.get_block_count());
+            self.write_tombstone(block_id, blocks);
+            self.pending_free.push((block_id, blocks));
+        }
+        self.write()
+    }
+
+    // Creates a disk backed directory called short_name inside of parent
+    fn add_directory(&mut self, parent: Arc<Mutex<Directory>>, short_name: String) -> Result<Arc<Mutex<Directory>>, FsError> {
+        let mut folder = Directory::new(0, format!("{}{}/", parent.lock().name, short_name));
+        folder.extra_blocks = folder.needed_extra_blocks();
+        folder.block_id = self.allocate(folder.extra_blocks + 1)?;
         folder.should_write();
+        folder.parent = Arc::downgrade(&parent);
+        let folder = Arc::new(Mutex::new(folder));
+        parent.lock().subdirectories.push(Arc::clone(&folder));
+        let result = Arc::clone(&folder);
+        self.files.push(result);
+        Ok(folder)
+    }
+
+    // Swaps old_path out for new_path in the names of a directory and everything inside of it
+    fn rename_directory_recursive(&self, folder: Arc<Mutex<Directory>>, old_path: &str, new_path: &str) {
+        let folder = folder.lock();
+        // The folder's own name gets changed by whoever locked it first, so only do the children here
         for i in folder.contents.iter() {
-            i.lock().name = "defrag".to_string();
-            i.lock().should_write();
+            let mut file = i.lock();
+            if file.name.starts_with(old_path) {
+                file.name = format!("{}{}", new_path, &file.name[old_path.len()..]);
+                file.should_write();
+            }
         }
         for i in folder.subdirectories.iter() {
-            self.remove_directory_recursive(Arc::clone(i));
+            {
+                let mut subdir = i.lock();
+                if subdir.name.starts_with(old_path) {
+                    subdir.name = format!("{}{}", new_path, &subdir.name[old_path.len()..]);
+                    subdir.should_write();
+                }
+            }
+            self.rename_directory_recursive(Arc::clone(i), old_path, new_path);
         }
     }
 
-    // Removes a directory if it exists, does nothing if it doesn't
-    pub fn remove_directory(&mut self, file: String, id: Option<u64>) {
-        if let Some(dir) =  self.resolve_directory(file, id) {
-            dir.lock().name = "defrag".to_string();
-            dir.lock().should_write();
-            let upgraded = dir.lock().parent.upgrade();
-            if let Some(parent) = upgraded {
-                let subdirs =  &mut parent.lock().subdirectories;
-                for (i, d) in subdirs.iter().enumerate() {
-                    if d.lock().name == "defrag" {
-                        subdirs.remove(i);
-                        break;
-                    }
-                }
+    // Moves a directory (and everything in it) into directory under short_name
+    fn relocate_directory(&mut self, dir: Arc<Mutex<Directory>>, directory: Arc<Mutex<Directory>>, short_name: String) -> Result<(), FsError> {
+        self.check_new_name(&directory, &short_name)?;
+        let old_path = dir.lock().name.to_string();
+        let new_path = format!("{}{}/", directory.lock().name, short_name);
+        // Can't move a directory inside of itself
+        if directory.lock().name.starts_with(&old_path) {
+            return Err(FsError::InvalidArgument);
+        }
+        // Root doesn't have a parent, so it can't be moved
+        let old_parent = match dir.lock().parent.upgrade() {
+            Some(parent) => parent,
+            None => return Err(FsError::Busy),
+        };
+        if !old_parent.lock().can(users::WRITE | users::EXECUTE) || !directory.lock().can(users::WRITE | users::EXECUTE) {
+            return Err(FsError::PermissionDenied);
+        }
+        old_parent.lock().subdirectories.retain(|d| !Arc::ptr_eq(d, &dir));
+        {
+            let mut dir_lock = dir.lock();
+            dir_lock.parent = Arc::downgrade(&directory);
+            dir_lock.name = new_path.to_string();
+            dir_lock.should_write();
+        }
+        directory.lock().subdirectories.push(Arc::clone(&dir));
+        self.rename_directory_recursive(dir, &old_path, &new_path);
+        self.retarget_hard_links(&old_path, &new_path);
+        self.write()
+    }
+
+    // Makes a copy of folder (and everything in it) inside of parent, called short_name
+    fn copy_directory_recursive(&mut self, folder: Arc<Mutex<Directory>>, parent: Arc<Mutex<Directory>>, short_name: String) -> Result<(), FsError> {
+        let new_folder = self.add_directory(parent, short_name)?;
+        let (files, subdirectories) = {
+            let folder = folder.lock();
+            (folder.contents.clone(), folder.subdirectories.clone())
+        };
+        for i in files {
+            let name = i.lock().get_short_name();
+            self.copy_file_into(i, Arc::clone(&new_folder), name)?;
+        }
+        for i in subdirectories {
+            let name = i.lock().get_short_name().replace("/", "");
+            self.copy_directory_recursive(i, Arc::clone(&new_folder), name)?;
+        }
+        Ok(())
+    }
+
+    // new_path can either be a directory to put it in or the new path for the directory
+    // If 1st param doesn't exist, or 2nd param already exists, nothing changes
+    pub fn move_directory(&mut self, dir: String, new_path: String, id: Option<u64>) -> Result<(), FsError> {
+        let dir = self.find_directory(dir, id)?;
+        let short_name = dir.lock().get_short_name().replace("/", "");
+        let (directory, short_name) = self.resolve_destination(new_path, short_name, id)?;
+        self.relocate_directory(dir, directory, short_name)
+    }
+
+    // Copies a directory and everything in it, new_path works the same way as it does for move_directory
+    pub fn copy_directory(&mut self, path: String, new_path: String, id: Option<u64>) -> Result<(), FsError> {
+        let dir = self.find_directory(path, id)?;
+        let short_name = dir.lock().get_short_name().replace("/", "");
+        let (directory, short_name) = self.resolve_destination(new_path, short_name, id)?;
+        self.check_new_name(&directory, &short_name)?;
+        // Copying a directory into itself would never end
+        let old_path = dir.lock().name.to_string();
+        if directory.lock().name.starts_with(&old_path) {
+            return Err(FsError::InvalidArgument);
+        }
+        if !dir.lock().can(users::READ | users::EXECUTE) || !directory.lock().can(users::WRITE | users::EXECUTE) {
+            return Err(FsError::PermissionDenied);
+        }
+        let result = self.copy_directory_recursive(dir, directory, short_name);
+        // Whatever got copied before running out of room still gets written, so it can be removed
+        self.write()?;
+        result
+    }
+
+    // 2nd parameter should not be a path, only the new name - the directory stays in the same parent
+    pub fn rename_directory(&mut self, path: String, new_name: String, id: Option<u64>) -> Result<(), FsError> {
+        let dir = self.find_directory(path, id)?;
+        let parent = match dir.lock().parent.upgrade() {
+            Some(parent) => parent,
+            None => return Err(FsError::Busy),
+        };
+        self.relocate_directory(dir, parent, new_name)
+    }
+
+    // Checks that the current user can add and remove things in the directory that the item called name is in
+    fn can_change_parent_of(&self, name: &str) -> bool {
+        let (first, _) = self.split_last_and_first(name.to_string());
+        match self.resolve_directory_absolute(first) {
+            Some(directory) => directory.lock().can(users::WRITE | users::EXECUTE),
+            None => false,
+        }
+    }
+
+    // The directory closest to where path would go that actually exists - directories in the path that don't 
+    // exist yet get made when something is put in them, so this is the one whose permissions count
+    fn nearest_directory(&mut self, path: &str, id: Option<u64>) -> Arc<Mutex<Directory>> {
+        let mut path = path.to_string();
+        loop {
+            let (first, _) = self.split_last_and_first(path);
+            let first = if first.is_empty() { ".".to_string() } else { first };
+            if let Some(directory) = self.resolve_directory(first.to_string(), id) {
+                return directory;
             }
-            self.remove_directory_recursive(dir);
-            self.write();
+            path = first;
         }
     }
 
-    // 2nd parameter should be complete path, but not the first one (first one should be relative)
-    // If 1st param doesn't exist, or 2nd param already exists, it won't do anything
-    // TODO: Later
-    pub fn move_directory(&mut self, dir: String, new_path: String, id: Option<u64>) -> bool {
-        let dir = self.resolve_directory(dir, id);
-        let mut parent_move_dir = self.resolve_directory(new_path.to_string(), id);
-        if Option::is_none(&dir)  {
-            return false;
+    // Finds the file (following links) or directory at path
+    // A directory that only exists because something is in it gets a header, so there's somewhere to keep changes to it
+    fn resolve_item(&mut self, path: String, id: Option<u64>) -> Result<Arc<Mutex<dyn USTARItem + Send + Sync>>, FsError> {
+        if let Some(file) = self.resolve_file(path.to_string(), id) {
+            return Ok(file);
         }
-        if self.is_absolute(&new_path) {
-            let (last, first) = self.split_last_and_first(new_path);
-            parent_move_dir = self.resolve_directory(last, id);
+        let dir = match self.resolve_directory(path.to_string(), id) {
+            Some(dir) => dir,
+            None => return Err(self.missing(&path, id, false)),
+        };
+        if dir.lock().block_id == u64::MAX {
+            // The root directory can't have a header
+            if dir.lock().parent.upgrade().is_none() {
+                return Err(FsError::Unsupported);
+            }
+            self.give_header(&dir)?;
+        }
+        Ok(dir)
+    }
+
+    // Gives a directory that only exists because something is in it a header of its own
+    // It still has to be marked to be written
+    fn give_header(&mut self, dir: &Arc<Mutex<Directory>>) -> Result<(), FsError> {
+        let extra_blocks = dir.lock().needed_extra_blocks();
+        let block_id = self.allocate(extra_blocks + 1)?;
+        let mut dir_lock = dir.lock();
+        dir_lock.block_id = block_id;
+        dir_lock.extra_blocks = extra_blocks;
+        dir_lock.time = current_time();
+        drop(dir_lock);
+        let result = Arc::clone(dir);
+        self.files.push(result);
+        Ok(())
+    }
+
+    // Changes who owns path and who can do what with it - change gets the current permissions, and returns false
+    // if the current user isn't allowed to make the change
+    fn change_permissions<F>(&mut self, path: String, id: Option<u64>, change: F) -> Result<(), FsError> where F: FnOnce(&mut Permissions) -> bool {
+        let item = self.resolve_item(path, id)?;
+        let mut item_lock = item.lock();
+        let mut permissions = item_lock.get_permissions();
+        if !change(&mut permissions) {
+            // A directory that just got a header still has to be written
+            drop(item_lock);
+            self.write()?;
+            return Err(FsError::PermissionDenied);
+        }
+        item_lock.set_permissions(permissions);
+        item_lock.should_write();
+        drop(item_lock);
+        self.write()
+    }
+
+    // Only the owner (or root) can change the permission bits
+    pub fn chmod(&mut self, path: String, mode: u64, id: Option<u64>) -> Result<(), FsError> {
+        let user = users::current_user();
+        self.change_permissions(path, id, |permissions| {
+            if user.uid != 0 && user.uid != permissions.owner_id {
+                return false;
+            }
+            permissions.mode = mode & 0o7777;
+            true
+        })
+    }
+
+    // Only root can give things away
+    pub fn chown(&mut self, path: String, owner_id: u64, id: Option<u64>) -> Result<(), FsError> {
+        let user = users::current_user();
+        self.change_permissions(path, id, |permissions| {
+            if user.uid != 0 {
+                return false;
+            }
+            permissions.owner_id = owner_id;
+            permissions.owner_name = users::user_name(owner_id);
+            true
+        })
+    }
+
+    // Root can change the group to anything, the owner can only change it to their own group
+    pub fn chgrp(&mut self, path: String, group_id: u64, id: Option<u64>) -> Result<(), FsError> {
+        let user = users::current_user();
+        self.change_permissions(path, id, |permissions| {
+            if user.uid != 0 && (user.uid != permissions.owner_id || user.gid != group_id) {
+                return false;
+            }
+            permissions.group_id = group_id;
+            permissions.group_name = users::group_name(group_id);
+            true
+        })
+    }
+}
+
+impl<D: BlockDevice> USTARFileSystem<D> {
+    // Checks the headers on the disk and how everything in memory fits together
+    // With repair set, anything that can be fixed is - headers with the wrong checksum get a new one, but init has 
+    // already skipped them, so they only show up after a reboot
+    // Everything gets written as soon as it changes, so what's in memory should always match the disk
+    pub fn fsck(&mut self, repair: bool) -> Result<Vec<FsckProblem>, FsError> {
+        let mut problems = Vec::new();
+        let (damaged, end) = self.scan_headers()?;
+        for (block_id, name) in damaged {
+            problems.push(FsckProblem::BadChecksum { block_id, name });
+        }
+        if end < self.disk_end {
+            problems.push(FsckProblem::Unreadable { block_id: end });
         }
-        else if self.split_path(&new_path).len() == 1 {
-            // If length is one, it's a single path thing
+        else if end > self.disk_end {
+            // The end got written over by a header
+            problems.push(FsckProblem::BadEndMarker { block_id: self.disk_end });
         }
         else {
-            let (last, first) = self.split_last_and_first(new_path);
-            parent_move_dir = self.resolve_directory(last, id);
-            // Length more, which means it's a "compound" path
+            let first = self.block_driver.read_block(end)?;
+            let second = self.block_driver.read_block(end + 1)?;
+            if first.iter().chain(second.iter()).any(|b| *b != 0) {
+                problems.push(FsckProblem::BadEndMarker { block_id: end });
+            }
         }
+        let mut unbacked = Vec::new();
+        self.find_unbacked(Arc::clone(&self.root), &mut unbacked);
+        for dir in unbacked.iter() {
+            problems.push(FsckProblem::MissingDirectory { name: dir.lock().name.to_string() });
+        }
+        problems.append(&mut self.find_overlaps());
+
+        if repair {
+            for problem in problems.iter() {
+                if let FsckProblem::BadChecksum { block_id, .. } = problem {
+                    let mut block = self.block_driver.read_block(*block_id)?;
+                    fix_checksum(&mut block);
+                    self.journal.record(*block_id, &block);
+                }
+            }
+            for dir in unbacked {
+                self.give_header(&dir)?;
+                dir.lock().should_write();
+            }
+            // Writing always puts the end of the archive back
+            self.write()?;
+        }
+        Ok(problems)
+    }
+
+    // Goes through the headers on the disk the same way init does
+    // Gives back the ones with the wrong checksum as (block, name), and the block the archive ends at
+    fn scan_headers(&mut self) -> Result<(Vec<(u64, String)>, u64), FsError> {
+        let mut damaged = Vec::new();
+        let mut reader = Reader::new();
+        while let Some(entry) = reader.next(|block_id| self.block_driver.read_block(block_id))? {
+            if let Entry::Damaged { block_id, name } = entry {
+                damaged.push((block_id, name));
+            }
+        }
+        Ok((damaged, reader.position()))
+    }
+
+    // Finds every directory under folder that doesn't have a header
+    fn find_unbacked(&self, folder: Arc<Mutex<Directory>>, unbacked: &mut Vec<Arc<Mutex<Directory>>>) {
+        let subdirectories = folder.lock().subdirectories.clone();
+        for i in subdirectories {
+            if i.lock().block_id == u64::MAX {
+                unbacked.push(Arc::clone(&i));
+            }
+            self.find_unbacked(i, unbacked);
+        }
+    }
+
+    // Everything that takes up space on the disk - files, directories, free space, the end of the archive and the 
+    // journal - should each have blocks of their own
+    fn find_overlaps(&self) -> Vec<FsckProblem> {
+        let mut ranges = Vec::new();
+        for i in self.files.iter() {
+            let item = i.lock();
+            if item.get_block_id() != u64::MAX {
+                ranges.push((item.get_block_id(), item.get_block_count(), item.get_name()));
+            }
+        }
+        for &(start, length) in self.free_extents.iter() {
+            ranges.push((start, length, format!("Free space at block {}", start)));
+        }
+        for trashed in self.trash.iter() {
+            let item = trashed.item();
+            ranges.push((item.get_block_id(), item.get_block_count(), format!("{} in the trash", trashed.path())));
+        }
+        ranges.push((self.disk_end, 2, "The end of the archive".to_string()));
+        ranges.push((self.journal.start(), JOURNAL_BLOCKS, "The journal".to_string()));
+        ustar_format::find_overlaps(ranges)
+    }
+}
+
+// Paths from the vfs always start at the root of the archive, so there's never a current directory to worry about
+impl<D: BlockDevice> FileSystem for USTARFileSystem<D> {
+    fn lookup(&mut self, path: &str) -> Result<FileType, FsError> {
+        self.lookup(path.to_string(), None)
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Stat, FsError> {
+        self.stat(path.to_string(), None)
+    }
+
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        self.read_file(path.to_string(), None)
+    }
 
+    fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.read_file_at(path.to_string(), offset, buf, None)
+    }
 
-        true
+    fn write(&mut self, path: &str, data: Vec<u8>) -> Result<(), FsError> {
+        self.write_file(path.to_string(), data, None)
     }
 
-    pub fn copy_directory(&mut self, path: String, new_path: String) {
+    fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.write_file_at(path.to_string(), offset, data, None)
+    }
 
+    fn readdir(&mut self, path: &str) -> Result<Vec<Stat>, FsError> {
+        self.list_directory(path.to_string(), None)
     }
 
-    pub fn rename_directory(&mut self, path: String, new_path: String) {
+    fn mkdir(&mut self, path: &str) -> Result<(), FsError> {
+        self.create_directory_absolute_path(path.to_string())
+    }
 
+    fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        self.remove_file(path.to_string(), None)
     }
 
+    fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        self.remove_directory(path.to_string(), None)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        match self.lookup(from.to_string(), None)? {
+            FileType::Directory => self.move_directory(from.to_string(), to.to_string(), None),
+            _ => self.move_file(from.to_string(), to.to_string(), None),
+        }
+    }
+
+    fn link(&mut self, target: &str, link: &str, symbolic: bool) -> Result<(), FsError> {
+        self.create_link(target.to_string(), link.to_string(), symbolic, None)
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, FsError> {
+        self.read_link(path.to_string(), None)
+    }
+
+    fn chmod(&mut self, path: &str, mode: u64) -> Result<(), FsError> {
+        self.chmod(path.to_string(), mode, None)
+    }
+
+    fn chown(&mut self, path: &str, owner_id: u64) -> Result<(), FsError> {
+        self.chown(path.to_string(), owner_id, None)
+    }
+
+    fn chgrp(&mut self, path: &str, group_id: u64) -> Result<(), FsError> {
+        self.chgrp(path.to_string(), group_id, None)
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        USTARFileSystem::write(self)?;
+        self.block_driver.sync()
+    }
+
+    fn defragment(&mut self) -> Result<(), FsError> {
+        USTARFileSystem::defragment(self)
+    }
+
+    fn trash(&mut self) -> Result<Vec<TrashEntry>, FsError> {
+        Ok(USTARFileSystem::trash(self))
+    }
+
+    fn undelete(&mut self, path: &str) -> Result<(), FsError> {
+        USTARFileSystem::undelete(self, path.to_string())
+    }
+
+    fn empty_trash(&mut self) -> Result<(), FsError> {
+        USTARFileSystem::empty_trash(self)
+    }
+
+    fn fsck(&mut self, repair: bool) -> Result<Vec<String>, FsError> {
+        let problems = USTARFileSystem::fsck(self, repair)?;
+        let mut result = Vec::new();
+        for i in problems {
+            if repair && i.fixable() {
+                result.push(format!("{} - fixed", i));
+            }
+            else {
+                result.push(format!("{}", i));
+            }
+        }
+        Ok(result)
+    }
 }
 
 lazy_static! {
-    pub static ref USTARFS: Mutex<USTARFileSystem> = {
-        Mutex::new(USTARFileSystem::new())
+    // In an Arc so that the vfs can mount it
+    // None if no drive was found to put it on, or the file system on it couldn't be read
+    pub static ref USTARFS: Option<Arc<Mutex<USTARFileSystem>>> = {
+        match USTARFileSystem::new() {
+            Some(fs) => Some(Arc::new(Mutex::new(fs))),
+            None => None,
+        }
     };
 }
\ No newline at end of file
@@ -0,0 +1,1005 @@
+// Puts every file system into one tree of directories
+// Each file system gets mounted at a path, and anything under that path is handled by it
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::format;
+use core::fmt;
+use hashbrown::HashMap;
+use crate::alloc::string::ToString;
+use crate::users;
+use crate::ustar::{USTARFileSystem, USTARFS};
+use crate::partition;
+use crate::tmpfs::TmpFileSystem;
+use crate::procfs::ProcFileSystem;
+use crate::devfs::DevFileSystem;
+use crate::fd::{DescriptorTable, OpenFile, SeekFrom, OPEN_READ, OPEN_WRITE, OPEN_APPEND, OPEN_CREATE};
+
+// Why something the file system was asked to do didn't happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    NoSpace,
+    // The disk didn't do what it was told
+    Io,
+    PermissionDenied,
+    // Something is mounted there, or someone is using it
+    Busy,
+    // The file system can't do that at all
+    Unsupported,
+    // Bad names, bad descriptors and things that make no sense, like moving a directory inside of itself
+    InvalidArgument,
+    // What's on the disk doesn't add up, like a header with the wrong checksum
+    Corrupted,
+    // There's no file system on it yet - mkfs makes one
+    NotFormatted,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            FsError::NotFound => "No such file or directory",
+            FsError::AlreadyExists => "Already exists",
+            FsError::NotADirectory => "Not a directory",
+            FsError::IsADirectory => "Is a directory",
+            FsError::NoSpace => "No space left on the disk",
+            FsError::Io => "Disk error",
+            FsError::PermissionDenied => "Permission denied",
+            FsError::Busy => "In use",
+            FsError::Unsupported => "Not supported by this file system",
+            FsError::InvalidArgument => "Invalid argument",
+            FsError::Corrupted => "The file system is damaged, try fsck",
+            FsError::NotFormatted => "There's no file system on it, try mkfs",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+// The parts of a header that say who can do what
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    pub mode: u64,
+    pub owner_id: u64,
+    pub group_id: u64,
+    pub owner_name: String,
+    pub group_name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    // Device nodes - a character device is a stream of bytes, a block device is a disk
+    CharDevice,
+    BlockDevice,
+}
+
+// What stat and readdir say about something
+#[derive(Debug, Clone)]
+pub struct Stat {
+    // Just the last part of the path, without a / on the end for directories
+    pub name: String,
+    pub file_type: FileType,
+    pub size: u64,
+    // Seconds since 1970
+    pub mtime: u64,
+    // Where it points, if it's a symlink
+    pub linked_name: Option<String>,
+    pub permissions: Permissions,
+}
+
+// Something that was removed, but can still be put back
+#[derive(Debug, Clone)]
+pub struct TrashEntry {
+    // Where it was, with no / on the end
+    pub path: String,
+    pub file_type: FileType,
+    pub size: u64,
+    // When it was removed, in seconds since 1970
+    pub removed: u64,
+}
+
+// Something that can be mounted
+// Paths always start at the root of the file system itself, so a file system mounted at /mnt gets /a for /mnt/a
+pub trait FileSystem {
+    // What kind of thing is at path, without following it if it's a symlink
+    fn lookup(&mut self, path: &str) -> Result<FileType, FsError>;
+    // What's at path, following symlinks
+    fn stat(&mut self, path: &str) -> Result<Stat, FsError>;
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, FsError>;
+    // Reads as much as fits in buf starting at offset, and says how much that was - 0 past the end of the file
+    // File systems that can get at part of a file without copying all of it should do that instead
+    fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.read(path)?;
+        let start = (offset as usize).min(data.len());
+        let count = buf.len().min(data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+    // Replaces whatever is in the file, and makes it if it isn't there
+    fn write(&mut self, path: &str, data: Vec<u8>) -> Result<(), FsError>;
+    // Writes data at offset without touching the rest, and says how much was written - a gap past the end gets zeros
+    // The file has to be there already. Open files get written this way, and a file system that can't do it gets the
+    // whole file written back when it's closed instead
+    fn write_at(&mut self, _path: &str, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::Unsupported)
+    }
+    fn readdir(&mut self, path: &str) -> Result<Vec<Stat>, FsError>;
+    fn mkdir(&mut self, path: &str) -> Result<(), FsError>;
+    // Removes a file or a link
+    fn unlink(&mut self, path: &str) -> Result<(), FsError>;
+    fn rmdir(&mut self, path: &str) -> Result<(), FsError>;
+
+    // Everything past here is optional - a file system that can't do it just says so
+
+    // to is the full new path, and nothing is there yet
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn link(&mut self, _target: &str, _link: &str, _symbolic: bool) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn read_link(&mut self, _path: &str) -> Result<String, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn chmod(&mut self, _path: &str, _mode: u64) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn chown(&mut self, _path: &str, _owner_id: u64) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn chgrp(&mut self, _path: &str, _group_id: u64) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    // Gets anything that's waiting to be written onto the disk
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    fn defragment(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    // What's been removed but can still be put back, oldest first - a file system without a trash never has anything
+    fn trash(&mut self) -> Result<Vec<TrashEntry>, FsError> {
+        Ok(Vec::new())
+    }
+
+    // Puts back whatever was last removed from path
+    fn undelete(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn empty_trash(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    // Looks for anything wrong with what's on the disk, and fixes what it can if repair is set
+    // Gives back a line about each thing it found
+    fn fsck(&mut self, _repair: bool) -> Result<Vec<String>, FsError> {
+        Err(FsError::Unsupported)
+    }
+}
+
+// Makes a new file system of the type the mount command was given, on device if it sits on one
+// Gives back the file system and the name of its device
+fn new_filesystem(kind: &str, device: Option<&str>) -> Result<(Arc<Mutex<dyn FileSystem + Send>>, String), FsError> {
+    match kind {
+        "ustar" => {
+            if let Some(root) = USTARFS.as_ref() {
+                let root_device = root.lock().device().to_string();
+                // The archive at / is already open, and a second copy of it would have the two fighting over the disk
+                if device.is_none() || device == Some(root_device.as_str()) {
+                    let fs = Arc::clone(root);
+                    return Ok((fs, root_device));
+                }
+            }
+            // No drive was found to put / on
+            let device = match device {
+                Some(device) => device,
+                None => return Err(FsError::NotFound),
+            };
+            let mut fs = USTARFileSystem::open(partition::find(device)?);
+            fs.init()?;
+            Ok((Arc::new(Mutex::new(fs)), device.to_string()))
+        },
+        // Every tmpfs is a new one, and it's not on anything
+        "tmpfs" => {
+            if device.is_some() {
+                return Err(FsError::InvalidArgument);
+            }
+            Ok((Arc::new(Mutex::new(TmpFileSystem::new())), "none".to_string()))
+        },
+        "procfs" => {
+            if device.is_some() {
+                return Err(FsError::InvalidArgument);
+            }
+            Ok((Arc::new(Mutex::new(ProcFileSystem::new())), "none".to_string()))
+        },
+        "devfs" => {
+            if device.is_some() {
+                return Err(FsError::InvalidArgument);
+            }
+            Ok((Arc::new(Mutex::new(DevFileSystem::new())), "none".to_string()))
+        },
+        _ => Err(FsError::Unsupported),
+    }
+}
+
+struct Mount {
+    // Absolute, with no / on the end (except for / itself)
+    path: String,
+    kind: String,
+    // The disk or partition it's on
+    device: String,
+    fs: Arc<Mutex<dyn FileSystem + Send>>,
+}
+
+pub struct Vfs {
+    mounts: Vec<Mount>,
+    // Everyone that has a current directory, as an absolute path
+    current_dirs: HashMap<u64, String>,
+    current_dirs_tracker: u64,
+    // Everyone's open files, by the same id as their current directory - only ids from get_id can open anything
+    descriptors: HashMap<u64, DescriptorTable>,
+}
+
+// Gets the last part of a path
+fn short_name(path: &str) -> String {
+    match path.rsplit('/').next() {
+        Some(name) => name.to_string(),
+        None => String::new(),
+    }
+}
+
+// Sticks name onto the end of the directory dir
+fn join(dir: &str, name: &str) -> String {
+    if dir == "/" {
+        format!("/{}", name)
+    }
+    else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+// Is path the directory dir, or something inside of it
+fn is_inside(path: &str, dir: &str) -> bool {
+    dir == "/" || path == dir || (path.starts_with(dir) && path[dir.len()..].starts_with('/'))
+}
+
+impl Vfs {
+    fn new() -> Vfs {
+        let mut vfs = Vfs {
+            mounts: Vec::new(),
+            current_dirs: HashMap::new(),
+            current_dirs_tracker: 1,
+            descriptors: HashMap::new(),
+        };
+        // The disk is always at the root - without one, there's still somewhere to put things until the next reboot
+        match new_filesystem("ustar", None) {
+            Ok((fs, device)) => vfs.mounts.push(Mount { path: "/".to_string(), kind: "ustar".to_string(), device, fs }),
+            Err(_) => {
+                if let Ok((fs, device)) = new_filesystem("tmpfs", None) {
+                    vfs.mounts.push(Mount { path: "/".to_string(), kind: "tmpfs".to_string(), device, fs });
+                }
+            },
+        }
+        // Scratch space that doesn't touch the disk
+        if let Ok((fs, device)) = new_filesystem("tmpfs", None) {
+            vfs.mounts.push(Mount { path: "/tmp".to_string(), kind: "tmpfs".to_string(), device, fs });
+        }
+        // What the kernel is up to
+        if let Ok((fs, device)) = new_filesystem("procfs", None) {
+            vfs.mounts.push(Mount { path: "/proc".to_string(), kind: "procfs".to_string(), device, fs });
+        }
+        // The hardware, as files
+        if let Ok((fs, device)) = new_filesystem("devfs", None) {
+            vfs.mounts.push(Mount { path: "/dev".to_string(), kind: "devfs".to_string(), device, fs });
+        }
+        vfs
+    }
+
+    pub fn get_id(&mut self) -> u64 {
+        self.current_dirs_tracker += 1;
+        self.current_dirs.insert(self.current_dirs_tracker, "/".to_string());
+        self.current_dirs_tracker
+    }
+
+    // Done with id - whatever it still has open gets closed, and it can't open anything else
+    pub fn release_id(&mut self, id: u64) -> Result<(), FsError> {
+        let mut result = Ok(());
+        if let Some(mut table) = self.descriptors.remove(&id) {
+            for file in table.iter_mut() {
+                if let (true, Some(data)) = (file.dirty, &file.data) {
+                    if let (Err(err), Ok(())) = (self.write_absolute(&file.path, data.clone()), result) {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+        self.current_dirs.remove(&id);
+        result
+    }
+
+    // The current directory, ending with a /
+    pub fn cwd(&self, id: u64) -> String {
+        match self.current_dirs.get(&id) {
+            Some(dir) if dir != "/" => format!("{}/", dir),
+            _ => "/".to_string(),
+        }
+    }
+
+    // Turns path into an absolute path with no . or .. in it
+    // Relative paths start from the current directory of id, or the root if there isn't one
+    fn absolute(&self, path: &str, id: Option<u64>) -> String {
+        let full = if path.starts_with('/') {
+            path.to_string()
+        }
+        else {
+            let cwd = match id {
+                Some(id) => self.cwd(id),
+                None => "/".to_string(),
+            };
+            format!("{}{}", cwd, path)
+        };
+        let mut parts = Vec::new();
+        for i in full.split('/') {
+            match i {
+                "" | "." => {},
+                ".." => {
+                    parts.pop();
+                },
+                _ => parts.push(i),
+            }
+        }
+        format!("/{}", parts.join("/"))
+    }
+
+    // Finds the mount that path is on - the one closest to path wins when they're inside of each other
+    fn mount_for(&self, path: &str) -> &Mount {
+        let mut best = &self.mounts[0];
+        for i in self.mounts.iter() {
+            if is_inside(path, &i.path) && i.path.len() > best.path.len() {
+                best = i;
+            }
+        }
+        best
+    }
+
+    // The file system path is on, and where it is inside of that file system
+    fn route(&self, path: &str) -> (Arc<Mutex<dyn FileSystem + Send>>, String) {
+        let mount = self.mount_for(path);
+        let inner = if mount.path == "/" {
+            path.to_string()
+        }
+        else if path.len() == mount.path.len() {
+            "/".to_string()
+        }
+        else {
+            path[mount.path.len()..].to_string()
+        };
+        (Arc::clone(&mount.fs), inner)
+    }
+
+    fn is_mount_point(&self, path: &str) -> bool {
+        self.mounts.iter().any(|m| m.path == path)
+    }
+
+    // Checks if a mount point is path or somewhere under it
+    fn has_mount_inside(&self, path: &str) -> bool {
+        self.mounts.iter().any(|m| m.path != "/" && is_inside(&m.path, path))
+    }
+
+    fn lookup_absolute(&self, path: &str) -> Result<FileType, FsError> {
+        let (fs, inner) = self.route(path);
+        let result = fs.lock().lookup(&inner);
+        result
+    }
+
+    fn stat_absolute(&self, path: &str) -> Result<Stat, FsError> {
+        let (fs, inner) = self.route(path);
+        let result = fs.lock().stat(&inner);
+        result
+    }
+
+    // The archives that are mounted on device, on a partition of it, or on the disk it's a partition of
+    fn mounted_on(&self, device: &str) -> Vec<Arc<Mutex<dyn FileSystem + Send>>> {
+        let mut found: Vec<Arc<Mutex<dyn FileSystem + Send>>> = Vec::new();
+        for i in self.mounts.iter().filter(|m| m.kind == "ustar" && partition::overlap(&m.device, device)) {
+            found.push(Arc::clone(&i.fs));
+        }
+        if let Some(root) = USTARFS.as_ref() {
+            if partition::overlap(root.lock().device(), device) {
+                found.push(Arc::clone(root) as Arc<Mutex<dyn FileSystem + Send>>);
+            }
+        }
+        found
+    }
+
+    // A mounted archive keeps blocks in its cache that haven't been written yet, so those go out before the disk
+    // under it gets read raw - and nothing gets written raw under it, since the archive would never see it
+    fn check_disk(&self, path: &str, writing: bool) -> Result<(), FsError> {
+        let mount = self.mount_for(path);
+        if mount.kind != "devfs" || path.len() <= mount.path.len() {
+            return Ok(());
+        }
+        let device = path[mount.path.len()..].trim_start_matches('/');
+        let mounted = self.mounted_on(device);
+        if writing && !mounted.is_empty() {
+            return Err(FsError::Busy);
+        }
+        for fs in mounted {
+            fs.lock().sync()?;
+        }
+        Ok(())
+    }
+
+    fn read_absolute(&self, path: &str) -> Result<Vec<u8>, FsError> {
+        self.check_disk(path, false)?;
+        let (fs, inner) = self.route(path);
+        let result = fs.lock().read(&inner);
+        result
+    }
+
+    fn write_absolute(&self, path: &str, data: Vec<u8>) -> Result<(), FsError> {
+        self.check_disk(path, true)?;
+        let (fs, inner) = self.route(path);
+        let result = fs.lock().write(&inner, data);
+        result
+    }
+
+    fn readdir_absolute(&self, path: &str) -> Result<Vec<Stat>, FsError> {
+        let (fs, inner) = self.route(path);
+        let result = fs.lock().readdir(&inner);
+        let mut result = result?;
+        // Mount points show up as directories even if there's nothing under them on the file system they're on
+        for i in self.mounts.iter() {
+            if i.path == "/" || i.path == path || !is_inside(&i.path, path) || i.path[path.len()..].trim_start_matches('/').contains('/') {
+                continue;
+            }
+            let name = short_name(&i.path);
+            let stat = i.fs.lock().stat("/");
+            if let Ok(mut stat) = stat {
+                stat.name = name.to_string();
+                result.retain(|s| s.name != name);
+                result.push(stat);
+            }
+        }
+        Ok(result)
+    }
+
+    fn mkdir_absolute(&self, path: &str) -> Result<(), FsError> {
+        let (fs, inner) = self.route(path);
+        let result = fs.lock().mkdir(&inner);
+        result
+    }
+
+    fn unlink_absolute(&self, path: &str) -> Result<(), FsError> {
+        let (fs, inner) = self.route(path);
+        let result = fs.lock().unlink(&inner);
+        result
+    }
+
+    fn rmdir_absolute(&self, path: &str) -> Result<(), FsError> {
+        if self.has_mount_inside(path) {
+            return Err(FsError::Busy);
+        }
+        let (fs, inner) = self.route(path);
+        let result = fs.lock().rmdir(&inner);
+        result
+    }
+
+    fn link_absolute(&self, target: &str, link: &str, symbolic: bool) -> Result<(), FsError> {
+        let (fs, inner) = self.route(link);
+        let result = fs.lock().link(target, &inner, symbolic);
+        result
+    }
+
+    fn read_link_absolute(&self, path: &str) -> Result<String, FsError> {
+        let (fs, inner) = self.route(path);
+        let result = fs.lock().read_link(&inner);
+        result
+    }
+
+    // Where something called name ends up when it goes to path - inside of path if it's a directory, otherwise path itself
+    fn destination(&self, path: &str, name: &str) -> String {
+        match self.stat_absolute(path) {
+            Ok(stat) if stat.file_type == FileType::Directory => join(path, name),
+            _ => path.to_string(),
+        }
+    }
+
+    // Copies from to to, which can be on different file systems
+    // follow is whether a symlink at from gets copied as what it points at, or as a link
+    fn copy_absolute(&self, from: &str, to: &str, follow: bool) -> Result<(), FsError> {
+        let file_type = if follow {
+            self.stat_absolute(from)?.file_type
+        }
+        else {
+            self.lookup_absolute(from)?
+        };
+        match file_type {
+            // A device gets copied as what's on it
+            FileType::File | FileType::CharDevice | FileType::BlockDevice => {
+                let data = self.read_absolute(from)?;
+                self.write_absolute(to, data)
+            },
+            FileType::Symlink => {
+                let target = self.read_link_absolute(from)?;
+                self.link_absolute(&target, to, true)
+            },
+            FileType::Directory => {
+                // Copying a directory into itself would never end
+                if is_inside(to, from) {
+                    return Err(FsError::InvalidArgument);
+                }
+                let contents = self.readdir_absolute(from)?;
+                self.mkdir_absolute(to)?;
+                // Keeps going after something fails, so as much as possible gets copied
+                let mut result = Ok(());
+                for i in contents.iter() {
+                    if let Err(err) = self.copy_absolute(&join(from, &i.name), &join(to, &i.name), false) {
+                        result = Err(err);
+                    }
+                }
+                result
+            },
+        }
+    }
+
+    pub fn change_directory(&mut self, directory: String, id: u64) -> Result<(), FsError> {
+        let path = self.absolute(&directory, Some(id));
+        // Going into a directory needs execute permission on it
+        let stat = self.stat_absolute(&path)?;
+        if stat.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let permissions = stat.permissions;
+        if !users::can_access(permissions.mode, permissions.owner_id, permissions.group_id, users::EXECUTE) {
+            return Err(FsError::PermissionDenied);
+        }
+        self.current_dirs.insert(id, path);
+        Ok(())
+    }
+
+    pub fn stat(&self, path: String, id: Option<u64>) -> Result<Stat, FsError> {
+        self.stat_absolute(&self.absolute(&path, id))
+    }
+
+    pub fn read_file(&self, file: String, id: Option<u64>) -> Result<Vec<u8>, FsError> {
+        self.read_absolute(&self.absolute(&file, id))
+    }
+
+    // Replaces what's in a file, making it if it doesn't exist
+    pub fn write_file(&self, file: String, data: Vec<u8>, id: Option<u64>) -> Result<(), FsError> {
+        self.write_absolute(&self.absolute(&file, id), data)
+    }
+
+    // Removing a link only removes the link, not what it points at
+    pub fn remove_file(&self, file: String, id: Option<u64>) -> Result<(), FsError> {
+        self.unlink_absolute(&self.absolute(&file, id))
+    }
+
+    pub fn create_directory(&self, directory: String, id: Option<u64>) -> Result<(), FsError> {
+        let path = self.absolute(&directory, id);
+        if self.lookup_absolute(&path).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        self.mkdir_absolute(&path)
+    }
+
+    // Removes a directory and everything in it - mount points and anything with one inside can't be removed
+    pub fn remove_directory(&self, directory: String, id: Option<u64>) -> Result<(), FsError> {
+        self.rmdir_absolute(&self.absolute(&directory, id))
+    }
+
+    // Everything in a directory, files first, then directories
+    pub fn list_directory(&self, directory: String, id: Option<u64>) -> Result<Vec<Stat>, FsError> {
+        self.readdir_absolute(&self.absolute(&directory, id))
+    }
+
+    // Moves a file or directory, new_path can either be a directory to put it in or the new path for it
+    // Moving onto a different file system copies it over and then removes the old one
+    pub fn move_item(&self, path: String, new_path: String, id: Option<u64>) -> Result<(), FsError> {
+        let from = self.absolute(&path, id);
+        let file_type = self.lookup_absolute(&from)?;
+        let to = self.destination(&self.absolute(&new_path, id), &short_name(&from));
+        if from == "/" || self.has_mount_inside(&from) {
+            return Err(FsError::Busy);
+        }
+        if self.lookup_absolute(&to).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        if self.mount_for(&from).path == self.mount_for(&to).path {
+            let (fs, from_inner) = self.route(&from);
+            let (_, to_inner) = self.route(&to);
+            let result = fs.lock().rename(&from_inner, &to_inner);
+            return result;
+        }
+        self.copy_absolute(&from, &to, false)?;
+        match file_type {
+            FileType::Directory => self.rmdir_absolute(&from),
+            _ => self.unlink_absolute(&from),
+        }
+    }
+
+    // Copies a file, or a directory and everything in it if recursive is set
+    // new_path works the same way as it does for move_item
+    pub fn copy(&self, path: String, new_path: String, recursive: bool, id: Option<u64>) -> Result<(), FsError> {
+        let from = self.absolute(&path, id);
+        let file_type = self.stat_absolute(&from)?.file_type;
+        if file_type == FileType::Directory && !recursive {
+            return Err(FsError::IsADirectory);
+        }
+        let to = self.destination(&self.absolute(&new_path, id), &short_name(&from));
+        if self.lookup_absolute(&to).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        self.copy_absolute(&from, &to, true)
+    }
+
+    // Makes a link at link_path that points at target, link_path can be a directory to put it in
+    // Symlinks keep the path they were given, hard links have to be on the same file system as what they point at
+    pub fn create_link(&self, target: String, link_path: String, symbolic: bool, id: Option<u64>) -> Result<(), FsError> {
+        let link = self.destination(&self.absolute(&link_path, id), &short_name(&self.absolute(&target, id)));
+        if symbolic {
+            return self.link_absolute(&target, &link, true);
+        }
+        let target = self.absolute(&target, id);
+        if self.mount_for(&target).path != self.mount_for(&link).path {
+            return Err(FsError::Unsupported);
+        }
+        let (fs, target_inner) = self.route(&target);
+        let (_, link_inner) = self.route(&link);
+        let result = fs.lock().link(&target_inner, &link_inner, false);
+        result
+    }
+
+    pub fn chmod(&self, path: String, mode: u64, id: Option<u64>) -> Result<(), FsError> {
+        let (fs, inner) = self.route(&self.absolute(&path, id));
+        let result = fs.lock().chmod(&inner, mode);
+        result
+    }
+
+    pub fn chown(&self, path: String, owner_id: u64, id: Option<u64>) -> Result<(), FsError> {
+        let (fs, inner) = self.route(&self.absolute(&path, id));
+        let result = fs.lock().chown(&inner, owner_id);
+        result
+    }
+
+    pub fn chgrp(&self, path: String, group_id: u64, id: Option<u64>) -> Result<(), FsError> {
+        let (fs, inner) = self.route(&self.absolute(&path, id));
+        let result = fs.lock().chgrp(&inner, group_id);
+        result
+    }
+
+    // Writes out everything that's mounted, and anything written to a file that's still open
+    // Everything gets a go even if something fails, and the first thing that failed is what comes back
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        let mut result = self.flush_descriptors();
+        for i in self.mounts.iter() {
+            if let (Err(err), Ok(())) = (i.fs.lock().sync(), result) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    // Same as sync, except the archive at / only gets as far as its cache - the write-back task takes it from there, so
+    // this doesn't have to wait for the disk
+    pub fn start_sync(&mut self) -> Result<(), FsError> {
+        let root = USTARFS.as_ref();
+        let root_device = root.map(|root| root.lock().device().to_string());
+        let mut result = self.flush_descriptors();
+        for i in self.mounts.iter() {
+            if i.kind == "ustar" && Some(&i.device) == root_device.as_ref() {
+                continue;
+            }
+            if let (Err(err), Ok(())) = (i.fs.lock().sync(), result) {
+                result = Err(err);
+            }
+        }
+        if let Some(root) = root {
+            if let (Err(err), Ok(())) = (root.lock().write(), result) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    // Writes anything written to a file that's still open onto its file system
+    fn flush_descriptors(&mut self) -> Result<(), FsError> {
+        let mut pending = Vec::new();
+        for table in self.descriptors.values_mut() {
+            for file in table.iter_mut() {
+                if let (true, Some(data)) = (file.dirty, &file.data) {
+                    pending.push((file.path.to_string(), data.clone()));
+                    file.dirty = false;
+                }
+            }
+        }
+        let mut result = Ok(());
+        for (path, data) in pending {
+            if let (Err(err), Ok(())) = (self.write_absolute(&path, data), result) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    // The open files of id, which has to have come from get_id
+    fn table(&mut self, id: u64) -> Result<&mut DescriptorTable, FsError> {
+        if !self.current_dirs.contains_key(&id) {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(self.descriptors.entry(id).or_insert_with(DescriptorTable::new))
+    }
+
+    // Opens a file with flags from fd (it has to have OPEN_READ and/or OPEN_WRITE) and gives back its descriptor
+    // Only the owner of id can use the descriptor
+    pub fn open(&mut self, path: String, flags: u64, id: u64) -> Result<usize, FsError> {
+        let path = self.absolute(&path, Some(id));
+        self.table(id)?;
+        let writing = flags & OPEN_WRITE != 0;
+        if flags & (OPEN_READ | OPEN_WRITE) == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut device = false;
+        match self.stat_absolute(&path) {
+            Ok(stat) => {
+                if stat.file_type == FileType::Directory {
+                    return Err(FsError::IsADirectory);
+                }
+                device = stat.file_type == FileType::CharDevice || stat.file_type == FileType::BlockDevice;
+                let mut access = 0;
+                if flags & OPEN_READ != 0 {
+                    access |= users::READ;
+                }
+                if writing {
+                    access |= users::WRITE;
+                }
+                let permissions = stat.permissions;
+                if !users::can_access(permissions.mode, permissions.owner_id, permissions.group_id, access) {
+                    return Err(FsError::PermissionDenied);
+                }
+            },
+            Err(FsError::NotFound) if writing && flags & OPEN_CREATE != 0 => {
+                self.write_absolute(&path, Vec::new())?;
+            },
+            Err(err) => return Err(err),
+        }
+        let file = if device {
+            OpenFile::for_device(path, flags)
+        }
+        else {
+            OpenFile::new(path, flags)
+        };
+        Ok(self.table(id)?.insert(file))
+    }
+
+    // Reads into buf from where the descriptor is, and moves it past what was read
+    // Gives back how much was read, which is 0 at the end of the file
+    pub fn read(&mut self, fd: usize, buf: &mut [u8], id: u64) -> Result<usize, FsError> {
+        let (path, offset) = {
+            let file = match self.table(id)?.get(fd) {
+                Some(file) if file.can_read() => file,
+                _ => return Err(FsError::InvalidArgument),
+            };
+            // Whatever's been written but not closed yet is the newest version of the file
+            if let Some(count) = file.read_buffered(buf) {
+                return Ok(count);
+            }
+            (file.path.to_string(), file.offset)
+        };
+        self.check_disk(&path, false)?;
+        let (fs, inner) = self.route(&path);
+        let count = fs.lock().read_at(&inner, offset, buf)?;
+        match self.table(id)?.get(fd) {
+            Some(file) => {
+                file.offset += count as u64;
+                Ok(count)
+            },
+            None => Err(FsError::InvalidArgument),
+        }
+    }
+
+    // Writes data where the descriptor is (or at the end for OPEN_APPEND), and moves it past what was written
+    // It goes straight to the file system, so only the part that changed gets written
+    pub fn write(&mut self, fd: usize, data: &[u8], id: u64) -> Result<usize, FsError> {
+        let (path, device, offset, append) = {
+            let file = match self.table(id)?.get(fd) {
+                Some(file) if file.can_write() => file,
+                _ => return Err(FsError::InvalidArgument),
+            };
+            if file.data.is_some() {
+                return Ok(file.write_buffered(data));
+            }
+            (file.path.to_string(), file.device, file.offset, file.flags & OPEN_APPEND != 0)
+        };
+        self.check_disk(&path, true)?;
+        let (fs, inner) = self.route(&path);
+        let offset = if append && !device { fs.lock().stat(&inner)?.size } else { offset };
+        let result = fs.lock().write_at(&inner, offset, data);
+        match result {
+            Ok(count) => {
+                if let Some(file) = self.table(id)?.get(fd) {
+                    file.offset = offset + count as u64;
+                }
+                Ok(count)
+            },
+            // The file system can't write part of a file, so the whole thing gets held onto until it's closed
+            Err(FsError::Unsupported) if !device => {
+                let contents = self.read_absolute(&path)?;
+                match self.table(id)?.get(fd) {
+                    Some(file) => {
+                        file.data = Some(contents);
+                        Ok(file.write_buffered(data))
+                    },
+                    None => Err(FsError::InvalidArgument),
+                }
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    // Moves the descriptor, and gives back where it ended up
+    pub fn seek(&mut self, fd: usize, position: SeekFrom, id: u64) -> Result<u64, FsError> {
+        let (path, buffered_size) = match self.table(id)?.get(fd) {
+            Some(file) => (file.path.to_string(), file.data.as_ref().map(|data| data.len() as u64)),
+            None => return Err(FsError::InvalidArgument),
+        };
+        let size = match buffered_size {
+            Some(size) => size,
+            None => self.stat_absolute(&path)?.size,
+        };
+        match self.table(id)?.get(fd).and_then(|file| file.seek(position, size)) {
+            Some(offset) => Ok(offset),
+            None => Err(FsError::InvalidArgument),
+        }
+    }
+
+    // Closes a descriptor, writing back anything that was written to it
+    // If that can't be written it stays open, so nothing is lost and it can be tried again
+    pub fn close(&mut self, fd: usize, id: u64) -> Result<(), FsError> {
+        let pending = match self.table(id)?.get(fd) {
+            Some(file) => match (file.dirty, &file.data) {
+                (true, Some(data)) => Some((file.path.to_string(), data.clone())),
+                _ => None,
+            },
+            None => return Err(FsError::InvalidArgument),
+        };
+        if let Some((path, data)) = pending {
+            self.write_absolute(&path, data)?;
+        }
+        self.table(id)?.remove(fd);
+        Ok(())
+    }
+
+    pub fn defragment(&self) -> Result<(), FsError> {
+        for i in self.mounts.iter() {
+            i.fs.lock().defragment()?;
+        }
+        Ok(())
+    }
+
+    // Everything in the trash on every file system, with the full paths they were at
+    pub fn trash(&self) -> Result<Vec<TrashEntry>, FsError> {
+        let mut result = Vec::new();
+        for (i, mount) in self.mounts.iter().enumerate() {
+            // A file system that's mounted twice only gets listed once
+            if self.mounts[..i].iter().any(|m| Arc::ptr_eq(&m.fs, &mount.fs)) {
+                continue;
+            }
+            let entries = mount.fs.lock().trash()?;
+            for mut entry in entries {
+                if mount.path != "/" {
+                    entry.path = format!("{}{}", mount.path, entry.path);
+                }
+                result.push(entry);
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn undelete(&self, path: String, id: Option<u64>) -> Result<(), FsError> {
+        let (fs, inner) = self.route(&self.absolute(&path, id));
+        let result = fs.lock().undelete(&inner);
+        result
+    }
+
+    pub fn empty_trash(&self) -> Result<(), FsError> {
+        for i in self.mounts.iter() {
+            i.fs.lock().empty_trash()?;
+        }
+        Ok(())
+    }
+
+    // Checks the file system that path is on - anything still open gets written out first, so that's checked too
+    pub fn fsck(&mut self, path: String, repair: bool, id: Option<u64>) -> Result<Vec<String>, FsError> {
+        self.flush_descriptors()?;
+        let (fs, _) = self.route(&self.absolute(&path, id));
+        let result = fs.lock().fsck(repair);
+        result
+    }
+
+    // Mounts a new file system of type kind at path, which can't already be a mount point or a file
+    // device picks the disk or partition for it, otherwise it's the one at /
+    pub fn mount(&mut self, kind: &str, path: String, device: Option<&str>, id: Option<u64>) -> Result<(), FsError> {
+        let path = self.absolute(&path, id);
+        if self.is_mount_point(&path) {
+            return Err(FsError::Busy);
+        }
+        match self.lookup_absolute(&path) {
+            Ok(FileType::Directory) | Err(FsError::NotFound) => {},
+            Ok(_) => return Err(FsError::NotADirectory),
+            Err(err) => return Err(err),
+        }
+        // A device that's already mounted somewhere is shared, for the same reason the one at / is
+        let existing = self.mounts.iter().find(|m| m.kind == kind && Some(m.device.as_str()) == device);
+        let (fs, device) = match existing {
+            Some(mount) => (Arc::clone(&mount.fs), mount.device.to_string()),
+            None => new_filesystem(kind, device)?,
+        };
+        self.mounts.push(Mount { path, kind: kind.to_string(), device, fs });
+        Ok(())
+    }
+
+    // The root can't be unmounted, and neither can anything that something else is mounted inside of or that
+    // someone is currently in
+    pub fn umount(&mut self, path: String, id: Option<u64>) -> Result<(), FsError> {
+        let path = self.absolute(&path, id);
+        if path == "/" {
+            return Err(FsError::Busy);
+        }
+        let index = match self.mounts.iter().position(|m| m.path == path) {
+            Some(index) => index,
+            None => return Err(FsError::InvalidArgument),
+        };
+        if self.mounts.iter().any(|m| m.path != path && is_inside(&m.path, &path)) {
+            return Err(FsError::Busy);
+        }
+        if self.current_dirs.values().any(|dir| is_inside(dir, &path)) {
+            return Err(FsError::Busy);
+        }
+        if self.descriptors.values_mut().any(|table| table.iter_mut().any(|file| is_inside(&file.path, &path))) {
+            return Err(FsError::Busy);
+        }
+        // Whatever didn't make it onto the disk would be lost, so it stays mounted
+        self.mounts[index].fs.lock().sync()?;
+        self.mounts.remove(index);
+        Ok(())
+    }
+
+    // Makes device into an empty archive, so it can be mounted - nothing on it or on any of its partitions can be
+    // mounted, since the file system would still think its files are there
+    pub fn mkfs(&mut self, device: &str) -> Result<(), FsError> {
+        if !self.mounted_on(device).is_empty() {
+            return Err(FsError::Busy);
+        }
+        USTARFileSystem::open(partition::find(device)?).format()
+    }
+
+    // What's mounted where, as (type, device, path)
+    pub fn mounts(&self) -> Vec<(String, String, String)> {
+        self.mounts.iter().map(|m| (m.kind.to_string(), m.device.to_string(), m.path.to_string())).collect()
+    }
+}
+
+lazy_static! {
+    pub static ref VFS: Mutex<Vfs> = {
+        Mutex::new(Vfs::new())
+    };
+}
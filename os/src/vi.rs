@@ -4,7 +4,8 @@ use crate::vga_buffer::ADVANCED_WRITER;
 use vga::colors::Color16;
 use alloc::vec::Vec;
 use alloc::string::String;
-use crate::ustar::USTARFS;
+use crate::vfs::{FsError, VFS};
+use crate::fd::{OPEN_WRITE, OPEN_CREATE, OPEN_TRUNCATE};
 use crate::println;
 use crate::keyboard_routing::KEYBOARD_ROUTER;
 use crate::timer_routing::TIME_ROUTER;
@@ -133,18 +134,20 @@ impl FakeVim {
                             TIME_ROUTER.lock().mode.vim = false;
                             ADVANCED_WRITER.lock().enable_blink();
                             println!();
-                            print!("[user@rust {}]# ", USTARFS.lock().cwd(COMMANDRUNNER.lock().dir_id));
+                            print!("[user@rust {}]# ", VFS.lock().cwd(COMMANDRUNNER.lock().dir_id));
                         });
                         return
                     },
                     'e' => {
-                        if let Some(data) = USTARFS.lock().read_file(self.filename.to_string(), self.id) {
+                        if let Ok(data) = VFS.lock().read_file(self.filename.to_string(), self.id) {
                             self.data = data;
                         }
                     }
                     'w' => {
-                        USTARFS.lock().remove_file(self.filename.to_string(), self.id);
-                        USTARFS.lock().write_file(self.filename.to_string(), self.data.clone(), self.id);
+                        if let Err(err) = self.save() {
+                            ADVANCED_WRITER.lock().clear_buffer();
+                            println!("Couldn't save {}: {}", self.filename, err);
+                        }
                     },
                     _ => {
 
@@ -338,7 +341,7 @@ impl FakeVim {
     }
 
     pub fn init(&mut self, file: String, id: Option<u64>) {
-        if let Some(data) = USTARFS.lock().read_file(file.to_string(), id) {
+        if let Ok(data) = VFS.lock().read_file(file.to_string(), id) {
             self.data = data;
             // Init the keyboard stuff
             interrupts::without_interrupts(|| {
@@ -372,6 +375,16 @@ impl FakeVim {
         }
     }
 
+    // Writes the whole buffer over the file
+    // The descriptor belongs to the shell that opened vi, so there has to be one
+    fn save(&self) -> Result<(), FsError> {
+        let id = self.id.ok_or(FsError::InvalidArgument)?;
+        let mut vfs = VFS.lock();
+        let fd = vfs.open(self.filename.to_string(), OPEN_WRITE | OPEN_CREATE | OPEN_TRUNCATE, id)?;
+        vfs.write(fd, &self.data, id)?;
+        vfs.close(fd, id)
+    }
+
     pub fn render_buffer(&mut self) {
         // Use the buffer for diffs, and render directly off the vector
         let mut x = 0;
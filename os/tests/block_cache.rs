@@ -0,0 +1,137 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::allocator;
+use os::block_cache::BlockCache;
+use os::block_device::BlockDevice;
+use os::vfs::FsError;
+use os::memory::{self, BootInfoFrameAllocator};
+use x86_64::VirtAddr;
+use alloc::vec;
+use common::{Event, FaultyDisk};
+
+mod common;
+
+// defines entry point for test and initializes the heap
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+// defines panic funtion for test
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
+
+// a block only gets read from the disk once
+#[test_case]
+fn reads_are_cached() {
+    let mut cache = BlockCache::new(FaultyDisk::new(16), 8);
+    assert_eq!(cache.read_block(3), Ok(vec![0; 512]));
+    assert_eq!(cache.read_block(3), Ok(vec![0; 512]));
+    // the blocks around it come in one read, and then they're all there too
+    let mut buf = vec![0; 512 * 3];
+    cache.read_blocks(2, &mut buf).unwrap();
+    cache.read_blocks(2, &mut buf).unwrap();
+    assert_eq!(cache.device().log, vec![Event::Read(3, 1), Event::Read(2, 3)]);
+}
+
+// writes stay in memory until a sync, and writing what's already there doesn't count
+#[test_case]
+fn writes_wait_for_sync() {
+    let mut cache = BlockCache::new(FaultyDisk::new(16), 8);
+    cache.write_blocks(5, &[1; 1024]).unwrap();
+    cache.read_block(9).unwrap();
+    cache.write_blocks(9, &[0; 512]).unwrap();
+    assert_eq!(cache.read_block(6), Ok(vec![1; 512]));
+    assert_eq!(cache.dirty_blocks(), 2);
+    assert_eq!(cache.device().disk.bytes()[5 * 512], 0);
+
+    cache.sync().unwrap();
+    assert_eq!(cache.dirty_blocks(), 0);
+    assert_eq!(cache.device().disk.bytes()[6 * 512], 1);
+    // the two blocks next to each other go out together
+    assert!(cache.device().log.contains(&Event::Write(5, 2)));
+}
+
+// whatever was written before a flush gets to the disk first, even when it's written again afterwards
+#[test_case]
+fn flushes_keep_their_order() {
+    let mut cache = BlockCache::new(FaultyDisk::new(16), 8);
+    cache.write_blocks(7, &[1; 512]).unwrap();
+    cache.flush().unwrap();
+    cache.write_blocks(2, &[2; 512]).unwrap();
+    cache.write_blocks(7, &[3; 512]).unwrap();
+    assert!(cache.device().log.is_empty());
+
+    cache.sync().unwrap();
+    assert_eq!(cache.device().log, vec![
+        Event::Write(7, 1), Event::Flush,
+        Event::Write(2, 1), Event::Write(7, 1), Event::Flush,
+        // and the sync at the end
+        Event::Flush,
+    ]);
+    assert_eq!(cache.read_block(7), Ok(vec![3; 512]));
+}
+
+// too many writes for the cache to hold means some of them have to go out early
+#[test_case]
+fn full_cache_writes_out() {
+    let mut cache = BlockCache::new(FaultyDisk::new(16), 4);
+    for block in 0..6 {
+        cache.write_blocks(block, &[block as u8 + 1; 512]).unwrap();
+        cache.flush().unwrap();
+    }
+    assert!(cache.dirty_blocks() <= 4);
+    assert_eq!(cache.device().disk.bytes()[0], 1);
+    for block in 0..6 {
+        assert_eq!(cache.read_block(block), Ok(vec![block as u8 + 1; 512]));
+    }
+}
+
+// the write-back task gets the oldest batch, and it's only forgotten about once that says it made it
+#[test_case]
+fn write_back_hands_out_the_oldest_batch() {
+    let mut cache = BlockCache::new(FaultyDisk::new(16), 8);
+    assert_eq!(cache.start_write_back(), None);
+    cache.write_blocks(3, &[1; 1024]).unwrap();
+    cache.write_blocks(8, &[2; 512]).unwrap();
+    assert_eq!(cache.start_write_back(), Some(vec![(3, vec![1; 1024]), (8, vec![2; 512])]));
+    // it's still there for reads, nothing else can write it, and new writes go in the next batch
+    assert_eq!(cache.start_write_back(), None);
+    assert_eq!(cache.sync(), Err(FsError::Busy));
+    cache.write_blocks(3, &[4; 512]).unwrap();
+    assert_eq!(cache.read_block(3), Ok(vec![4; 512]));
+    assert_eq!(cache.read_block(4), Ok(vec![1; 512]));
+
+    // it didn't make it, so it's handed out again
+    assert_eq!(cache.finish_write_back(Err(FsError::Io)), Err(FsError::Io));
+    assert_eq!(cache.dirty_blocks(), 4);
+    assert_eq!(cache.start_write_back(), Some(vec![(3, vec![1; 1024]), (8, vec![2; 512])]));
+    assert_eq!(cache.finish_write_back(Ok(())), Ok(()));
+    assert_eq!(cache.dirty_blocks(), 1);
+    assert_eq!(cache.read_block(3), Ok(vec![4; 512]));
+    assert_eq!(cache.start_write_back(), Some(vec![(3, vec![4; 512])]));
+    assert_eq!(cache.finish_write_back(Ok(())), Ok(()));
+    assert_eq!(cache.dirty_blocks(), 0);
+    // none of it went through the device here
+    assert!(cache.device().log.is_empty());
+}
@@ -0,0 +1,134 @@
+// disks for the tests that need one to go wrong on purpose, or to say what was done to it
+// pulled into a test file with `mod common;` - not every file uses all of it
+#![allow(dead_code)]
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use os::block_device::{check_range, BlockDevice, RamDisk};
+use os::vfs::FsError;
+
+// what a FaultyDisk was asked to do, in order
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Read(u64, usize),
+    Write(u64, usize),
+    Flush,
+}
+
+// wraps a disk, writing down everything that happens to it, and losing power after a set number of block writes
+// once the power is out every write fails, and nothing more reaches the disk
+pub struct FaultyDisk<D: BlockDevice = RamDisk> {
+    pub disk: D,
+    // None is a disk that never loses power
+    pub writes_left: Option<usize>,
+    pub log: Vec<Event>,
+}
+
+impl FaultyDisk<RamDisk> {
+    pub fn new(blocks: u64) -> FaultyDisk {
+        FaultyDisk::on(RamDisk::new(blocks))
+    }
+}
+
+impl<D: BlockDevice> FaultyDisk<D> {
+    pub fn on(disk: D) -> FaultyDisk<D> {
+        FaultyDisk { disk, writes_left: None, log: Vec::new() }
+    }
+
+    // loses power after writes more block writes
+    pub fn cut_after(mut self, writes: usize) -> FaultyDisk<D> {
+        self.writes_left = Some(writes);
+        self
+    }
+
+    pub fn power_is_out(&self) -> bool {
+        self.writes_left == Some(0)
+    }
+
+    // the power comes back
+    pub fn restore_power(&mut self) {
+        self.writes_left = None;
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for FaultyDisk<D> {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.disk.block_count()
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.log.push(Event::Read(block_id, buf.len() / 512));
+        self.disk.read_blocks(block_id, buf)
+    }
+
+    fn write_blocks(&mut self, block_id: u64, data: &[u8]) -> Result<(), FsError> {
+        self.log.push(Event::Write(block_id, data.len() / 512));
+        for (i, block) in data.chunks(512).enumerate() {
+            match self.writes_left {
+                Some(0) => return Err(FsError::Io),
+                Some(n) => self.writes_left = Some(n - 1),
+                None => {},
+            }
+            self.disk.write_blocks(block_id + i as u64, block)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.log.push(Event::Flush);
+        self.disk.flush()
+    }
+}
+
+// a huge disk where only the blocks that have been written take up memory - the rest are zeros
+pub struct SparseDisk {
+    blocks: u64,
+    written: BTreeMap<u64, Vec<u8>>,
+}
+
+impl SparseDisk {
+    pub fn new(blocks: u64) -> SparseDisk {
+        SparseDisk { blocks, written: BTreeMap::new() }
+    }
+
+    pub fn written(&self, block_id: u64) -> Option<&Vec<u8>> {
+        self.written.get(&block_id)
+    }
+}
+
+impl BlockDevice for SparseDisk {
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        check_range(self, block_id, buf.len())?;
+        for (i, block) in buf.chunks_mut(512).enumerate() {
+            match self.written.get(&(block_id + i as u64)) {
+                Some(data) => block.copy_from_slice(data),
+                None => block.fill(0),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, block_id: u64, data: &[u8]) -> Result<(), FsError> {
+        check_range(self, block_id, data.len())?;
+        for (i, block) in data.chunks(512).enumerate() {
+            self.written.insert(block_id + i as u64, block.to_vec());
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::allocator;
+use os::block_device::RamDisk;
+use os::journal::{Journal, JOURNAL_BLOCKS, JOURNAL_ENTRIES};
+use os::vfs::FsError;
+use os::memory::{self, BootInfoFrameAllocator};
+use x86_64::VirtAddr;
+use alloc::vec;
+use alloc::vec::Vec;
+use common::{Event, FaultyDisk, SparseDisk};
+
+mod common;
+
+// defines entry point for test and initializes the heap
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+// defines panic funtion for test
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
+
+// a disk with every block filled with its own number, so it's easy to tell what changed
+fn numbered_disk(blocks: u64) -> FaultyDisk {
+    let mut data = Vec::new();
+    for i in 0..blocks {
+        data.extend_from_slice(&[i as u8; 512]);
+    }
+    FaultyDisk::on(RamDisk::from_bytes(data))
+}
+
+fn block(disk: &FaultyDisk, block_id: usize) -> &[u8] {
+    &disk.disk.bytes()[block_id * 512..(block_id + 1) * 512]
+}
+
+// the changes made by the transaction in the tests
+fn record_changes(journal: &mut Journal) {
+    journal.record(0, &[b'h'; 512]);
+    journal.record(3, &[b'd'; 512]);
+    journal.record(4, &[b'e'; 512]);
+    journal.record(7, &[0; 512]);
+}
+
+// a committed transaction ends up where it belongs and leaves nothing to replay
+#[test_case]
+fn commit_writes_everything() {
+    let mut disk = numbered_disk(16 + JOURNAL_BLOCKS);
+    let mut journal = Journal::at_end_of(16 + JOURNAL_BLOCKS);
+    record_changes(&mut journal);
+    assert_eq!(journal.commit(&mut disk), Ok(()));
+    assert_eq!(block(&disk, 0), &[b'h'; 512][..]);
+    assert_eq!(block(&disk, 3), &[b'd'; 512][..]);
+    assert_eq!(block(&disk, 4), &[b'e'; 512][..]);
+    assert_eq!(block(&disk, 7), &[0; 512][..]);
+    assert_eq!(block(&disk, 5), &[5; 512][..]);
+    assert_eq!(journal.replay(&mut disk), Ok(false));
+}
+
+// losing power after any write leaves the disk either untouched or fully updated once the journal is replayed
+#[test_case]
+fn crash_at_every_step() {
+    let before = numbered_disk(16 + JOURNAL_BLOCKS);
+    let mut after = numbered_disk(16 + JOURNAL_BLOCKS);
+    let mut journal = Journal::at_end_of(16 + JOURNAL_BLOCKS);
+    record_changes(&mut journal);
+    journal.commit(&mut after).unwrap();
+    let mut step = 0;
+    loop {
+        let mut disk = numbered_disk(16 + JOURNAL_BLOCKS).cut_after(step);
+        let mut journal = Journal::at_end_of(16 + JOURNAL_BLOCKS);
+        record_changes(&mut journal);
+        let finished = journal.commit(&mut disk).is_ok();
+        disk.restore_power();
+        journal.replay(&mut disk).unwrap();
+        let untouched = disk.disk.bytes()[..16 * 512] == before.disk.bytes()[..16 * 512];
+        let updated = disk.disk.bytes()[..16 * 512] == after.disk.bytes()[..16 * 512];
+        assert!(untouched || updated);
+        // once the header is written it has to go all the way through
+        if finished {
+            assert!(updated);
+            break;
+        }
+        step += 1;
+    }
+}
+
+// a transaction that doesn't fit in the journal isn't written at all, wherever the power goes out, and one that only
+// just fits is still all or nothing
+#[test_case]
+fn crash_with_a_full_journal() {
+    let blocks = JOURNAL_ENTRIES as u64 + 8;
+    let before = numbered_disk(blocks + JOURNAL_BLOCKS);
+    for cut in [Some(0), Some(JOURNAL_ENTRIES), Some(2 * JOURNAL_ENTRIES), None].iter() {
+        let mut disk = numbered_disk(blocks + JOURNAL_BLOCKS);
+        disk.writes_left = *cut;
+        let mut journal = Journal::at_end_of(blocks + JOURNAL_BLOCKS);
+        for block_id in 0..JOURNAL_ENTRIES as u64 + 1 {
+            journal.record(block_id, &[b'x'; 512]);
+        }
+        assert_eq!(journal.commit(&mut disk), Err(FsError::NoSpace));
+        disk.restore_power();
+        assert_eq!(journal.replay(&mut disk), Ok(false));
+        assert!(disk.disk.bytes() == before.disk.bytes());
+        assert!(!disk.log.iter().any(|event| matches!(event, Event::Write(_, _))));
+    }
+
+    let mut step = 0;
+    loop {
+        let mut disk = numbered_disk(blocks + JOURNAL_BLOCKS).cut_after(step);
+        let mut journal = Journal::at_end_of(blocks + JOURNAL_BLOCKS);
+        for block_id in 0..JOURNAL_ENTRIES as u64 {
+            journal.record(block_id, &[b'x'; 512]);
+        }
+        let finished = journal.commit(&mut disk).is_ok();
+        disk.restore_power();
+        journal.replay(&mut disk).unwrap();
+        let untouched = disk.disk.bytes()[..blocks as usize * 512] == before.disk.bytes()[..blocks as usize * 512];
+        let updated = (0..JOURNAL_ENTRIES).all(|id| block(&disk, id) == &[b'x'; 512][..])
+            && (JOURNAL_ENTRIES..blocks as usize).all(|id| block(&disk, id) == block(&before, id));
+        assert!(untouched || updated);
+        if finished {
+            assert!(updated);
+            break;
+        }
+        // every sector is its own write, so going one at a time would take a while
+        step += 61;
+    }
+}
+
+// block ids past what fits in 32 bits still get replayed to the right place
+#[test_case]
+fn replay_past_u32() {
+    let blocks = 1 << 34;
+    let target = (1 << 33) + 5;
+    // the sector, the table and the header make it, but the power goes out before the sector gets where it belongs
+    let mut disk = FaultyDisk::on(SparseDisk::new(blocks)).cut_after(3);
+    let mut journal = Journal::at_end_of(blocks);
+    journal.record(target, &[b'x'; 512]);
+    assert_eq!(journal.commit(&mut disk), Err(FsError::Io));
+    assert_eq!(disk.disk.written(target), None);
+
+    disk.restore_power();
+    assert_eq!(journal.replay(&mut disk), Ok(true));
+    assert_eq!(disk.disk.written(target), Some(&vec![b'x'; 512]));
+    assert_eq!(disk.disk.written(5), None);
+}
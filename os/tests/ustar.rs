@@ -0,0 +1,301 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::allocator;
+use os::block_cache::{BlockCache, CACHE_BLOCKS};
+use os::block_device::{BlockDevice, RamDisk};
+use os::journal::JOURNAL_BLOCKS;
+use os::ustar::USTARFileSystem;
+use os::vfs::{FileSystem, FileType, FsError};
+use ustar_format::Header;
+use os::memory::{self, BootInfoFrameAllocator};
+use x86_64::VirtAddr;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use common::FaultyDisk;
+
+mod common;
+
+// defines entry point for test and initializes the heap
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+// defines panic funtion for test
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
+
+// an empty file system with room for blocks blocks of files before the journal, the way mkfs leaves it
+fn formatted(blocks: u64) -> RamDisk {
+    let mut fs = USTARFileSystem::on(RamDisk::new(blocks + JOURNAL_BLOCKS));
+    fs.format().unwrap();
+    fs.into_device()
+}
+
+fn mount(disk: RamDisk) -> USTARFileSystem<RamDisk> {
+    let mut fs = USTARFileSystem::on(disk);
+    fs.init().unwrap();
+    fs
+}
+
+// what the next boot would see
+fn remount(fs: USTARFileSystem<RamDisk>) -> USTARFileSystem<RamDisk> {
+    mount(fs.into_device())
+}
+
+fn names(fs: &mut USTARFileSystem<RamDisk>, path: &str) -> Vec<String> {
+    let mut names: Vec<String> = FileSystem::readdir(fs, path).unwrap().into_iter().map(|stat| stat.name).collect();
+    names.sort();
+    names
+}
+
+// files and directories make it onto the disk and come back after a reboot
+#[test_case]
+fn write_and_read_back() {
+    let mut fs = mount(formatted(256));
+    FileSystem::write(&mut fs, "/hello.txt", b"Hello, World!".to_vec()).unwrap();
+    FileSystem::mkdir(&mut fs, "/docs").unwrap();
+    FileSystem::write(&mut fs, "/docs/big.txt", vec![b'x'; 3000]).unwrap();
+    FileSystem::write(&mut fs, "/docs/café.txt", b"UTF-8".to_vec()).unwrap();
+
+    let mut fs = remount(fs);
+    assert_eq!(FileSystem::read(&mut fs, "/hello.txt"), Ok(b"Hello, World!".to_vec()));
+    assert_eq!(FileSystem::read(&mut fs, "/docs/big.txt"), Ok(vec![b'x'; 3000]));
+    assert_eq!(FileSystem::lookup(&mut fs, "/docs"), Ok(FileType::Directory));
+    assert_eq!(FileSystem::read(&mut fs, "/docs/café.txt"), Ok(b"UTF-8".to_vec()));
+    assert_eq!(names(&mut fs, "/docs"), vec![String::from("big.txt"), String::from("café.txt")]);
+    assert_eq!(FileSystem::fsck(&mut fs, false), Ok(Vec::new()));
+}
+
+// the disk is a plain tar archive, so the first block is the header of the first file
+#[test_case]
+fn disk_is_an_archive() {
+    let mut fs = mount(formatted(64));
+    FileSystem::write(&mut fs, "/a.txt", b"aaa".to_vec()).unwrap();
+    let mut disk = fs.into_device();
+    let block = disk.read_block(0).unwrap();
+    assert_eq!(&block[257..262], b"ustar");
+    assert_eq!(disk.read_block(1).unwrap()[..3], b"aaa"[..]);
+}
+
+// removing something puts it in the trash, where it stays across a reboot until it's put back
+#[test_case]
+fn trash_survives_a_reboot() {
+    let mut fs = mount(formatted(64));
+    FileSystem::write(&mut fs, "/gone.txt", b"bye".to_vec()).unwrap();
+    FileSystem::unlink(&mut fs, "/gone.txt").unwrap();
+    assert_eq!(FileSystem::read(&mut fs, "/gone.txt"), Err(FsError::NotFound));
+
+    let mut fs = remount(fs);
+    let trash = FileSystem::trash(&mut fs).unwrap();
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].path, "/gone.txt");
+    FileSystem::undelete(&mut fs, "/gone.txt").unwrap();
+
+    let mut fs = remount(fs);
+    assert_eq!(FileSystem::read(&mut fs, "/gone.txt"), Ok(b"bye".to_vec()));
+    assert_eq!(FileSystem::trash(&mut fs).map(|trash| trash.len()), Ok(0));
+}
+
+// things in the trash keep the time they were last changed, and a path too long for a header still goes in
+#[test_case]
+fn trash_keeps_times_and_long_paths() {
+    let mut disk = formatted(64);
+    let mut header = Header::new("/old.txt", b'0', 3);
+    header.mtime = 100;
+    disk.write_blocks(0, &header.to_block()).unwrap();
+    disk.write_blocks(1, &[b'o'; 512]).unwrap();
+    let mut fs = mount(disk);
+    let long = format!("/{}", "l".repeat(200));
+    FileSystem::write(&mut fs, &long, b"long".to_vec()).unwrap();
+    FileSystem::unlink(&mut fs, "/old.txt").unwrap();
+    FileSystem::unlink(&mut fs, &long).unwrap();
+
+    let mut fs = remount(fs);
+    let trash = FileSystem::trash(&mut fs).unwrap();
+    assert_eq!(trash.len(), 2);
+    assert!(trash.iter().all(|entry| entry.removed > 100));
+    assert!(trash.iter().any(|entry| entry.path == long));
+    FileSystem::undelete(&mut fs, "/old.txt").unwrap();
+    FileSystem::undelete(&mut fs, &long).unwrap();
+
+    let mut fs = remount(fs);
+    assert_eq!(FileSystem::stat(&mut fs, "/old.txt").map(|stat| stat.mtime), Ok(100));
+    assert_eq!(FileSystem::read(&mut fs, "/old.txt"), Ok(b"ooo".to_vec()));
+    assert_eq!(FileSystem::read(&mut fs, &long), Ok(b"long".to_vec()));
+    assert_eq!(FileSystem::fsck(&mut fs, false), Ok(Vec::new()));
+}
+
+// writing part of a file leaves the rest alone, whether it fits in the blocks the file has or it has to move
+#[test_case]
+fn write_part_of_a_file() {
+    let mut fs = mount(formatted(64));
+    FileSystem::write(&mut fs, "/a.txt", vec![b'a'; 1000]).unwrap();
+    FileSystem::write(&mut fs, "/b.txt", b"bbb".to_vec()).unwrap();
+    assert_eq!(FileSystem::write_at(&mut fs, "/a.txt", 510, b"xyz"), Ok(3));
+    assert_eq!(FileSystem::write_at(&mut fs, "/a.txt", 1020, b"end"), Ok(3));
+    assert_eq!(FileSystem::write_at(&mut fs, "/a.txt", 1200, b"moved"), Ok(5));
+    assert_eq!(FileSystem::write_at(&mut fs, "/c.txt", 0, b"c"), Err(FsError::NotFound));
+
+    let mut expected = vec![b'a'; 1000];
+    expected[510..513].copy_from_slice(b"xyz");
+    expected.resize(1200, 0);
+    expected[1020..1023].copy_from_slice(b"end");
+    expected.extend_from_slice(b"moved");
+    let mut fs = remount(fs);
+    assert_eq!(FileSystem::read(&mut fs, "/a.txt"), Ok(expected));
+    assert_eq!(FileSystem::read(&mut fs, "/b.txt"), Ok(b"bbb".to_vec()));
+    assert_eq!(FileSystem::fsck(&mut fs, false), Ok(Vec::new()));
+}
+
+// a disk that was never made into an archive is left alone until mkfs, whether it's blank or has something else on it
+#[test_case]
+fn only_formatted_disks_mount() {
+    let mut fs = USTARFileSystem::on(RamDisk::new(16 + JOURNAL_BLOCKS));
+    assert_eq!(fs.init(), Err(FsError::NotFormatted));
+    let mut disk = fs.into_device();
+    disk.write_blocks(2, &[0x53; 512]).unwrap();
+    let mut fs = USTARFileSystem::on(disk.clone());
+    assert_eq!(fs.init(), Err(FsError::NotFormatted));
+    disk.write_blocks(0, &[0xEF; 512]).unwrap();
+    assert_eq!(os::ustar::is_formatted(&mut disk), Ok(false));
+
+    let mut fs = USTARFileSystem::on(disk);
+    fs.format().unwrap();
+    let mut fs = remount(fs);
+    assert_eq!(FileSystem::readdir(&mut fs, "/").map(|list| list.len()), Ok(0));
+    FileSystem::write(&mut fs, "/a.txt", b"a".to_vec()).unwrap();
+    let mut fs = remount(fs);
+    assert_eq!(FileSystem::read(&mut fs, "/a.txt"), Ok(b"a".to_vec()));
+}
+
+// the archive can't grow into the journal
+#[test_case]
+fn full_disk() {
+    let mut fs = mount(formatted(16));
+    assert_eq!(FileSystem::write(&mut fs, "/big", vec![0; 32 * 512]), Err(FsError::NoSpace));
+    FileSystem::write(&mut fs, "/small", vec![1; 512]).unwrap();
+    let mut fs = remount(fs);
+    assert_eq!(FileSystem::read(&mut fs, "/small"), Ok(vec![1; 512]));
+}
+
+type CachedDisk = BlockCache<FaultyDisk>;
+
+fn cached(disk: FaultyDisk) -> CachedDisk {
+    BlockCache::new(disk, CACHE_BLOCKS)
+}
+
+// the steps the crash test goes through, and what the files should look like after each one
+fn crash_step(fs: &mut USTARFileSystem<CachedDisk>, step: usize) -> Result<(), FsError> {
+    match step {
+        0 => FileSystem::write(fs, "/a.txt", vec![b'A'; 3000]),
+        1 => FileSystem::unlink(fs, "/b.txt"),
+        2 => FileSystem::write(fs, "/n.txt", vec![b'N'; 700]),
+        3 => FileSystem::empty_trash(fs),
+        4 => FileSystem::write(fs, "/m.txt", vec![b'M'; 1600]),
+        5 => FileSystem::defragment(fs),
+        6 => FileSystem::unlink(fs, "/n.txt"),
+        _ => FileSystem::defragment(fs),
+    }
+}
+const CRASH_STEPS: usize = 8;
+
+fn crash_states() -> Vec<BTreeMap<&'static str, Vec<u8>>> {
+    let mut state = BTreeMap::new();
+    state.insert("/a.txt", vec![b'a'; 5000]);
+    state.insert("/b.txt", vec![b'b'; 2000]);
+    state.insert("/c.txt", vec![b'c'; 900]);
+    let mut states = vec![state.clone()];
+    state.insert("/a.txt", vec![b'A'; 3000]);
+    states.push(state.clone());
+    state.remove("/b.txt");
+    states.push(state.clone());
+    state.insert("/n.txt", vec![b'N'; 700]);
+    states.push(state.clone());
+    states.push(state.clone());
+    state.insert("/m.txt", vec![b'M'; 1600]);
+    states.push(state.clone());
+    states.push(state.clone());
+    state.remove("/n.txt");
+    states.push(state.clone());
+    states.push(state.clone());
+    states
+}
+
+fn crash_state(fs: &mut USTARFileSystem<CachedDisk>) -> BTreeMap<&'static str, Vec<u8>> {
+    let mut state = BTreeMap::new();
+    for name in ["/a.txt", "/b.txt", "/c.txt", "/n.txt", "/m.txt"].iter() {
+        if let Ok(data) = FileSystem::read(fs, name) {
+            state.insert(*name, data);
+        }
+    }
+    state
+}
+
+// losing power after any write in the middle of writing, removing or defragmenting leaves the files the way they were
+// before or after the step it happened in, once the journal is replayed on the next boot
+// The disk is behind a cache like it is on a real boot, so everything reaches it during the sync and that's where the
+// power goes out
+#[test_case]
+fn crash_anywhere() {
+    let states = crash_states();
+    let mut writes = 0;
+    loop {
+        let mut fs = USTARFileSystem::on(cached(FaultyDisk::on(formatted(128))));
+        fs.init().unwrap();
+        FileSystem::write(&mut fs, "/a.txt", vec![b'a'; 5000]).unwrap();
+        FileSystem::write(&mut fs, "/b.txt", vec![b'b'; 2000]).unwrap();
+        FileSystem::write(&mut fs, "/c.txt", vec![b'c'; 900]).unwrap();
+        FileSystem::sync(&mut fs).unwrap();
+
+        let mut fs = USTARFileSystem::on(cached(fs.into_device().into_device().cut_after(writes)));
+        let mut done = 0;
+        let mounted = fs.init().is_ok();
+        while mounted && done < CRASH_STEPS {
+            // nothing goes to the disk until the sync, so only the sync can fail
+            crash_step(&mut fs, done).unwrap();
+            if FileSystem::sync(&mut fs).is_err() {
+                break;
+            }
+            done += 1;
+        }
+
+        // the power comes back, and whatever was still in the cache is gone
+        let mut disk = fs.into_device().into_device();
+        let finished = !disk.power_is_out();
+        disk.restore_power();
+        let mut fs = USTARFileSystem::on(cached(disk));
+        fs.init().unwrap();
+        let state = crash_state(&mut fs);
+        assert!(state == states[done] || (done < CRASH_STEPS && state == states[done + 1]));
+        assert_eq!(FileSystem::fsck(&mut fs, false), Ok(Vec::new()));
+        if finished {
+            assert_eq!(done, CRASH_STEPS);
+            break;
+        }
+        writes += 1;
+    }
+}
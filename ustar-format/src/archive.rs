@@ -0,0 +1,198 @@
+// Goes through the headers of an archive one at a time, putting long names together with the entry they belong to
+// It doesn't know anything about disks - it's handed something that reads a block, so the kernel and the host tools
+// can both use it
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::header::{blocks_for_size, checksum_matches, header_string, is_header, parse_octal, Header};
+use crate::long_names::parse_pax_records;
+use crate::{trashed_path, trashed_time, TRASHED_PATH_KEY};
+
+// What was found at one spot in the archive
+#[derive(Debug, Clone)]
+pub enum Entry {
+    // A file, link or directory
+    Item(Item),
+    // A PAX global header - what's in it counts for every entry after it, so nothing needs it anymore
+    Global { block_id: u64, blocks: u64 },
+    // A header with the wrong checksum. Nothing in it can be trusted, but its size is the best guess at where the
+    // next header is
+    Damaged { block_id: u64, name: String },
+    // A type that isn't supported - it gets skipped along with whatever data it has
+    Unsupported { block_id: u64, blocks: u64, type_flag: u8 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Item {
+    // Any long names have been put in already, and something in the trash has the path it was removed from
+    pub header: Header,
+    // Where it starts - long name headers come in front of the real header
+    pub block_id: u64,
+    // Blocks of long name headers in front of the real header
+    pub extra_blocks: u64,
+    // Did the long names come from GNU headers?
+    pub gnu_long_names: bool,
+    pub trashed: bool,
+    // When it went in the trash, for something that's in there
+    pub removed: u64,
+}
+
+impl Item {
+    pub fn is_directory(&self) -> bool {
+        self.header.type_flag == b'5'
+    }
+
+    // Deleted space - a header called defrag that covers all of it
+    pub fn is_tombstone(&self) -> bool {
+        !self.trashed && self.header.name == "defrag"
+    }
+
+    // Headers and data - directories never have anything after them
+    pub fn blocks(&self) -> u64 {
+        let data = if self.is_directory() { 0 } else { blocks_for_size(self.header.size) };
+        self.extra_blocks + 1 + data
+    }
+}
+
+#[derive(Default)]
+pub struct Reader {
+    position: u64,
+    // Long names from PAX and GNU headers, waiting for the entry they go with
+    long_name: Option<String>,
+    long_linked_name: Option<String>,
+    // The path of something in the trash that was too long for its header
+    long_trashed_path: Option<String>,
+    gnu_long_names: bool,
+    first_extra_block: Option<u64>,
+    // PAX global headers count for every entry after them
+    global_name: Option<String>,
+    global_linked_name: Option<String>,
+}
+
+impl Reader {
+    pub fn new() -> Reader {
+        Reader::default()
+    }
+
+    // Where the next header is - once next gives back None, this is where the archive ends
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // The next entry, or None at the end of the archive (the first block that isn't a header)
+    pub fn next<E, F>(&mut self, mut read: F) -> Result<Option<Entry>, E> where F: FnMut(u64) -> Result<Vec<u8>, E> {
+        loop {
+            let counter = self.position;
+            let block = read(counter)?;
+            if !is_header(&block) {
+                return Ok(None);
+            }
+            if !checksum_matches(&block) {
+                self.position += blocks_for_size(parse_octal(&block[124..136])) + 1;
+                self.forget_long_names();
+                return Ok(Some(Entry::Damaged { block_id: counter, name: header_string(&block[0..100]) }));
+            }
+            let mut header = match Header::parse(&block) {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            match header.type_flag {
+                b'0' | b'1' | b'2' | b'5' => {
+                    let directory = header.type_flag == b'5';
+                    let block_id = match self.first_extra_block {
+                        Some(first) => first,
+                        None => counter,
+                    };
+                    if let Some(name) = self.long_name.take().or_else(|| self.global_name.clone()) {
+                        header.name = name;
+                        if directory && !header.name.ends_with('/') {
+                            header.name.push('/');
+                        }
+                    }
+                    // Directories don't point at anything
+                    let linked_name = self.long_linked_name.take().or_else(|| self.global_linked_name.clone());
+                    if !directory {
+                        if let Some(linked_name) = linked_name {
+                            header.linked_name = linked_name;
+                        }
+                    }
+                    let trashed = match trashed_path(&block, self.long_trashed_path.take()) {
+                        Some(path) => {
+                            header.name = path;
+                            true
+                        },
+                        None => false,
+                    };
+                    let removed = if trashed { trashed_time(&block).unwrap_or(header.mtime) } else { 0 };
+                    let item = Item {
+                        header,
+                        block_id,
+                        extra_blocks: counter - block_id,
+                        gnu_long_names: self.gnu_long_names,
+                        trashed,
+                        removed,
+                    };
+                    self.gnu_long_names = false;
+                    self.first_extra_block = None;
+                    self.position = item.block_id + item.blocks();
+                    return Ok(Some(Entry::Item(item)));
+                },
+                b'x' | b'g' | b'L' | b'K' => {
+                    // Long names - the data has the real name (PAX also has other stuff that gets ignored)
+                    let blocks = blocks_for_size(header.size);
+                    let mut data = Vec::with_capacity((blocks * 512) as usize);
+                    for i in 0..blocks {
+                        data.append(&mut read(counter + 1 + i)?);
+                    }
+                    data.truncate(header.size as usize);
+                    self.position += blocks + 1;
+                    // GNU names end at the first null
+                    let gnu_name = String::from_utf8_lossy(data.split(|c| *c == 0).next().unwrap_or(&[])).into_owned();
+                    match header.type_flag {
+                        b'L' => {
+                            self.long_name = Some(gnu_name);
+                            self.gnu_long_names = true;
+                        },
+                        b'K' => {
+                            self.long_linked_name = Some(gnu_name);
+                            self.gnu_long_names = true;
+                        },
+                        flag => {
+                            for (key, value) in parse_pax_records(&data) {
+                                match (flag, key.as_str()) {
+                                    (b'x', "path") => self.long_name = Some(value),
+                                    (b'x', "linkpath") => self.long_linked_name = Some(value),
+                                    (b'x', TRASHED_PATH_KEY) => self.long_trashed_path = Some(value),
+                                    (b'g', "path") => self.global_name = Some(value),
+                                    (b'g', "linkpath") => self.global_linked_name = Some(value),
+                                    _ => {},
+                                }
+                            }
+                        },
+                    }
+                    // A global header doesn't belong to any one entry
+                    if header.type_flag == b'g' {
+                        return Ok(Some(Entry::Global { block_id: counter, blocks: blocks + 1 }));
+                    }
+                    if self.first_extra_block.is_none() {
+                        self.first_extra_block = Some(counter);
+                    }
+                },
+                type_flag => {
+                    let blocks = blocks_for_size(header.size) + 1;
+                    self.position += blocks;
+                    self.forget_long_names();
+                    return Ok(Some(Entry::Unsupported { block_id: counter, blocks, type_flag }));
+                },
+            }
+        }
+    }
+
+    fn forget_long_names(&mut self) {
+        self.long_name = None;
+        self.long_linked_name = None;
+        self.long_trashed_path = None;
+        self.gnu_long_names = false;
+        self.first_extra_block = None;
+    }
+}
@@ -0,0 +1,59 @@
+// What fsck can find wrong with an archive - the kernel checks a mounted disk, the host tools check an image
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    // The header at block_id doesn't match its checksum, so it gets skipped
+    BadChecksum { block_id: u64, name: String },
+    // A directory that has things in it, but no header on the disk
+    MissingDirectory { name: String },
+    // Two things that think they have the same blocks
+    Overlap { first: String, second: String },
+    // What's at the end of the archive isn't two empty blocks
+    BadEndMarker { block_id: u64 },
+    // Something that isn't a header where one should be, which hides everything after it
+    Unreadable { block_id: u64 },
+}
+
+impl FsckProblem {
+    // Overlaps and garbage in the middle of the archive need someone to work out what was supposed to be there
+    pub fn fixable(&self) -> bool {
+        !matches!(self, FsckProblem::Overlap { .. } | FsckProblem::Unreadable { .. })
+    }
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsckProblem::BadChecksum { block_id, name } => write!(f, "The header for {} at block {} has the wrong checksum", name, block_id),
+            FsckProblem::MissingDirectory { name } => write!(f, "{} has things in it, but no header of its own", name),
+            FsckProblem::Overlap { first, second } => write!(f, "{} and {} are using the same blocks", first, second),
+            FsckProblem::BadEndMarker { block_id } => write!(f, "The end of the archive at block {} isn't two empty blocks", block_id),
+            FsckProblem::Unreadable { block_id } => write!(f, "Block {} isn't a header, so nothing after it can be read", block_id),
+        }
+    }
+}
+
+// Everything that takes up space on the disk should have blocks of its own - ranges are (start, length, what it is)
+pub fn find_overlaps(mut ranges: Vec<(u64, u64, String)>) -> Vec<FsckProblem> {
+    ranges.sort_by_key(|range| range.0);
+    let mut problems = Vec::new();
+    // Whatever reaches the furthest so far, since something big can overlap more than just the next thing
+    let mut furthest: Option<(u64, String)> = None;
+    for (start, length, name) in ranges {
+        let end = start + length;
+        match &furthest {
+            Some((furthest_end, other)) if *furthest_end > start => {
+                problems.push(FsckProblem::Overlap { first: other.to_string(), second: name.to_string() });
+                if end > *furthest_end {
+                    furthest = Some((end, name));
+                }
+            },
+            _ => furthest = Some((end, name)),
+        }
+    }
+    problems
+}
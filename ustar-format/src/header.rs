@@ -0,0 +1,252 @@
+// One 512 byte USTAR header, and the little helpers for the fields in it
+// https://wiki.osdev.org/USTAR
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+pub const BLOCK_SIZE: usize = 512;
+
+// Everything in a header that anyone cares about - the rest is always the same
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    // With the prefix put back on the front
+    pub name: String,
+    // Just the permission bits - rwxrwxrwx
+    pub mode: u64,
+    pub owner_id: u64,
+    pub group_id: u64,
+    pub size: u64,
+    // Seconds since 1970
+    pub mtime: u64,
+    // The actual character - b'0' for a file, b'1' for a hard link, b'2' for a symlink, b'5' for a directory and so on
+    pub type_flag: u8,
+    // What a link points at
+    pub linked_name: String,
+    pub owner_name: String,
+    pub group_name: String,
+}
+
+impl Header {
+    // A header that belongs to root and was made at the start of 1970 - for headers nobody is going to look at the
+    // owner or time of, like long names and tombstones
+    pub fn new(name: &str, type_flag: u8, size: u64) -> Header {
+        Header {
+            name: name.to_string(),
+            mode: 0o644,
+            owner_id: 0,
+            group_id: 0,
+            size,
+            mtime: 0,
+            type_flag,
+            linked_name: String::new(),
+            owner_name: "root".to_string(),
+            group_name: "root".to_string(),
+        }
+    }
+
+    // Reads in a header, or gives back None if it isn't one or the checksum is wrong
+    pub fn parse(block: &[u8]) -> Option<Header> {
+        if !is_header(block) || !checksum_matches(block) {
+            return None;
+        }
+        // Old archives use a null byte for normal files
+        let type_flag = match block[156] {
+            0 => b'0',
+            flag => flag,
+        };
+        Some(Header {
+            name: join_prefix(block, header_string(&block[0..100])),
+            mode: parse_octal(&block[100..108]) & 0o7777,
+            owner_id: parse_octal(&block[108..116]),
+            group_id: parse_octal(&block[116..124]),
+            size: parse_octal(&block[124..136]),
+            mtime: parse_octal(&block[136..148]),
+            type_flag,
+            linked_name: header_string(&block[157..257]),
+            owner_name: header_string(&block[265..297]),
+            group_name: header_string(&block[297..329]),
+        })
+    }
+
+    pub fn to_block(&self) -> Vec<u8> {
+        let mut block = Vec::with_capacity(BLOCK_SIZE);
+
+        // Filename - a long one gets split up with the start of it in the prefix, and if it's too long for that
+        // the long name headers have the real thing
+        let (prefix, name) = match ustar_name_fields(&self.name) {
+            Some(fields) => fields,
+            None => (String::new(), self.name.to_string()),
+        };
+        block.extend(name.bytes().take(100));
+        block.resize(100, 0);
+
+        // Mode, owner and group ID - 7 octal digits and a null each
+        block.extend(format!("{:07o}\0", self.mode & 0o7777).bytes());
+        block.extend(format!("{:07o}\0", self.owner_id & 0o7777777).bytes());
+        block.extend(format!("{:07o}\0", self.group_id & 0o7777777).bytes());
+        // Size and time - 11 octal digits and a null
+        block.extend(format!("{:011o}\0", self.size & 0o77777777777).bytes());
+        block.extend(format!("{:011o}\0", self.mtime & 0o77777777777).bytes());
+        // Checksum - filled in at the end
+        block.extend(b"        ");
+        block.push(self.type_flag);
+
+        // Linked name - it's supposed to be 0 for everything that isn't a link
+        block.extend(self.linked_name.bytes().take(100));
+        block.resize(257, 0);
+        block.extend(b"ustar\0");
+        block.extend(b"00");
+
+        block.extend(self.owner_name.bytes().take(31));
+        block.resize(297, 0);
+        block.extend(self.group_name.bytes().take(31));
+        block.resize(329, 0);
+
+        // Device major and minor number
+        block.extend(b"0000000\0");
+        block.extend(b"0000000\0");
+
+        block.extend(prefix.bytes().take(155));
+        block.resize(BLOCK_SIZE, 0);
+
+        // Otherwise archivemount sees it as corrupted
+        fix_checksum(&mut block);
+        block
+    }
+}
+
+// How many 512 byte blocks it takes to hold size bytes
+pub fn blocks_for_size(size: u64) -> u64 {
+    let blocks = size / 512;
+    if blocks * 512 == size {
+        blocks
+    }
+    else {
+        blocks + 1
+    }
+}
+
+// Does block have the magic value of a header? GNU tar uses its own
+pub fn is_header(block: &[u8]) -> bool {
+    block.len() >= BLOCK_SIZE && ((&block[257..263] == b"ustar\0" && &block[263..265] == b"00") || &block[257..265] == b"ustar  \0")
+}
+
+// Reads a null terminated string out of a header field - names are written as UTF-8, and anything that isn't valid
+// UTF-8 gets the replacement character instead
+pub fn header_string(field: &[u8]) -> String {
+    let end = field.iter().position(|chr| *chr == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+// Checks the checksum stored in a header against the bytes that are actually in it
+// The sum counts the checksum field itself as spaces, since it can't include itself
+pub fn checksum_matches(block: &[u8]) -> bool {
+    let mut sum = 0;
+    for (i, n) in block.iter().enumerate() {
+        if i > 147 && i < 156 {
+            sum += 32;
+        }
+        else {
+            sum += *n as u64;
+        }
+    }
+    sum == parse_octal(&block[148..156])
+}
+
+// Puts the right checksum into a header
+pub fn fix_checksum(block: &mut [u8]) {
+    block[148..156].fill(b' ');
+    let sum: u64 = block.iter().map(|n| *n as u64).sum();
+    let checksum = format!("{:06o}\0 ", sum);
+    block[148..156].copy_from_slice(checksum.as_bytes());
+}
+
+// Reads an octal number out of a header field, stopping at the first thing that isn't a digit
+pub fn parse_octal(field: &[u8]) -> u64 {
+    let mut result = 0;
+    for chr in field.iter().skip_while(|c| **c == b' ') {
+        if *chr < b'0' || *chr > b'7' {
+            break;
+        }
+        result = result * 8 + (*chr - b'0') as u64;
+    }
+    result
+}
+
+// Names that don't fit in the 100 byte name field can be split at a / with the first part going in the 155 byte prefix
+// Gives back (prefix, name), or None if there's no way to split it so it fits
+pub fn ustar_name_fields(name: &str) -> Option<(String, String)> {
+    if name.len() <= 100 {
+        return Some((String::new(), name.to_string()));
+    }
+    // The / on the end of a directory name belongs to the name part
+    let search = name.trim_end_matches('/');
+    for (i, chr) in search.char_indices() {
+        // A name of defrag with a prefix is how the trash looks, so the split has to go somewhere else
+        if chr == '/' && i > 0 && i <= 155 && name.len() - i - 1 <= 100 && &name[i + 1..] != "defrag" {
+            return Some((name[..i].to_string(), name[i + 1..].to_string()));
+        }
+    }
+    None
+}
+
+// Only POSIX ustar headers have a prefix - GNU headers keep other stuff there
+pub fn join_prefix(block: &[u8], name: String) -> String {
+    if block.len() < 500 || &block[257..263] != b"ustar\0" {
+        return name;
+    }
+    let prefix = header_string(&block[345..500]);
+    if prefix.is_empty() {
+        name
+    }
+    else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut header = Header::new("some/dir/file.txt", b'0', 1234);
+        header.mode = 0o755;
+        header.owner_id = 1000;
+        header.group_id = 100;
+        header.mtime = 1_600_000_000;
+        header.owner_name = "user".to_string();
+        let block = header.to_block();
+        assert_eq!(block.len(), BLOCK_SIZE);
+        assert!(checksum_matches(&block));
+        assert_eq!(Header::parse(&block), Some(header));
+    }
+
+    #[test]
+    fn long_name_goes_in_prefix() {
+        let name = format!("{}/{}", "a".repeat(120), "b".repeat(90));
+        let block = Header::new(&name, b'0', 0).to_block();
+        assert_eq!(header_string(&block[0..100]), "b".repeat(90));
+        assert_eq!(Header::parse(&block).unwrap().name, name);
+    }
+
+    #[test]
+    fn non_ascii_names() {
+        let header = Header::new("/café.txt", b'0', 0);
+        let block = header.to_block();
+        assert_eq!(header_string(&block[0..100]), "/café.txt");
+        assert_eq!(Header::parse(&block), Some(header));
+        let name = format!("{}/{}", "é".repeat(60), "ü".repeat(40));
+        assert_eq!(Header::parse(&Header::new(&name, b'0', 0).to_block()).unwrap().name, name);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut block = Header::new("file", b'0', 5).to_block();
+        block[0] = b'g';
+        assert_eq!(Header::parse(&block), None);
+        fix_checksum(&mut block);
+        assert_eq!(Header::parse(&block).unwrap().name, "gile");
+    }
+}
@@ -0,0 +1,104 @@
+// The on-disk format of the file system - USTAR headers, the long names in front of them, what deleted space and the
+// trash look like, and where the journal goes. The kernel reads and writes disks with this, and the host tools
+// read and write images of them, so both always agree on what the bytes mean
+#![no_std]
+
+extern crate alloc;
+
+mod archive;
+mod fsck;
+mod header;
+mod long_names;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub use archive::{Entry, Item, Reader};
+pub use fsck::{find_overlaps, FsckProblem};
+pub use header::{blocks_for_size, checksum_matches, fix_checksum, header_string, is_header, join_prefix, parse_octal,
+    ustar_name_fields, Header, BLOCK_SIZE};
+pub use long_names::{extension_header, gnu_long_entry, long_name_headers, parse_pax_records, pax_headers, pax_record};
+
+// The journal lives in the last JOURNAL_BLOCKS blocks of the disk, and has JOURNAL_MAGIC at the start of it while
+// there's a transaction that hasn't been finished
+pub const JOURNAL_MAGIC: [u8; 8] = *b"USTARJNL";
+// What's at the start of the journal the rest of the time. An empty archive is all zeros, which plenty of other things
+// are too, so this is how a disk that was made into one gets told apart from a disk that's just blank
+pub const JOURNAL_IDLE: [u8; 8] = *b"USTARIDL";
+// Every block of the target table holds 64 block ids, each a little endian u64
+pub const JOURNAL_TABLE_BLOCKS: u64 = 16;
+// How many bytes a block id takes up in the target table
+pub const JOURNAL_ID_SIZE: usize = 8;
+// How many sectors one transaction can hold
+pub const JOURNAL_ENTRIES: usize = JOURNAL_TABLE_BLOCKS as usize * BLOCK_SIZE / JOURNAL_ID_SIZE;
+// Header + target table + the sectors themselves
+pub const JOURNAL_BLOCKS: u64 = 1 + JOURNAL_TABLE_BLOCKS + JOURNAL_ENTRIES as u64;
+
+// A "defrag" header that covers blocks blocks - what deleted space looks like on disk
+pub fn tombstone_block(blocks: u64) -> Vec<u8> {
+    Header::new("defrag", b'0', (blocks - 1) * 512).to_block()
+}
+
+// The PAX record with the path of something in the trash, when it's too long for the prefix. Nothing but us knows
+// what it means, so other tar programs skip over it
+pub const TRASHED_PATH_KEY: &str = "USTARFS.trashed";
+
+// The headers for something going in the trash, from header (its normal one) and how many blocks are in front of it
+// They're the same as before, except the name is defrag with the old path in the prefix, and when it was removed goes
+// in the padding at the end of the header, so the time it was last changed is still there when it comes back. A path
+// too long for the prefix goes in a PAX record in front instead. Whatever else is in front can only keep what a link
+// points at, since a name there would bring it back to life. Gives back None if the headers won't fit where the old
+// ones were
+pub fn trash_headers(mut header: Vec<u8>, path: &str, linked_name: &str, extra_blocks: u64, removed: u64) -> Option<Vec<u8>> {
+    let long_path = path.len() > 155;
+    let mut result = if long_path {
+        let mut records = pax_record(TRASHED_PATH_KEY, path);
+        if linked_name.len() > 100 {
+            records.push_str(&pax_record("linkpath", linked_name));
+        }
+        pax_headers(records, extra_blocks)
+    }
+    else {
+        long_name_headers("", linked_name, false, extra_blocks)
+    };
+    if result.len() as u64 != extra_blocks * 512 {
+        return None;
+    }
+    header[0..100].fill(0);
+    header[0..6].copy_from_slice(b"defrag");
+    header[345..500].fill(0);
+    if !long_path {
+        header[345..345 + path.len()].copy_from_slice(path.as_bytes());
+    }
+    header[500..512].copy_from_slice(format!("{:011o}\0", removed).as_bytes());
+    fix_checksum(&mut header);
+    result.extend(header);
+    Some(result)
+}
+
+// The path something in the trash used to be at, if block is its header - long_path is what a PAX record in front of
+// it said, if there was one
+pub fn trashed_path(block: &[u8], long_path: Option<String>) -> Option<String> {
+    if header_string(&block[0..100]) != "defrag" || &block[257..263] != b"ustar\0" {
+        return None;
+    }
+    let path = header_string(&block[345..500]);
+    if path.is_empty() {
+        long_path
+    }
+    else {
+        Some(path)
+    }
+}
+
+// When something in the trash was removed, if block is its header. None if the padding is empty - older kernels put
+// the time in mtime instead
+pub fn trashed_time(block: &[u8]) -> Option<u64> {
+    if block[500..512].iter().all(|b| *b == 0) {
+        None
+    }
+    else {
+        Some(parse_octal(&block[500..512]))
+    }
+}
@@ -0,0 +1,107 @@
+// Names that are too long for a normal header go in front of it, either as a PAX header or a GNU 'L'/'K' entry
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use crate::header::{blocks_for_size, ustar_name_fields, Header};
+
+// One PAX record - "<length> <key>=<value>\n", where the length counts itself too
+pub fn pax_record(key: &str, value: &str) -> String {
+    let base = key.len() + value.len() + 3;
+    let mut length = base + 1;
+    while length != base + format!("{}", length).len() {
+        length = base + format!("{}", length).len();
+    }
+    format!("{} {}={}\n", length, key, value)
+}
+
+// Splits the data of a PAX header up into (key, value) pairs
+pub fn parse_pax_records(data: &[u8]) -> Vec<(String, String)> {
+    let mut result = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let space = match data[position..].iter().position(|c| *c == b' ') {
+            Some(space) => position + space,
+            None => break,
+        };
+        let length = match core::str::from_utf8(&data[position..space]).ok().and_then(|l| l.parse::<usize>().ok()) {
+            Some(length) => length,
+            None => break,
+        };
+        if length == 0 || position + length > data.len() {
+            break;
+        }
+        // Everything between the space and the newline on the end
+        let record = String::from_utf8_lossy(&data[space + 1..position + length - 1]).to_string();
+        if let Some(equals) = record.find('=') {
+            result.push((record[..equals].to_string(), record[equals + 1..].to_string()));
+        }
+        position += length;
+    }
+    result
+}
+
+// The header in front of a PAX or GNU long name - type_flag is the actual character that goes in the header
+pub fn extension_header(name: &str, type_flag: u8, size: u64) -> Vec<u8> {
+    Header::new(name, type_flag, size).to_block()
+}
+
+// GNU long names are just the name (with a null on the end) as the data of an 'L' or 'K' entry
+// Extra nulls pad it out to blocks blocks, if there's that much room
+pub fn gnu_long_entry(type_flag: u8, value: &str, blocks: u64) -> Vec<u8> {
+    let mut data = value.as_bytes().to_vec();
+    data.push(0);
+    let target = (blocks.saturating_sub(1) * 512) as usize;
+    if data.len() < target {
+        data.resize(target, 0);
+    }
+    let mut result = extension_header("././@LongLink", type_flag, data.len() as u64);
+    result.extend(data);
+    result.resize(blocks_for_size(result.len() as u64) as usize * 512, 0);
+    result
+}
+
+// The headers that go in front of an item whose name or linked name is too long for the normal header
+// Uses PAX headers unless the item came off the disk with GNU ones. They get padded out to take up blocks blocks,
+// so an item whose name got shorter still fits exactly where it was - blocks = 0 gives the fewest blocks that will do
+pub fn long_name_headers(name: &str, linked_name: &str, gnu: bool, blocks: u64) -> Vec<u8> {
+    let name_fits = ustar_name_fields(name).is_some();
+    let link_fits = linked_name.len() <= 100;
+    if gnu {
+        let mut result = Vec::new();
+        if !link_fits {
+            result.extend(gnu_long_entry(b'K', linked_name, 0));
+        }
+        let used = result.len() as u64 / 512;
+        if !name_fits || used < blocks {
+            result.extend(gnu_long_entry(b'L', name, blocks.saturating_sub(used)));
+        }
+        return result;
+    }
+    let mut records = String::new();
+    if !name_fits {
+        records.push_str(&pax_record("path", name));
+    }
+    if !link_fits {
+        records.push_str(&pax_record("linkpath", linked_name));
+    }
+    if records.is_empty() && blocks == 0 {
+        return Vec::new();
+    }
+    pax_headers(records, blocks)
+}
+
+// A PAX header with records in it, padded out to blocks blocks the same way as long_name_headers
+pub fn pax_headers(mut records: String, blocks: u64) -> Vec<u8> {
+    // A comment record fills up whatever room is left over
+    let target = (blocks.saturating_sub(1) * 512) as usize;
+    if blocks_for_size(records.len() as u64) + 1 < blocks {
+        let left = target - records.len();
+        let filler = left - 10 - format!("{}", left).len();
+        records.push_str(&pax_record("comment", &".".repeat(filler)));
+    }
+    let mut result = extension_header("././@PaxHeader", b'x', records.len() as u64);
+    result.extend(records.into_bytes());
+    result.resize(blocks_for_size(result.len() as u64) as usize * 512, 0);
+    result
+}